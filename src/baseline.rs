@@ -0,0 +1,177 @@
+#![allow(dead_code)]
+use std::collections::VecDeque;
+
+/// EWMA weight of each new sample in the baseline (RFC 6298 style 1/8).
+const ALPHA: f64 = 0.125;
+/// Weight of each new sample in the variance estimate.
+const BETA: f64 = 0.25;
+/// Replies used for the short-term median.
+const SHORT_TERM: usize = 5;
+/// Replies needed before the baseline is trusted.
+const WARMUP: u32 = 10;
+
+pub enum BaselineEvent {
+    /// Short-term median has been over the threshold for the configured number of probes.
+    Degraded { median_us: u64, baseline_us: u64 },
+    /// Short-term median has been back under the threshold for as long.
+    Recovered { median_us: u64, baseline_us: u64 },
+}
+
+/// Per-host latency baseline: an EWMA of RTT and its variance, plus the
+/// short-term median it is compared against.  Samples over the threshold are
+/// kept out of the baseline, and it is frozen while a host is degraded, so it
+/// does not drift up to the slow path before the detector can trip.
+pub struct LatencyBaseline {
+    factor: f64,
+    probes: u32,
+    ewma_us: f64,
+    var_us: f64,
+    samples: u32,
+    recent: VecDeque<u64>,
+    degraded: bool,
+    /// Consecutive probes on the other side of the threshold from the current state.
+    streak: u32,
+}
+
+impl LatencyBaseline {
+    /// `factor` of 0 disables detection; the baseline itself is still tracked.
+    pub fn new(factor: f64, probes: u32) -> LatencyBaseline {
+        LatencyBaseline {
+            factor,
+            probes: probes.max(1),
+            ewma_us: 0.0,
+            var_us: 0.0,
+            samples: 0,
+            recent: VecDeque::with_capacity(SHORT_TERM),
+            degraded: false,
+            streak: 0,
+        }
+    }
+
+    pub fn baseline_us(&self) -> Option<f64> {
+        (self.samples > 0).then_some(self.ewma_us)
+    }
+
+    pub fn stdev_us(&self) -> Option<f64> {
+        (self.samples > 1).then(|| self.var_us.sqrt())
+    }
+
+    pub fn is_degraded(&self) -> bool {
+        self.degraded
+    }
+
+    fn median_us(&self) -> u64 {
+        let mut v: Vec<u64> = self.recent.iter().copied().collect();
+        v.sort_unstable();
+        v[v.len() / 2]
+    }
+
+    pub fn update(&mut self, rtt_us: u64) -> Option<BaselineEvent> {
+        if self.recent.len() == SHORT_TERM {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(rtt_us);
+
+        let x = rtt_us as f64;
+        // Once trusted, a sample over the threshold may be the start of a step;
+        // absorbing it would raise the baseline until the step no longer counts.
+        let outlier = self.factor > 0.0 && self.samples >= WARMUP && x > self.ewma_us * self.factor;
+        if !self.degraded && !outlier {
+            if self.samples == 0 {
+                self.ewma_us = x;
+                self.var_us = 0.0;
+            } else {
+                let err = x - self.ewma_us;
+                self.ewma_us += ALPHA * err;
+                self.var_us = (1.0 - BETA) * self.var_us + BETA * err * err;
+            }
+            self.samples = self.samples.saturating_add(1);
+        }
+
+        if self.factor <= 0.0 || self.samples < WARMUP {
+            return None;
+        }
+        let median_us = self.median_us();
+        let baseline_us = self.ewma_us as u64;
+        let over = median_us as f64 > self.ewma_us * self.factor;
+        if over != self.degraded {
+            self.streak += 1;
+        } else {
+            self.streak = 0;
+        }
+        if self.streak < self.probes {
+            return None;
+        }
+        self.streak = 0;
+        self.degraded = over;
+        Some(if over {
+            BaselineEvent::Degraded { median_us, baseline_us }
+        } else {
+            BaselineEvent::Recovered { median_us, baseline_us }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn warmed_up() -> LatencyBaseline {
+        let mut b = LatencyBaseline::new(2.0, 5);
+        for _ in 0..20 {
+            assert!(b.update(1000).is_none());
+        }
+        b
+    }
+
+    /// Feeds `rtt_us` `n` times and returns the sample (1 based) that raised an event.
+    fn feed(b: &mut LatencyBaseline, rtt_us: u64, n: u32) -> Option<(u32, BaselineEvent)> {
+        (1..=n).find_map(|i| b.update(rtt_us).map(|e| (i, e)))
+    }
+
+    #[test]
+    fn sustained_step_trips() {
+        for factor in [3, 5, 20] {
+            let mut b = warmed_up();
+            let (at, ev) = feed(&mut b, 1000 * factor, 50).expect("step must trip");
+            // median over from the 3rd slow sample, then 5 probes in a row
+            assert_eq!(at, 7);
+            assert!(matches!(ev, BaselineEvent::Degraded { baseline_us: 1000, .. }));
+            assert!(b.is_degraded());
+            assert_eq!(b.baseline_us(), Some(1000.0));
+        }
+    }
+
+    #[test]
+    fn single_spike_does_not_trip() {
+        let mut b = warmed_up();
+        assert!(b.update(50_000).is_none());
+        assert!(feed(&mut b, 1000, 20).is_none());
+        assert!(!b.is_degraded());
+        assert_eq!(b.baseline_us(), Some(1000.0));
+    }
+
+    #[test]
+    fn recovers_after_step_ends() {
+        let mut b = warmed_up();
+        assert!(feed(&mut b, 4000, 10).is_some());
+        let (_, ev) = feed(&mut b, 1000, 20).expect("must recover");
+        assert!(matches!(ev, BaselineEvent::Recovered { median_us: 1000, baseline_us: 1000 }));
+        assert!(!b.is_degraded());
+    }
+
+    #[test]
+    fn small_changes_follow_the_baseline() {
+        let mut b = warmed_up();
+        assert!(feed(&mut b, 1500, 100).is_none());
+        assert!(b.baseline_us().unwrap() > 1450.0);
+    }
+
+    #[test]
+    fn disabled_detection_still_tracks() {
+        let mut b = LatencyBaseline::new(0.0, 5);
+        assert!(feed(&mut b, 1000, 20).is_none());
+        assert!(feed(&mut b, 10_000, 100).is_none());
+        assert!(b.baseline_us().unwrap() > 9000.0);
+    }
+}
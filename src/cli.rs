@@ -1,11 +1,12 @@
 use clap::Parser;
 use std::time::Duration;
-use std::net::{IpAddr, ToSocketAddrs};
+use std::path::PathBuf;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, ToSocketAddrs};
 use humantime::parse_duration;
 use std::str::FromStr;
 use anyhow::{anyhow,Context};
 use std::fmt;
-use log::{debug, LevelFilter};
+use log::{debug, info, warn, LevelFilter};
 
 type ResultS<T> = std::result::Result<T, anyhow::Error>;
 
@@ -25,7 +26,7 @@ pub struct Config {
     pub stat_interval: Duration,
 
     #[arg(value_parser = to_addr)]
-    /// list of IPs or hostnames
+    /// list of IPs or hostnames, each optionally followed by ,netns=<name> and/or ,mark=<n>
     pub ips: Vec<HostInfo>,
 
     #[arg(short)]
@@ -36,17 +37,209 @@ pub struct Config {
     /// log level
     pub log_level: LevelFilter,
 
+    #[arg(long, value_parser = to_log_sink, default_value = "stderr", value_name = "stderr|syslog|unix:<path>|udp://host[:port]")]
+    /// where log lines go: stderr, or RFC 5424 syslog over the /dev/log or another unix socket, or UDP (port 514 by default)
+    pub log_sink: LogSink,
+
+    #[arg(long, value_parser = to_log_format, default_value = "text")]
+    /// log line format: text, logfmt or json; the latter two carry host, seq, rtt etc. as separate fields
+    pub log_format: LogFormat,
+
     #[arg(short = 'I', long, default_value = "11000")]
     /// log level
     pub ident_base: u16,
 
+    #[arg(long, default_value = "1")]
+    /// number of flows (distinct ICMP idents) probed per host each round, to sample
+    /// ECMP paths that hash on the ident/checksum
+    pub flows: usize,
+
+    #[arg(long, value_parser = to_ip_option)]
+    /// IPv4 option set on echo requests: rr (record route) or ts (internet timestamp)
+    pub ip_option: Option<IpOptionKind>,
+
+    #[arg(long)]
+    /// also report last 1m/5m/15m and since-start loss and latency side by side
+    pub windows: bool,
+
+    #[arg(long, default_value = "2.0")]
+    /// flag latency as degraded when the short-term median RTT exceeds the EWMA
+    /// baseline by this factor (0 disables)
+    pub degrade_factor: f64,
+
+    #[arg(long, default_value = "5")]
+    /// consecutive probes over (or back under) the degrade threshold before
+    /// a degraded/recovered event is raised
+    pub degrade_probes: u32,
+
+    #[arg(long, default_value = "3")]
+    /// consecutive missed pings before a host is considered down (and an outage opens); with --flows, rounds in which every flow missed
+    pub down_after: u32,
+
+    #[arg(long, default_value = "2")]
+    /// consecutive replies (rounds with any flow answered) before a down host is considered up again
+    pub up_after: u32,
+
+    #[arg(long, default_value = "20")]
+    /// loss percentage over the last 20 pings (rounds with --flows) at which a reachable host is degraded
+    pub degraded_loss: f64,
+
+    #[arg(long, default_value = "4")]
+    /// up/down changes within --flap-window that mark a host as flapping (0 disables)
+    pub flap_count: u32,
+
+    #[arg(long, value_parser = parse_duration, default_value = "5m")]
+    /// window for the flap detector
+    pub flap_window: Duration,
+
+    #[arg(long, value_parser = parse_duration, default_value = "5s")]
+    /// outages on several hosts starting within this window are reported as one common-cause outage (0 disables)
+    pub correlate_window: Duration,
+
+    #[arg(long)]
+    /// add the IPv4 default gateway from /proc/net/route as a gateway tier target
+    pub auto_gateway: bool,
+
+    #[arg(long, value_name = "path|-")]
+    /// write one JSON object per probe result, state change, outage start and outage end to this file ("-" for stdout)
+    pub events_json: Option<String>,
+
+    #[arg(long)]
+    /// write every ICMP packet sent and received, including discarded ones, to this pcap file
+    pub pcap: Option<PathBuf>,
+
+    #[arg(long, value_name = "path|-")]
+    /// append one CSV row per probe result to this file ("-" for stdout)
+    pub csv_probes: Option<String>,
+
+    #[arg(long, value_name = "path|-")]
+    /// append the per-interval stats table as CSV rows to this file ("-" for stdout)
+    pub csv_stats: Option<String>,
+
+    #[arg(long, value_parser = to_delimiter, default_value = ",")]
+    /// field delimiter for the CSV files: a single character or "tab"
+    pub csv_delimiter: char,
+
+    #[arg(long, value_name = "[ip]:port", value_parser = to_listen_addr)]
+    /// serve Prometheus metrics at http://<addr>/metrics, e.g. :9464 or 127.0.0.1:9464
+    pub prometheus: Option<SocketAddr>,
+
+    #[arg(long, value_name = "path.prom")]
+    /// rewrite this file with the Prometheus metrics every stats interval, for node_exporter's textfile collector
+    pub textfile: Option<PathBuf>,
+
+    #[arg(long, value_name = "host:port")]
+    /// send StatsD over UDP: an rtt timer and reply/timeout counter per probe, gauges every stats interval
+    pub statsd: Option<String>,
+
+    #[arg(long, value_name = "host:port")]
+    /// push Graphite plaintext lines over TCP every stats interval
+    pub graphite: Option<String>,
+
+    #[arg(long, default_value = "sirping")]
+    /// prefix of StatsD / Graphite metric paths, followed by .<host>.<metric>
+    pub metrics_prefix: String,
+
+    #[arg(long, value_name = "path|udp://host:port|http://host:port/path")]
+    /// write InfluxDB line protocol per probe and per stats interval to a file, UDP or HTTP endpoint
+    pub influx: Option<String>,
+
+    #[arg(long)]
+    /// token sent as "Authorization: Token ..." on HTTP influx writes
+    pub influx_token: Option<String>,
+
+    #[arg(long, value_name = "http://host:port[/path]")]
+    /// push OTLP/HTTP metrics to this collector every stats interval (path defaults to /v1/metrics)
+    pub otlp: Option<String>,
+
+    #[arg(long)]
+    /// send OTLP as JSON instead of protobuf
+    pub otlp_json: bool,
+
+    #[arg(long, value_name = "name=value")]
+    /// extra HTTP header on OTLP requests, e.g. for authentication; may be repeated
+    pub otlp_header: Vec<String>,
+
+    #[arg(long, value_name = "host[:port]")]
+    /// publish retained <prefix>/<host>/state and per-interval <prefix>/<host>/stats JSON to this MQTT broker
+    pub mqtt: Option<String>,
+
+    #[arg(long, default_value = "sirping")]
+    /// MQTT topic prefix; <prefix>/status is "online", or "offline" as the last will
+    pub mqtt_prefix: String,
+
+    #[arg(long)]
+    /// MQTT username
+    pub mqtt_user: Option<String>,
+
+    #[arg(long)]
+    /// MQTT password
+    pub mqtt_password: Option<String>,
+
     #[arg(short = 'R')]
     /// reset stats after each print interval (default: print cumulative stats since start)
     pub reset_stats: bool,
 
 }
 
+impl Config {
+    /// With --auto-gateway, tags the default gateway as the gateway tier, adding
+    /// it as the first target if it is not already on the command line.
+    pub fn add_auto_gateway(&mut self) -> ResultS<()> {
+        if !self.auto_gateway {
+            return Ok(());
+        }
+        let Some(gw) = crate::util::default_gateway()? else {
+            warn!("--auto-gateway: no IPv4 default route found");
+            return Ok(());
+        };
+        let key = HostKey::from(IpAddr::V4(gw));
+        if let Some(h) = self.ips.iter_mut().find(|h| h.key() == key) {
+            h.tier = Tier::Gateway;
+        } else {
+            let mut h = HostInfo::new(Some(String::from("gateway")), IpAddr::V4(gw));
+            h.tier = Tier::Gateway;
+            self.ips.insert(0, h);
+        }
+        info!("default gateway {} added as gateway tier", gw);
+        Ok(())
+    }
+}
+
+/// Parses a target of the form `host[,key=value...]`.  Recognised per-target
+/// options:
+///   netns=<name>   create the probe socket inside /var/run/netns/<name>
+///   mark=<n>       set SO_MARK (fwmark) on the probe socket, decimal or 0x hex
+///   tier=<tier>    gateway, isp or remote (the default), for fault diagnosis
 pub fn to_addr(s: &str) -> ResultS<HostInfo> {
+    let mut parts = s.split(',');
+    let addr = parts.next().unwrap_or_default();
+    let mut hostinfo = resolve_addr(addr)?;
+    for opt in parts {
+        match opt.split_once('=') {
+            Some(("netns", v)) => {
+                if v.is_empty() || v.contains('/') || v == "." || v == ".." {
+                    return Err(anyhow!("invalid netns name \"{}\" for target \"{}\"", v, s));
+                }
+                hostinfo.netns = Some(String::from(v));
+            }
+            Some(("mark", v)) => {
+                let mark = match v.strip_prefix("0x") {
+                    Some(hex) => u32::from_str_radix(hex, 16),
+                    None => v.parse::<u32>(),
+                }.with_context(|| format!("invalid mark \"{}\" for target \"{}\"", v, s))?;
+                hostinfo.mark = Some(mark);
+            }
+            Some(("tier", v)) => {
+                hostinfo.tier = to_tier(v).with_context(|| format!("for target \"{}\"", s))?;
+            }
+            _ => return Err(anyhow!("unknown target option \"{}\" for \"{}\", expected netns=<name>, mark=<n> or tier=<tier>", opt, s)),
+        }
+    }
+    Ok(hostinfo)
+}
+
+fn resolve_addr(s: &str) -> ResultS<HostInfo> {
     match s.to_socket_addrs() {
         Ok(mut ip) => {
             if let Some(x) = ip.next() {
@@ -80,6 +273,27 @@ pub fn to_addr(s: &str) -> ResultS<HostInfo> {
 pub struct HostInfo {
     pub host: Option<String> ,
     pub ip: IpAddr,
+    /// Network namespace (name under /var/run/netns) the probe socket lives in.
+    pub netns: Option<String>,
+    /// SO_MARK applied to the probe socket for fwmark based policy routing.
+    pub mark: Option<u32>,
+    /// Where the target sits on the path out, for fault diagnosis.
+    pub tier: Tier,
+}
+
+/// Identifies one probe target in `Tracks`: the same IP may be monitored
+/// from several network namespaces or routing marks at once.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct HostKey {
+    pub ip: IpAddr,
+    pub netns: Option<String>,
+    pub mark: Option<u32>,
+}
+
+impl From<IpAddr> for HostKey {
+    fn from(ip: IpAddr) -> Self {
+        HostKey { ip, netns: None, mark: None }
+    }
 }
 
 impl HostInfo {
@@ -87,17 +301,142 @@ impl HostInfo {
         HostInfo {
             host,
             ip,
+            netns: None,
+            mark: None,
+            tier: Tier::Remote,
         }
     }
+
+    pub fn key(&self) -> HostKey {
+        HostKey { ip: self.ip, netns: self.netns.clone(), mark: self.mark }
+    }
 }
 
 impl fmt::Display for HostInfo {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        if self.host.is_none() {
-            write!(f, "{}", self.ip)
-        } else {
-            write!(f, "{}({})", self.host.as_ref().unwrap(), self.ip)
+        match &self.host {
+            None => write!(f, "{}", self.ip)?,
+            Some(host) => write!(f, "{}({})", host, self.ip)?,
         }
+        match (&self.netns, self.mark) {
+            (None, None) => {}
+            (Some(netns), None) => write!(f, "[netns={}]", netns)?,
+            (None, Some(mark)) => write!(f, "[mark={:#x}]", mark)?,
+            (Some(netns), Some(mark)) => write!(f, "[netns={},mark={:#x}]", netns, mark)?,
+        }
+        Ok(())
+    }
+}
+
+/// Position of a target on the path from here to the internet, nearest first.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Tier {
+    Gateway,
+    Isp,
+    Remote,
+}
+
+impl fmt::Display for Tier {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Tier::Gateway => "gateway",
+            Tier::Isp => "isp",
+            Tier::Remote => "remote",
+        })
+    }
+}
+
+pub fn to_tier(s: &str) -> anyhow::Result<Tier, anyhow::Error> {
+    match s {
+        "gateway" | "gw" => Ok(Tier::Gateway),
+        "isp" => Ok(Tier::Isp),
+        "remote" => Ok(Tier::Remote),
+        _ => Err(anyhow::anyhow!("Error for tier: must be one of gateway, gw, isp, remote but got {}", &s))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum IpOptionKind {
+    RecordRoute,
+    Timestamp,
+}
+
+pub fn to_ip_option(s: &str) -> anyhow::Result<IpOptionKind, anyhow::Error> {
+    match s {
+        "rr" | "record-route" => Ok(IpOptionKind::RecordRoute),
+        "ts" | "timestamp" => Ok(IpOptionKind::Timestamp),
+        _ => Err(anyhow::anyhow!("Error for ip option: must be one of rr, record-route, ts, timestamp but got {}", &s))
+    }
+}
+
+/// Parses --csv-delimiter: a single character, or "tab".
+pub fn to_delimiter(s: &str) -> anyhow::Result<char, anyhow::Error> {
+    let c = match s {
+        "tab" | "\\t" => '\t',
+        _ => {
+            let mut chars = s.chars();
+            match (chars.next(), chars.next()) {
+                (Some(c), None) => c,
+                _ => return Err(anyhow::anyhow!("Error for csv delimiter: must be a single character or tab but got {}", &s)),
+            }
+        }
+    };
+    if c == '"' || c == '\n' || c == '\r' {
+        return Err(anyhow::anyhow!("Error for csv delimiter: cannot be a quote or newline"));
+    }
+    Ok(c)
+}
+
+/// Parses a listen address; a bare ":port" listens on all IPv4 addresses.
+pub fn to_listen_addr(s: &str) -> anyhow::Result<SocketAddr, anyhow::Error> {
+    if let Some(port) = s.strip_prefix(':') {
+        let port = port.parse::<u16>().with_context(|| format!("Error for listen address: bad port in {}", s))?;
+        return Ok(SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), port));
+    }
+    SocketAddr::from_str(s).with_context(|| format!("Error for listen address: expected [ip]:port but got {}", s))
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum LogSink {
+    Stderr,
+    /// RFC 5424 syslog datagrams to a unix socket.
+    Unix(PathBuf),
+    /// RFC 5424 syslog datagrams over UDP to host:port.
+    Udp(String),
+}
+
+pub fn to_log_sink(s: &str) -> anyhow::Result<LogSink, anyhow::Error> {
+    if s == "stderr" {
+        Ok(LogSink::Stderr)
+    } else if s == "syslog" {
+        Ok(LogSink::Unix(PathBuf::from("/dev/log")))
+    } else if let Some(path) = s.strip_prefix("unix:") {
+        Ok(LogSink::Unix(PathBuf::from(path)))
+    } else if let Some(addr) = s.strip_prefix("udp://") {
+        let with_port = if addr.rsplit_once(':').is_some_and(|(_, p)| p.parse::<u16>().is_ok()) && !addr.ends_with(']') {
+            addr.to_string()
+        } else {
+            format!("{}:514", addr)
+        };
+        Ok(LogSink::Udp(with_port))
+    } else {
+        Err(anyhow::anyhow!("Error for log sink: must be one of stderr, syslog, unix:<path>, udp://host[:port] but got {}", &s))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LogFormat {
+    Text,
+    Logfmt,
+    Json,
+}
+
+pub fn to_log_format(s: &str) -> anyhow::Result<LogFormat, anyhow::Error> {
+    match s {
+        "text" => Ok(LogFormat::Text),
+        "logfmt" => Ok(LogFormat::Logfmt),
+        "json" => Ok(LogFormat::Json),
+        _ => Err(anyhow::anyhow!("Error for log format: must be one of text, logfmt, json but got {}", &s))
     }
 }
 
@@ -112,3 +451,55 @@ pub fn to_log_level(s: &str) -> anyhow::Result<LevelFilter, anyhow::Error> {
         _ => Err(anyhow::anyhow!("Error for log level: must be one of off, o, error, e, warn, w, info, i, debug, d, trace, t but got {}", &s))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn netns_target_option() {
+        let h = to_addr("192.0.2.1,netns=blue").unwrap();
+        assert_eq!(h.ip, IpAddr::from([192, 0, 2, 1]));
+        assert_eq!(h.netns.as_deref(), Some("blue"));
+        assert_eq!(h.to_string(), "192.0.2.1[netns=blue]");
+        assert_eq!(to_addr("192.0.2.1").unwrap().to_string(), "192.0.2.1");
+
+        for bad in ["192.0.2.1,netns=", "192.0.2.1,netns=a/b", "192.0.2.1,netns=.",
+                    "192.0.2.1,netns=..", "192.0.2.1,vrf=blue", "192.0.2.1,netns"] {
+            assert!(to_addr(bad).is_err(), "{} accepted", bad);
+        }
+    }
+
+    #[test]
+    fn same_ip_in_two_namespaces_is_two_targets() {
+        let blue = to_addr("192.0.2.1,netns=blue").unwrap().key();
+        let red = to_addr("192.0.2.1,netns=red").unwrap().key();
+        let plain = to_addr("192.0.2.1").unwrap().key();
+        assert_ne!(blue, red);
+        assert_ne!(blue, plain);
+        assert_eq!(plain, HostKey::from(IpAddr::from([192, 0, 2, 1])));
+    }
+
+    #[test]
+    fn mark_target_option() {
+        assert_eq!(to_addr("192.0.2.1,mark=42").unwrap().mark, Some(42));
+        let h = to_addr("192.0.2.1,mark=0x1f").unwrap();
+        assert_eq!(h.mark, Some(0x1f));
+        assert_eq!(h.to_string(), "192.0.2.1[mark=0x1f]");
+        let h = to_addr("192.0.2.1,netns=blue,mark=16").unwrap();
+        assert_eq!(h.to_string(), "192.0.2.1[netns=blue,mark=0x10]");
+
+        for bad in ["192.0.2.1,mark=", "192.0.2.1,mark=x", "192.0.2.1,mark=0xzz",
+                    "192.0.2.1,mark=-1", "192.0.2.1,mark=4294967296"] {
+            assert!(to_addr(bad).is_err(), "{} accepted", bad);
+        }
+    }
+
+    #[test]
+    fn same_ip_with_two_marks_is_two_targets() {
+        let one = to_addr("192.0.2.1,mark=1").unwrap().key();
+        let two = to_addr("192.0.2.1,mark=2").unwrap().key();
+        assert_ne!(one, two);
+        assert_ne!(one, HostKey::from(one.ip));
+    }
+}
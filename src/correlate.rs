@@ -0,0 +1,171 @@
+#![allow(dead_code)]
+use std::time::{Duration, SystemTime};
+use crate::cli::{HostInfo, HostKey};
+use humantime::format_rfc3339_millis;
+use log::{debug, warn};
+
+/// Hosts that must go down together before it counts as a common cause.
+const MIN_HOSTS: usize = 2;
+
+/// Several hosts' outages that started within the correlation window of each other.
+#[derive(Clone)]
+pub struct CommonOutage {
+    /// Earliest start among the hosts.
+    pub start: SystemTime,
+    /// When the last of them came back; None while any is still down.
+    pub end: Option<SystemTime>,
+    pub hosts: Vec<HostInfo>,
+}
+
+struct Group {
+    outage: CommonOutage,
+    /// Latest start among the members; `outage.start` is the earliest.
+    latest: SystemTime,
+    /// Members still down.
+    down: Vec<HostKey>,
+    keys: Vec<HostKey>,
+}
+
+/// Groups per-host outages whose start times fall within `window` of a
+/// member's, so a group can grow past the window as long as each start is
+/// close to the previous ones.  A group is only reported once MIN_HOSTS hosts are
+/// in it; single host outages are left to the per-host outage list.  Each
+/// group is logged once, when its last host is back.
+pub struct Correlator {
+    window: Duration,
+    total_hosts: usize,
+    groups: Vec<Group>,
+    completed: Vec<CommonOutage>,
+}
+
+impl Correlator {
+    /// A zero `window` disables correlation.
+    pub fn new(window: Duration, total_hosts: usize) -> Correlator {
+        Correlator { window, total_hosts, groups: Vec::new(), completed: Vec::new() }
+    }
+
+    /// A host became unreachable; `start` is when its miss streak began.
+    pub fn host_down(&mut self, key: &HostKey, host: &HostInfo, start: SystemTime) {
+        if self.window.is_zero() {
+            return;
+        }
+        let window = self.window;
+        let no = match self.groups.iter().position(|g| start + window >= g.outage.start && start <= g.latest + window) {
+            Some(no) => no,
+            None => {
+                self.groups.push(Group {
+                    outage: CommonOutage { start, end: None, hosts: Vec::new() },
+                    latest: start,
+                    down: Vec::new(),
+                    keys: Vec::new(),
+                });
+                self.groups.len() - 1
+            }
+        };
+        let g = &mut self.groups[no];
+        if !g.keys.contains(key) {
+            g.keys.push(key.clone());
+            g.outage.hosts.push(host.clone());
+        }
+        if !g.down.contains(key) {
+            g.down.push(key.clone());
+        }
+        g.outage.start = g.outage.start.min(start);
+        g.latest = g.latest.max(start);
+        if g.outage.hosts.len() >= MIN_HOSTS {
+            debug!("common-cause outage from {}: {} joined, {} of {} hosts",
+                   format_rfc3339_millis(g.outage.start), host, g.outage.hosts.len(), self.total_hosts);
+        }
+    }
+
+    /// A host is reachable again.  Closes its group once every member is back,
+    /// returning it if it was a common-cause outage.
+    pub fn host_up(&mut self, key: &HostKey, at: SystemTime) -> Option<CommonOutage> {
+        let no = self.groups.iter().position(|g| g.down.contains(key))?;
+        let g = &mut self.groups[no];
+        g.down.retain(|k| k != key);
+        if !g.down.is_empty() {
+            return None;
+        }
+        let mut g = self.groups.remove(no);
+        if g.outage.hosts.len() < MIN_HOSTS {
+            return None;
+        }
+        g.outage.end = Some(at);
+        let names = g.outage.hosts.iter().map(|h| h.to_string()).collect::<Vec<_>>().join(", ");
+        warn!("common-cause outage from {} to {}: {} of {} hosts down within {} of each other: {}",
+              format_rfc3339_millis(g.outage.start), format_rfc3339_millis(at), g.outage.hosts.len(), self.total_hosts,
+              humantime::format_duration(self.window), names);
+        self.completed.push(g.outage.clone());
+        Some(g.outage)
+    }
+
+    /// Completed common outages followed by ongoing ones; with `reset` the
+    /// completed ones are drained.
+    pub fn report(&mut self, reset: bool) -> Vec<CommonOutage> {
+        let mut out = if reset { std::mem::take(&mut self.completed) } else { self.completed.clone() };
+        out.extend(self.groups.iter().filter(|g| g.outage.hosts.len() >= MIN_HOSTS).map(|g| g.outage.clone()));
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn host(n: u8) -> HostInfo {
+        HostInfo::new(None, std::net::IpAddr::from([192, 0, 2, n]))
+    }
+
+    fn at(s: u64) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(s)
+    }
+
+    #[test]
+    fn chains_by_latest_start() {
+        let mut c = Correlator::new(Duration::from_secs(5), 4);
+        for (n, s) in [(1, 0), (2, 4), (3, 8)] {
+            c.host_down(&host(n).key(), &host(n), at(s));
+        }
+        let r = c.report(false);
+        assert_eq!(r.len(), 1);
+        assert_eq!(r[0].hosts.len(), 3);
+        assert_eq!(r[0].start, at(0));
+        assert_eq!(r[0].end, None);
+    }
+
+    #[test]
+    fn far_apart_starts_stay_separate() {
+        let mut c = Correlator::new(Duration::from_secs(5), 4);
+        for (n, s) in [(1, 0), (2, 4), (3, 10), (4, 30)] {
+            c.host_down(&host(n).key(), &host(n), at(s));
+        }
+        let r = c.report(false);
+        assert_eq!(r.len(), 1);
+        assert_eq!(r[0].hosts.len(), 2);
+    }
+
+    #[test]
+    fn ends_when_all_members_are_back() {
+        let mut c = Correlator::new(Duration::from_secs(5), 2);
+        c.host_down(&host(1).key(), &host(1), at(0));
+        c.host_down(&host(2).key(), &host(2), at(1));
+        assert!(c.host_up(&host(1).key(), at(20)).is_none());
+        assert_eq!(c.report(false)[0].end, None);
+        // handed back once, for the whole group, by the last host up
+        let done = c.host_up(&host(2).key(), at(25)).unwrap();
+        assert_eq!((done.start, done.end, done.hosts.len()), (at(0), Some(at(25)), 2));
+        assert!(c.host_up(&host(2).key(), at(26)).is_none());
+        let r = c.report(true);
+        assert_eq!(r[0].end, Some(at(25)));
+        assert!(c.report(false).is_empty());
+    }
+
+    #[test]
+    fn single_host_outage_is_not_common() {
+        let mut c = Correlator::new(Duration::from_secs(5), 2);
+        c.host_down(&host(1).key(), &host(1), at(0));
+        assert!(c.host_up(&host(1).key(), at(5)).is_none());
+        assert!(c.report(false).is_empty());
+    }
+}
@@ -0,0 +1,191 @@
+#![allow(dead_code)]
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::sync::{Mutex, OnceLock};
+use std::time::SystemTime;
+use anyhow::{bail, Context, Result};
+use humantime::format_rfc3339_micros;
+use log::warn;
+use crate::cli::HostInfo;
+use crate::events::ProbeEvent;
+
+/// Columns of the per-probe file; only ever append new ones at the end.
+pub const PROBE_HEADER: &[&str] = &[
+    "ts", "host", "ip", "netns", "mark", "flow", "seq", "ident", "outcome", "rtt_us", "ttl", "error",
+];
+
+/// Columns of the per-interval stats file; only ever append new ones at the end.
+pub const STATS_HEADER: &[&str] = &[
+    "ts", "mode", "host", "ip", "netns", "mark", "flow", "ident", "state",
+    "reply", "nonreply", "timeout", "loss_pct", "avg_ms", "min_ms", "max_ms", "stdev_ms", "jitter_ms",
+    "ipdv_avg_ms", "ipdv_max_ms", "p50_ms", "p90_ms", "p99_ms", "ttl", "hops_min", "hops_max", "r", "mos",
+];
+
+pub struct CsvWriter {
+    w: Mutex<Box<dyn Write + Send>>,
+    delim: char,
+}
+
+impl CsvWriter {
+    /// Opens `path` ("-" for stdout) for appending, writing `header` unless
+    /// the file already has content.  A file whose first line is a different
+    /// header is refused rather than mixing two layouts in one file.
+    pub fn open(path: &str, delim: char, header: &[&str]) -> Result<CsvWriter> {
+        let (w, empty): (Box<dyn Write + Send>, bool) = if path == "-" {
+            (Box::new(std::io::stdout()), true)
+        } else {
+            let f = OpenOptions::new().create(true).append(true).open(path)
+                .with_context(|| format!("opening csv file \"{}\"", path))?;
+            let empty = f.metadata().map(|m| m.len() == 0).unwrap_or(true);
+            (Box::new(f), empty)
+        };
+        let csv = CsvWriter { w: Mutex::new(w), delim };
+        let header_line = csv.row(header);
+        if empty {
+            csv.write(&[header_line])?;
+        } else {
+            let mut first = String::new();
+            BufReader::new(File::open(path).with_context(|| format!("reading csv file \"{}\"", path))?)
+                .read_line(&mut first).with_context(|| format!("reading csv file \"{}\"", path))?;
+            if first != header_line {
+                bail!("csv file \"{}\" has a different header, expected \"{}\"; move it away or pick another file",
+                      path, header_line.trim_end());
+            }
+        }
+        Ok(csv)
+    }
+
+    fn push_field(&self, line: &mut String, field: &str) {
+        if field.contains([self.delim, '"', '\n', '\r']) {
+            line.push('"');
+            line.push_str(&field.replace('"', "\"\""));
+            line.push('"');
+        } else {
+            line.push_str(field);
+        }
+    }
+
+    /// One line, newline included, with fields quoted where needed.
+    pub fn row<S: AsRef<str>>(&self, fields: &[S]) -> String {
+        let mut line = String::with_capacity(128);
+        for (i, f) in fields.iter().enumerate() {
+            if i > 0 {
+                line.push(self.delim);
+            }
+            self.push_field(&mut line, f.as_ref());
+        }
+        line.push('\n');
+        line
+    }
+
+    pub fn write(&self, lines: &[String]) -> std::io::Result<()> {
+        let mut w = self.w.lock().unwrap();
+        lines.iter().try_for_each(|l| w.write_all(l.as_bytes()))?;
+        w.flush()
+    }
+}
+
+static PROBES: OnceLock<CsvWriter> = OnceLock::new();
+static STATS: OnceLock<CsvWriter> = OnceLock::new();
+
+pub fn open_probes(path: &str, delim: char) -> Result<()> {
+    let _ = PROBES.set(CsvWriter::open(path, delim, PROBE_HEADER)?);
+    Ok(())
+}
+
+pub fn open_stats(path: &str, delim: char) -> Result<()> {
+    let _ = STATS.set(CsvWriter::open(path, delim, STATS_HEADER)?);
+    Ok(())
+}
+
+pub fn stats_enabled() -> bool {
+    STATS.get().is_some()
+}
+
+fn write(sink: &OnceLock<CsvWriter>, lines: &[String]) {
+    if lines.is_empty() {
+        return;
+    }
+    if let Some(w) = sink.get() {
+        if let Err(e) = w.write(lines) {
+            warn!("error writing csv row: {}", e);
+        }
+    }
+}
+
+/// Target columns shared by both files: host, ip, netns, mark.
+pub fn host_fields(h: &HostInfo) -> [String; 4] {
+    [
+        h.host.clone().unwrap_or_else(|| h.ip.to_string()),
+        h.ip.to_string(),
+        h.netns.clone().unwrap_or_default(),
+        h.mark.map_or(String::new(), |m| m.to_string()),
+    ]
+}
+
+pub fn ts(t: SystemTime) -> String {
+    format_rfc3339_micros(t).to_string()
+}
+
+/// A PROBE_HEADER line for `p`, to be written with `write_probes`.
+pub fn probe(p: &ProbeEvent) -> Option<String> {
+    let w = PROBES.get()?;
+    let mut row = vec![ts(p.sent)];
+    row.extend(host_fields(p.host));
+    row.extend([
+        p.flow.to_string(),
+        p.seq.to_string(),
+        p.ident.to_string(),
+        p.outcome().to_string(),
+        p.rtt_us.map_or(String::new(), |v| v.to_string()),
+        p.ttl.map_or(String::new(), |v| v.to_string()),
+        p.error_class().unwrap_or_default().to_string(),
+    ]);
+    Some(w.row(&row))
+}
+
+/// Writes lines built by `probe`.  Callers holding the `Tracks` lock collect
+/// them and write once it is released.
+pub fn write_probes(lines: &[String]) {
+    write(&PROBES, lines);
+}
+
+/// One row of STATS_HEADER, built by the report.
+pub fn stats_row(row: &[String]) {
+    if let Some(w) = STATS.get() {
+        write(&STATS, &[w.row(row)]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn writer(delim: char) -> CsvWriter {
+        CsvWriter { w: Mutex::new(Box::new(std::io::sink())), delim }
+    }
+
+    #[test]
+    fn quotes_only_fields_that_need_it() {
+        let w = writer(',');
+        assert_eq!(w.row(&["a", "b;c", ""]), "a,b;c,\n");
+        assert_eq!(w.row(&["a,b", "say \"hi\"", "l1\nl2", "cr\r"]), "\"a,b\",\"say \"\"hi\"\"\",\"l1\nl2\",\"cr\r\"\n");
+        assert_eq!(writer(';').row(&["a,b", "a;b"]), "a,b;\"a;b\"\n");
+        assert_eq!(writer('\t').row(&["a\tb", r"back\slash"]), "\"a\tb\"\tback\\slash\n");
+    }
+
+    #[test]
+    fn append_checks_the_header() {
+        let path = std::env::temp_dir().join(format!("sirping-csv-test-{}", std::process::id()));
+        let path = path.to_str().unwrap();
+        let _ = std::fs::remove_file(path);
+        CsvWriter::open(path, ',', PROBE_HEADER).unwrap();
+        // same header: appended to
+        CsvWriter::open(path, ',', PROBE_HEADER).unwrap();
+        assert!(CsvWriter::open(path, ',', STATS_HEADER).is_err());
+        assert!(CsvWriter::open(path, ';', PROBE_HEADER).is_err());
+        let content = std::fs::read_to_string(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(content, writer(',').row(PROBE_HEADER));
+    }
+}
@@ -0,0 +1,120 @@
+#![allow(dead_code)]
+use std::fmt;
+use crate::cli::{HostInfo, Tier};
+use crate::health::HealthState;
+
+/// Where a fault most likely is, judged from which tiers of targets are reachable.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Verdict {
+    AllUp,
+    /// Every gateway target is down.
+    LanDown,
+    /// Gateways answer but every ISP edge target is down.
+    IspDown,
+    /// Everything nearer answers but every remote target is down.
+    RemoteDown,
+    /// Some, not all, targets of a tier are down while every nearer tier is up.
+    Only(Tier, Vec<String>),
+    /// Nothing is down, but targets of this tier, the nearest with any, are
+    /// degraded or flapping.
+    Degraded(Tier, Vec<String>),
+}
+
+impl fmt::Display for Verdict {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Verdict::AllUp => write!(f, "all up"),
+            Verdict::LanDown => write!(f, "LAN down (gateway unreachable)"),
+            Verdict::IspDown => write!(f, "ISP down (gateway up, ISP edge unreachable)"),
+            Verdict::RemoteDown => write!(f, "upstream down (all remote targets unreachable)"),
+            Verdict::Only(tier, hosts) => write!(f, "{} {} only", tier, hosts.join(", ")),
+            Verdict::Degraded(tier, hosts) => write!(f, "{} {} degraded", tier, hosts.join(", ")),
+        }
+    }
+}
+
+const TIERS: [Tier; 3] = [Tier::Gateway, Tier::Isp, Tier::Remote];
+
+/// Walks the tiers nearest first and blames the first one with a target down:
+/// the whole tier if none of it answers, otherwise just the targets that don't.
+/// Targets further out than a fully failed tier are not looked at.  With
+/// every target reachable, the nearest tier with degraded or flapping targets
+/// is named instead.  Each host comes with whether it is reachable and its
+/// health state.
+pub fn diagnose<'a>(hosts: impl IntoIterator<Item = (&'a HostInfo, bool, HealthState)>) -> Verdict {
+    let hosts: Vec<(&HostInfo, bool, HealthState)> = hosts.into_iter().collect();
+    for tier in TIERS {
+        let in_tier: Vec<&(&HostInfo, bool, HealthState)> = hosts.iter().filter(|(h, _, _)| h.tier == tier).collect();
+        let down: Vec<String> = in_tier.iter().filter(|(_, up, _)| !up).map(|(h, _, _)| h.to_string()).collect();
+        if down.is_empty() {
+            continue;
+        }
+        if down.len() < in_tier.len() {
+            return Verdict::Only(tier, down);
+        }
+        return match tier {
+            Tier::Gateway => Verdict::LanDown,
+            Tier::Isp => Verdict::IspDown,
+            Tier::Remote => Verdict::RemoteDown,
+        };
+    }
+    for tier in TIERS {
+        let degraded: Vec<String> = hosts.iter()
+            .filter(|(h, _, state)| h.tier == tier && matches!(state, HealthState::Degraded | HealthState::Flapping))
+            .map(|(h, _, _)| h.to_string())
+            .collect();
+        if !degraded.is_empty() {
+            return Verdict::Degraded(tier, degraded);
+        }
+    }
+    Verdict::AllUp
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use HealthState::{Degraded, Down, Flapping, Up};
+
+    fn host(name: &str, tier: Tier) -> HostInfo {
+        let mut h = HostInfo::new(Some(name.to_string()), "192.0.2.1".parse().unwrap());
+        h.tier = tier;
+        h
+    }
+
+    #[test]
+    fn verdicts() {
+        let gw = [host("gw1", Tier::Gateway), host("gw2", Tier::Gateway)];
+        let isp = host("edge", Tier::Isp);
+        let remote = [host("r1", Tier::Remote), host("r2", Tier::Remote)];
+        let targets = [&gw[0], &gw[1], &isp, &remote[0], &remote[1]];
+        let names = |hosts: &[&HostInfo]| hosts.iter().map(|h| h.to_string()).collect();
+        let only = |tier, hosts: &[&HostInfo]| Verdict::Only(tier, names(hosts));
+        let degraded = |tier, hosts: &[&HostInfo]| Verdict::Degraded(tier, names(hosts));
+        // states of gw1, gw2, edge, r1, r2
+        let cases = [
+            ([Up, Up, Up, Up, Up], Verdict::AllUp),
+            ([Down, Down, Down, Down, Down], Verdict::LanDown),
+            ([Down, Up, Down, Down, Down], only(Tier::Gateway, &[&gw[0]])),
+            ([Up, Up, Down, Down, Down], Verdict::IspDown),
+            ([Up, Up, Up, Down, Down], Verdict::RemoteDown),
+            ([Up, Up, Up, Up, Down], only(Tier::Remote, &[&remote[1]])),
+            // a fully failed tier hides what is beyond it
+            ([Down, Down, Up, Up, Up], Verdict::LanDown),
+            ([Up, Up, Up, Degraded, Up], degraded(Tier::Remote, &[&remote[0]])),
+            ([Up, Flapping, Up, Degraded, Up], degraded(Tier::Gateway, &[&gw[1]])),
+            // down beats degraded wherever it is
+            ([Degraded, Up, Up, Up, Down], only(Tier::Remote, &[&remote[1]])),
+        ];
+        for (states, want) in cases {
+            let hosts = targets.iter().zip(states).map(|(h, s)| (*h, s != Down, s));
+            assert_eq!(diagnose(hosts), want, "{:?}", states);
+        }
+    }
+
+    #[test]
+    fn flapping_counts_as_down_while_unreachable() {
+        let r = host("r1", Tier::Remote);
+        assert_eq!(diagnose([(&r, false, Flapping)]), Verdict::RemoteDown);
+        assert_eq!(diagnose([(&r, true, Flapping)]), Verdict::Degraded(Tier::Remote, vec![r.to_string()]));
+    }
+}
@@ -0,0 +1,86 @@
+#![allow(dead_code)]
+// Simplified ITU-T G.107 E-model, as commonly used to turn ping style
+// measurements into a voice quality estimate.  Assumes G.711 with packet loss
+// concealment (Ie = 0, Bpl = 25.1), a 10ms codec delay, and a jitter buffer
+// sized at twice the measured jitter.
+
+/// Basic signal-to-noise ratio with default G.107 parameters (Ro - Is).
+const R_BASE: f64 = 93.2;
+const CODEC_DELAY_MS: f64 = 10.0;
+const IE: f64 = 0.0;
+const BPL: f64 = 25.1;
+
+#[derive(Debug, Clone, Copy)]
+pub struct VoiceQuality {
+    /// Transmission rating factor, 0 (unusable) to ~93 (toll quality ceiling).
+    pub r: f64,
+    /// Mean opinion score estimate, 1.0 to 4.5.
+    pub mos: f64,
+}
+
+/// Estimates voice quality from the average round trip time, jitter and loss
+/// percentage of a path.  One way delay is taken as half the RTT.
+pub fn estimate(rtt_ms: f64, jitter_ms: f64, loss_pct: f64) -> VoiceQuality {
+    let delay_ms = rtt_ms / 2.0 + 2.0 * jitter_ms + CODEC_DELAY_MS;
+    let id = if delay_ms > 177.3 {
+        0.024 * delay_ms + 0.11 * (delay_ms - 177.3)
+    } else {
+        0.024 * delay_ms
+    };
+    let ppl = loss_pct.clamp(0.0, 100.0);
+    let ie_eff = IE + (95.0 - IE) * ppl / (ppl + BPL);
+    let r = (R_BASE - id - ie_eff).clamp(0.0, 100.0);
+    VoiceQuality { r, mos: mos_from_r(r) }
+}
+
+fn mos_from_r(r: f64) -> f64 {
+    if r <= 0.0 {
+        1.0
+    } else if r >= 100.0 {
+        4.5
+    } else {
+        1.0 + 0.035 * r + 7.0e-6 * r * (r - 60.0) * (100.0 - r)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_quality(rtt_ms: f64, jitter_ms: f64, loss_pct: f64, r: f64, mos: f64) {
+        let q = estimate(rtt_ms, jitter_ms, loss_pct);
+        assert!((q.r - r).abs() < 0.001, "R for {}/{}/{}: {} != {}", rtt_ms, jitter_ms, loss_pct, q.r, r);
+        assert!((q.mos - mos).abs() < 0.001, "MOS for {}/{}/{}: {} != {}", rtt_ms, jitter_ms, loss_pct, q.mos, mos);
+    }
+
+    #[test]
+    fn delay_only() {
+        // only the 10ms codec delay
+        assert_quality(0.0, 0.0, 0.0, 92.96, 4.4046);
+        // 50ms one way + 2 x 5ms jitter buffer + 10ms codec
+        assert_quality(100.0, 5.0, 0.0, 91.52, 4.3744);
+        // 210ms one way is past the 177.3ms knee
+        assert_quality(400.0, 0.0, 0.0, 84.563, 4.1842);
+    }
+
+    #[test]
+    fn loss() {
+        assert_quality(0.0, 0.0, 1.0, 89.3202, 4.3220);
+        assert_quality(20.0, 2.0, 5.0, 76.8433, 3.8993);
+        assert_quality(0.0, 0.0, 100.0, 17.0208, 1.1708);
+        // out of range loss is clamped
+        assert_quality(0.0, 0.0, 250.0, 17.0208, 1.1708);
+    }
+
+    #[test]
+    fn clamped_at_unusable() {
+        assert_quality(3000.0, 0.0, 0.0, 0.0, 1.0);
+    }
+
+    #[test]
+    fn mos_curve() {
+        assert_eq!(mos_from_r(0.0), 1.0);
+        assert_eq!(mos_from_r(100.0), 4.5);
+        assert!((mos_from_r(50.0) - 2.575).abs() < 1e-9);
+    }
+}
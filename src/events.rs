@@ -0,0 +1,185 @@
+#![allow(dead_code)]
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::sync::{Mutex, OnceLock};
+use std::time::SystemTime;
+use anyhow::{Context, Result};
+use humantime::format_rfc3339_micros;
+use log::warn;
+use crate::cli::HostInfo;
+use crate::correlate::CommonOutage;
+use crate::json::JsonObject;
+
+/// JSON Lines sink for --events-json; unset means events are off.
+static SINK: OnceLock<Mutex<Box<dyn Write + Send>>> = OnceLock::new();
+
+/// Opens the events sink: "-" is stdout, anything else a file appended to.
+pub fn open(path: &str) -> Result<()> {
+    let w: Box<dyn Write + Send> = if path == "-" {
+        Box::new(std::io::stdout())
+    } else {
+        Box::new(OpenOptions::new().create(true).append(true).open(path)
+            .with_context(|| format!("opening events file \"{}\"", path))?)
+    };
+    let _ = SINK.set(Mutex::new(w));
+    Ok(())
+}
+
+pub fn enabled() -> bool {
+    SINK.get().is_some()
+}
+
+/// Writes lines built by the functions below.  Callers holding the `Tracks`
+/// lock collect them and write once it is released.
+pub fn write(lines: &[String]) {
+    if lines.is_empty() {
+        return;
+    }
+    if let Some(sink) = SINK.get() {
+        let mut w = sink.lock().unwrap();
+        if let Err(e) = lines.iter().try_for_each(|l| w.write_all(l.as_bytes())).and_then(|_| w.flush()) {
+            warn!("error writing event: {}", e);
+        }
+    }
+}
+
+fn line(obj: JsonObject) -> String {
+    let mut line = obj.finish();
+    line.push('\n');
+    line
+}
+
+/// The fields naming a target.
+fn target(obj: JsonObject, h: &HostInfo) -> JsonObject {
+    obj.str("host", &h.host.clone().unwrap_or_else(|| h.ip.to_string()))
+        .str("ip", &h.ip.to_string())
+        .opt_str("netns", h.netns.as_deref())
+        .opt_num("mark", h.mark)
+}
+
+/// Common leading fields: timestamp, event type and which target it is about.
+fn header(kind: &str, at: SystemTime, h: &HostInfo) -> JsonObject {
+    target(JsonObject::new()
+        .str("ts", &format_rfc3339_micros(at).to_string())
+        .str("type", kind), h)
+}
+
+/// Result of one probe: answered (`rtt_us` set), timed out, or failed with `error`.
+pub struct ProbeEvent<'a> {
+    pub host: &'a HostInfo,
+    pub flow: usize,
+    /// When the probe was sent.
+    pub sent: SystemTime,
+    pub seq: u16,
+    pub ident: u16,
+    pub rtt_us: Option<u64>,
+    pub ttl: Option<u8>,
+    /// Error class when it failed, e.g. "timeout", "send", "io".
+    pub error: Option<&'a str>,
+}
+
+impl ProbeEvent<'_> {
+    /// "reply", "timeout" or "error".
+    pub fn outcome(&self) -> &'static str {
+        match (self.rtt_us, self.error) {
+            (Some(_), _) => "reply",
+            (None, None | Some("timeout")) => "timeout",
+            (None, Some(_)) => "error",
+        }
+    }
+
+    /// Error class, "timeout" for a plain timeout and None for a reply.
+    pub fn error_class(&self) -> Option<&str> {
+        match self.outcome() {
+            "reply" => None,
+            _ => Some(self.error.unwrap_or("timeout")),
+        }
+    }
+}
+
+pub fn probe(p: &ProbeEvent) -> Option<String> {
+    if !enabled() {
+        return None;
+    }
+    Some(line(header("probe", p.sent, p.host)
+        .num("flow", p.flow)
+        .num("seq", p.seq)
+        .num("ident", p.ident)
+        .str("outcome", p.outcome())
+        .opt_num("rtt_us", p.rtt_us)
+        .opt_num("ttl", p.ttl)
+        .opt_str("error", p.error_class())))
+}
+
+pub fn state_change(h: &HostInfo, at: SystemTime, from: &str, to: &str) -> Option<String> {
+    if !enabled() {
+        return None;
+    }
+    Some(line(header("state", at, h).str("from", from).str("to", to)))
+}
+
+/// The host just went down; `missed` rounds so far since `start`.
+pub fn outage_start(h: &HostInfo, start: SystemTime, at: SystemTime, missed: u32) -> Option<String> {
+    if !enabled() {
+        return None;
+    }
+    Some(line(header("outage_start", at, h)
+        .str("start", &format_rfc3339_micros(start).to_string())
+        .num("missed", missed)))
+}
+
+/// A completed outage, written when the host is back up.
+pub fn outage(h: &HostInfo, start: SystemTime, end: SystemTime, missed: u32) -> Option<String> {
+    if !enabled() {
+        return None;
+    }
+    Some(line(header("outage", end, h)
+        .str("start", &format_rfc3339_micros(start).to_string())
+        .str("end", &format_rfc3339_micros(end).to_string())
+        .num("duration_ms", end.duration_since(start).unwrap_or_default().as_millis())
+        .num("missed", missed)))
+}
+
+/// A completed common-cause outage, once for the whole group.
+pub fn common_outage(c: &CommonOutage) -> Option<String> {
+    if !enabled() {
+        return None;
+    }
+    Some(common_outage_line(c))
+}
+
+fn common_outage_line(c: &CommonOutage) -> String {
+    let end = c.end.unwrap_or(c.start);
+    let hosts = c.hosts.iter().map(|h| target(JsonObject::new(), h).finish()).collect::<Vec<_>>().join(",");
+    line(JsonObject::new()
+        .str("ts", &format_rfc3339_micros(end).to_string())
+        .str("type", "common_outage")
+        .str("start", &format_rfc3339_micros(c.start).to_string())
+        .str("end", &format_rfc3339_micros(end).to_string())
+        .num("duration_ms", end.duration_since(c.start).unwrap_or_default().as_millis())
+        .raw("hosts", &format!("[{}]", hosts)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn one_common_outage_line_for_the_group() {
+        let t0 = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let mut b = HostInfo::new(Some("b".to_string()), "192.0.2.2".parse().unwrap());
+        b.netns = Some("blue".to_string());
+        b.mark = Some(7);
+        let c = CommonOutage {
+            start: t0,
+            end: Some(t0 + Duration::from_millis(2500)),
+            hosts: vec![HostInfo::new(None, "192.0.2.1".parse().unwrap()), b],
+        };
+        assert_eq!(common_outage_line(&c), concat!(
+            r#"{"ts":"2023-11-14T22:13:22.500000Z","type":"common_outage","start":"2023-11-14T22:13:20.000000Z","#,
+            r#""end":"2023-11-14T22:13:22.500000Z","duration_ms":2500,"hosts":["#,
+            r#"{"host":"192.0.2.1","ip":"192.0.2.1","netns":null,"mark":null},"#,
+            r#"{"host":"b","ip":"192.0.2.2","netns":"blue","mark":7}]}"#, "\n"));
+    }
+}
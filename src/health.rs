@@ -0,0 +1,254 @@
+#![allow(dead_code)]
+use std::collections::VecDeque;
+use std::fmt;
+use std::time::{Duration, SystemTime};
+
+/// Probe outcomes kept for the Degraded loss percentage.
+const LOSS_WINDOW: usize = 20;
+/// Probes seen before Degraded or Flapping can be reported; a loss rate over
+/// a handful of probes, or a few early flips, says too little.
+const MIN_PROBES: u32 = 10;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HealthState {
+    Up,
+    /// Reachable, but losing at least the configured share of recent probes.
+    Degraded,
+    Down,
+    /// Going up and down too often to call either.
+    Flapping,
+}
+
+impl fmt::Display for HealthState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            HealthState::Up => "up",
+            HealthState::Degraded => "degraded",
+            HealthState::Down => "down",
+            HealthState::Flapping => "flapping",
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct HealthConfig {
+    /// Consecutive misses before a host is Down.
+    pub down_after: u32,
+    /// Consecutive replies before a Down host is back Up.
+    pub up_after: u32,
+    /// Loss percentage over the last LOSS_WINDOW probes that makes a host Degraded.
+    pub degraded_loss_pct: f64,
+    /// Up/down flips within `flap_window` that make a host Flapping (0 disables).
+    pub flap_count: u32,
+    pub flap_window: Duration,
+}
+
+/// A state change, returned so the caller can log and record it.
+pub struct Transition {
+    pub from: HealthState,
+    pub to: HealthState,
+    pub at: SystemTime,
+}
+
+/// Per-host health with hysteresis.  Reachability only flips after
+/// `down_after` misses or `up_after` replies in a row; the reported state is
+/// derived from reachability, recent loss and how often reachability flipped.
+pub struct Health {
+    cfg: HealthConfig,
+    state: HealthState,
+    since: SystemTime,
+    reachable: bool,
+    /// Probes seen since start, up to MIN_PROBES.
+    probes: u32,
+    misses: u32,
+    replies: u32,
+    recent: VecDeque<bool>,
+    flips: VecDeque<SystemTime>,
+}
+
+impl Health {
+    pub fn new(cfg: HealthConfig) -> Health {
+        Health {
+            cfg,
+            state: HealthState::Up,
+            since: SystemTime::now(),
+            reachable: true,
+            probes: 0,
+            misses: 0,
+            replies: 0,
+            recent: VecDeque::with_capacity(LOSS_WINDOW),
+            flips: VecDeque::new(),
+        }
+    }
+
+    pub fn state(&self) -> HealthState {
+        self.state
+    }
+
+    pub fn since(&self) -> SystemTime {
+        self.since
+    }
+
+    /// False once `down_after` misses in a row were seen, until `up_after` replies in a row.
+    pub fn is_reachable(&self) -> bool {
+        self.reachable
+    }
+
+    pub fn recent_loss_pct(&self) -> f64 {
+        if self.recent.is_empty() {
+            return 0.0;
+        }
+        let lost = self.recent.iter().filter(|ok| !**ok).count();
+        lost as f64 * 100.0 / self.recent.len() as f64
+    }
+
+    pub fn on_probe(&mut self, ok: bool, now: SystemTime) -> Option<Transition> {
+        if self.recent.len() == LOSS_WINDOW {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(ok);
+        self.probes = (self.probes + 1).min(MIN_PROBES);
+
+        if ok {
+            self.replies = self.replies.saturating_add(1);
+            self.misses = 0;
+            if !self.reachable && self.replies >= self.cfg.up_after {
+                self.reachable = true;
+                self.flips.push_back(now);
+            }
+        } else {
+            self.misses = self.misses.saturating_add(1);
+            self.replies = 0;
+            if self.reachable && self.misses >= self.cfg.down_after {
+                self.reachable = false;
+                self.flips.push_back(now);
+            }
+        }
+        while let Some(t) = self.flips.front() {
+            if now.duration_since(*t).unwrap_or_default() > self.cfg.flap_window {
+                self.flips.pop_front();
+            } else {
+                break;
+            }
+        }
+
+        let enough = self.probes >= MIN_PROBES;
+        let next = if enough && self.cfg.flap_count > 0 && self.flips.len() >= self.cfg.flap_count as usize {
+            HealthState::Flapping
+        } else if !self.reachable {
+            HealthState::Down
+        } else if enough && self.recent_loss_pct() >= self.cfg.degraded_loss_pct {
+            HealthState::Degraded
+        } else {
+            HealthState::Up
+        };
+        if next == self.state {
+            return None;
+        }
+        let from = self.state;
+        self.state = next;
+        self.since = now;
+        Some(Transition { from, to: next, at: now })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn health(down_after: u32, up_after: u32, flap_count: u32) -> Health {
+        Health::new(HealthConfig {
+            down_after,
+            up_after,
+            degraded_loss_pct: 20.0,
+            flap_count,
+            flap_window: Duration::from_secs(60),
+        })
+    }
+
+    /// Feeds one probe a second from t0 and returns the state after each.
+    fn run(h: &mut Health, t0: SystemTime, outcomes: &str) -> Vec<HealthState> {
+        outcomes.chars().enumerate().map(|(i, c)| {
+            h.on_probe(c == '+', t0 + Duration::from_secs(i as u64));
+            h.state()
+        }).collect()
+    }
+
+    use HealthState::*;
+
+    #[test]
+    fn down_and_up_with_hysteresis() {
+        let mut h = health(3, 2, 0);
+        let t0 = SystemTime::UNIX_EPOCH;
+        assert_eq!(run(&mut h, t0, "++++++++"), vec![Up; 8]);
+        // 2 lost of 10
+        assert_eq!(run(&mut h, t0, "--"), vec![Up, Degraded]);
+        assert!(h.is_reachable());
+        assert_eq!(run(&mut h, t0, "-"), vec![Down]);
+        assert!(!h.is_reachable());
+        // one reply is not enough to come back
+        assert_eq!(run(&mut h, t0, "+-+"), vec![Down, Down, Down]);
+        assert_eq!(run(&mut h, t0, "+"), vec![Degraded]);
+        assert!(h.is_reachable());
+    }
+
+    #[test]
+    fn transitions_are_reported_once() {
+        let mut h = health(1, 1, 0);
+        let t0 = SystemTime::UNIX_EPOCH;
+        run(&mut h, t0, "++++++++++");
+        let t = h.on_probe(false, t0 + Duration::from_secs(30)).unwrap();
+        assert_eq!((t.from, t.to, t.at), (Up, Down, t0 + Duration::from_secs(30)));
+        assert_eq!(h.since(), t.at);
+        assert!(h.on_probe(false, t0 + Duration::from_secs(31)).is_none());
+    }
+
+    #[test]
+    fn no_degraded_on_a_tiny_sample() {
+        let mut h = health(3, 2, 0);
+        let t0 = SystemTime::UNIX_EPOCH;
+        // 1 miss in 2 probes is 50% loss, but too few probes to judge
+        assert_eq!(run(&mut h, t0, "+-"), vec![Up, Up]);
+        assert_eq!(run(&mut h, t0, "+++++++"), vec![Up; 7]);
+        // 2 lost of 10
+        assert_eq!(run(&mut h, t0, "-"), vec![Degraded]);
+    }
+
+    #[test]
+    fn degraded_clears_as_losses_leave_the_window() {
+        let mut h = health(3, 2, 0);
+        let t0 = SystemTime::UNIX_EPOCH;
+        let states = run(&mut h, t0, "--++++++++++++++++++++");
+        assert_eq!(states[9], Degraded);
+        // a 20 probe window that still holds one of the misses is 5% loss
+        assert_eq!(states[20], Up);
+    }
+
+    #[test]
+    fn down_is_reported_before_min_probes() {
+        let mut h = health(3, 2, 0);
+        assert_eq!(run(&mut h, SystemTime::UNIX_EPOCH, "---"), vec![Up, Up, Down]);
+    }
+
+    #[test]
+    fn flapping() {
+        let mut h = health(1, 1, 4);
+        let t0 = SystemTime::UNIX_EPOCH;
+        // four flips in the first four probes are not enough history yet
+        assert_eq!(run(&mut h, t0, "-+-+"), vec![Down, Up, Down, Up]);
+        let states = run(&mut h, t0 + Duration::from_secs(4), "++++++-");
+        assert_eq!(states[5], Flapping);
+        assert_eq!(states[6], Flapping);
+    }
+
+    #[test]
+    fn flips_expire_from_the_flap_window() {
+        let mut h = health(1, 1, 4);
+        let t0 = SystemTime::UNIX_EPOCH;
+        run(&mut h, t0, "++++++++++-+-+");
+        assert_eq!(h.state(), Flapping);
+        // a minute later the old flips no longer count
+        h.on_probe(true, t0 + Duration::from_secs(80));
+        assert_eq!(h.state(), Up);
+    }
+}
@@ -0,0 +1,179 @@
+#![allow(dead_code)]
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Values below 2^SUB_BITS get a bucket each; above that every power of two is
+/// split into 2^SUB_BITS linear buckets, so a bucket is within ~3% of its values.
+const SUB_BITS: u32 = 5;
+const SUB_COUNT: usize = 1 << SUB_BITS;
+/// Largest tracked value is just under 2^MAX_BITS micros (~134s); larger values
+/// land in the top bucket.
+const MAX_BITS: u32 = 27;
+const BUCKETS: usize = SUB_COUNT + (MAX_BITS - SUB_BITS) as usize * SUB_COUNT;
+
+/// Log-linear latency histogram in microseconds, updated lock free like `Stats`.
+pub struct Histogram {
+    counts: Box<[AtomicU64]>,
+}
+
+/// Point in time copy of a `Histogram`.  Snapshots can be merged, e.g. to
+/// combine flows or intervals.
+#[derive(Clone)]
+pub struct HistogramSnapShot {
+    counts: Vec<u64>,
+}
+
+fn bucket_of(micros: u64) -> usize {
+    let v = micros.min((1u64 << MAX_BITS) - 1);
+    if v < SUB_COUNT as u64 {
+        return v as usize;
+    }
+    let exp = 63 - v.leading_zeros();
+    let sub = (v >> (exp - SUB_BITS)) as usize & (SUB_COUNT - 1);
+    SUB_COUNT + (exp - SUB_BITS) as usize * SUB_COUNT + sub
+}
+
+/// Highest value that maps to `bucket`.
+fn bucket_upper(bucket: usize) -> u64 {
+    if bucket < SUB_COUNT {
+        return bucket as u64;
+    }
+    let exp = ((bucket - SUB_COUNT) / SUB_COUNT) as u32 + SUB_BITS;
+    let sub = ((bucket - SUB_COUNT) % SUB_COUNT) as u64;
+    let width = 1u64 << (exp - SUB_BITS);
+    ((SUB_COUNT as u64 + sub) << (exp - SUB_BITS)) + width - 1
+}
+
+impl Histogram {
+    pub fn new() -> Histogram {
+        Histogram {
+            counts: (0..BUCKETS).map(|_| AtomicU64::new(0)).collect(),
+        }
+    }
+
+    pub fn record(&self, micros: u64) {
+        self.counts[bucket_of(micros)].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> HistogramSnapShot {
+        HistogramSnapShot {
+            counts: self.counts.iter().map(|c| c.load(Ordering::Relaxed)).collect(),
+        }
+    }
+
+    pub fn zero_extract(&self) -> HistogramSnapShot {
+        HistogramSnapShot {
+            counts: self.counts.iter().map(|c| c.swap(0, Ordering::Relaxed)).collect(),
+        }
+    }
+}
+
+impl HistogramSnapShot {
+    pub fn count(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    pub fn merge(&mut self, other: &HistogramSnapShot) {
+        for (a, b) in self.counts.iter_mut().zip(other.counts.iter()) {
+            *a += *b;
+        }
+    }
+
+    /// Value in micros at or below which `pct` percent of samples fall, reported
+    /// as the upper edge of the bucket holding that sample.  None if empty.
+    pub fn percentile(&self, pct: f64) -> Option<u64> {
+        let total = self.count();
+        if total == 0 {
+            return None;
+        }
+        let rank = ((pct / 100.0) * total as f64).ceil().max(1.0) as u64;
+        let mut seen = 0u64;
+        for (bucket, &c) in self.counts.iter().enumerate() {
+            seen += c;
+            if seen >= rank {
+                return Some(bucket_upper(bucket));
+            }
+        }
+        Some(bucket_upper(BUCKETS - 1))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn small_values_are_exact() {
+        for v in 0..SUB_COUNT as u64 {
+            assert_eq!(bucket_of(v), v as usize);
+            assert_eq!(bucket_upper(v as usize), v);
+        }
+    }
+
+    #[test]
+    fn bucket_boundaries() {
+        // 32..63 still one value per bucket, from 64 on two, from 128 on four...
+        assert_eq!((bucket_of(32), bucket_upper(32)), (32, 32));
+        assert_eq!((bucket_of(63), bucket_upper(63)), (63, 63));
+        assert_eq!(bucket_of(64), 64);
+        assert_eq!(bucket_of(65), 64);
+        assert_eq!(bucket_upper(64), 65);
+        assert_eq!(bucket_of(66), 65);
+        // 992..=1007 share a 16 wide bucket
+        assert_eq!(bucket_of(991), 189);
+        assert_eq!(bucket_of(992), 190);
+        assert_eq!(bucket_of(1000), 190);
+        assert_eq!(bucket_of(1007), 190);
+        assert_eq!(bucket_upper(190), 1007);
+        assert_eq!(bucket_of(1008), 191);
+    }
+
+    #[test]
+    fn every_value_within_its_bucket() {
+        for v in (0..200_000u64).chain([1 << 20, (1 << 20) + 1, (1 << 26) - 1, 1 << 26]) {
+            let b = bucket_of(v);
+            assert!(bucket_upper(b) >= v, "{} above bucket {}", v, b);
+            if b > 0 {
+                assert!(bucket_upper(b - 1) < v, "{} fits bucket {}", v, b - 1);
+            }
+        }
+    }
+
+    #[test]
+    fn large_values_saturate() {
+        assert_eq!(bucket_of((1 << MAX_BITS) - 1), BUCKETS - 1);
+        assert_eq!(bucket_of(1 << MAX_BITS), BUCKETS - 1);
+        assert_eq!(bucket_of(u64::MAX), BUCKETS - 1);
+        assert_eq!(bucket_upper(BUCKETS - 1), (1 << MAX_BITS) - 1);
+    }
+
+    #[test]
+    fn percentiles() {
+        let h = Histogram::new();
+        assert_eq!(h.snapshot().percentile(50.0), None);
+        for v in 1..=100 {
+            h.record(v);
+        }
+        let s = h.snapshot();
+        assert_eq!(s.count(), 100);
+        assert_eq!(s.percentile(0.0), Some(1));
+        assert_eq!(s.percentile(50.0), Some(50));
+        // 90 and 99 sit in two wide buckets, reported by their upper edge
+        assert_eq!(s.percentile(90.0), Some(91));
+        assert_eq!(s.percentile(99.0), Some(99));
+        assert_eq!(s.percentile(100.0), Some(101));
+    }
+
+    #[test]
+    fn zero_extract_and_merge() {
+        let h = Histogram::new();
+        h.record(10);
+        h.record(20);
+        let mut a = h.zero_extract();
+        assert_eq!(h.snapshot().count(), 0);
+        h.record(30);
+        a.merge(&h.snapshot());
+        assert_eq!(a.count(), 3);
+        assert_eq!(a.percentile(100.0), Some(30));
+        assert_eq!(a.percentile(34.0), Some(20));
+    }
+}
@@ -0,0 +1,60 @@
+#![allow(dead_code)]
+use std::fmt::Write as FmtWrite;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::time::Duration;
+use anyhow::{anyhow, Context, Result};
+
+/// Where a plain http:// URL points: resolved once at startup.
+pub struct HttpTarget {
+    pub addr: SocketAddr,
+    /// Host header value, as given in the URL.
+    pub host: String,
+    /// Path and query.
+    pub path: String,
+}
+
+impl HttpTarget {
+    /// Parses `http://host[:port][/path]`, using `default_path` when the URL has none.
+    pub fn parse(url: &str, default_path: &str) -> Result<HttpTarget> {
+        let rest = url.strip_prefix("http://")
+            .ok_or_else(|| anyhow!("\"{}\" is not an http:// URL (https is not supported)", url))?;
+        let (host, path) = match rest.find('/') {
+            Some(i) => (&rest[..i], &rest[i..]),
+            None => (rest, default_path),
+        };
+        let with_port = if host.rsplit_once(':').is_some_and(|(_, p)| p.parse::<u16>().is_ok()) && !host.ends_with(']') {
+            host.to_string()
+        } else {
+            format!("{}:80", host)
+        };
+        let addr = with_port.to_socket_addrs().with_context(|| format!("resolving \"{}\"", with_port))?
+            .next().ok_or_else(|| anyhow!("no address for \"{}\"", with_port))?;
+        Ok(HttpTarget { addr, host: host.to_string(), path: path.to_string() })
+    }
+
+    /// POSTs `body` on a fresh connection and returns the response status line.
+    pub fn post(&self, content_type: &str, headers: &[(String, String)], body: &[u8]) -> std::io::Result<(u16, String)> {
+        let mut s = TcpStream::connect_timeout(&self.addr, Duration::from_secs(5))?;
+        s.set_read_timeout(Some(Duration::from_secs(10)))?;
+        s.set_write_timeout(Some(Duration::from_secs(10)))?;
+        let mut req = format!("POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n",
+                              self.path, self.host, content_type, body.len());
+        for (k, v) in headers {
+            let _ = write!(req, "{}: {}\r\n", k, v);
+        }
+        req.push_str("\r\n");
+        s.write_all(req.as_bytes())?;
+        s.write_all(body)?;
+        let mut status = String::new();
+        BufReader::new(&s).read_line(&mut status)?;
+        let code = status.split_whitespace().nth(1).and_then(|c| c.parse().ok()).unwrap_or(0);
+        Ok((code, status.trim_end().to_string()))
+    }
+}
+
+impl std::fmt::Display for HttpTarget {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "http://{}{}", self.host, self.path)
+    }
+}
@@ -1,6 +1,7 @@
 #![allow(unused_imports, unused_variables, unused_mut, unused_parens, dead_code)]
 use anyhow::{anyhow,Context};
 use std::io::Write;
+use std::net::IpAddr;
 type ResultS<T> = std::result::Result<T, anyhow::Error>;
 
 pub const HEADER_SIZE: usize = 8;
@@ -13,6 +14,14 @@ pub trait Proto {
     const ECHO_REQUEST_CODE: u8;
     const ECHO_REPLY_TYPE: u8;
     const ECHO_REPLY_CODE: u8;
+    /// IP protocol number, as quoted in error messages.
+    const PROTOCOL: u8;
+
+    /// Error messages, which quote the start of the packet that caused them.
+    fn is_error(type_: u8) -> bool;
+
+    /// Destination and next protocol of a quoted IP header, and the header length.
+    fn quoted_ip(buffer: &[u8]) -> Option<(IpAddr, u8, usize)>;
 }
 
 impl Proto for IcmpV4 {
@@ -20,6 +29,18 @@ impl Proto for IcmpV4 {
     const ECHO_REQUEST_CODE: u8 = 0;
     const ECHO_REPLY_TYPE: u8 = 0;
     const ECHO_REPLY_CODE: u8 = 0;
+    const PROTOCOL: u8 = 1;
+
+    /// Destination unreachable, source quench, redirect, time exceeded, parameter problem.
+    fn is_error(type_: u8) -> bool {
+        matches!(type_, 3 | 4 | 5 | 11 | 12)
+    }
+
+    fn quoted_ip(buffer: &[u8]) -> Option<(IpAddr, u8, usize)> {
+        let ihl = 4 * usize::from(buffer.first()? & 0x0f);
+        let dst: [u8; 4] = buffer.get(16..20)?.try_into().ok()?;
+        (ihl >= 20).then(|| (IpAddr::from(dst), buffer[9], ihl))
+    }
 }
 
 impl Proto for IcmpV6 {
@@ -27,6 +48,18 @@ impl Proto for IcmpV6 {
     const ECHO_REQUEST_CODE: u8 = 0;
     const ECHO_REPLY_TYPE: u8 = 129;
     const ECHO_REPLY_CODE: u8 = 0;
+    const PROTOCOL: u8 = 58;
+
+    /// Types below 128 are errors (RFC 4443).
+    fn is_error(type_: u8) -> bool {
+        type_ < 128
+    }
+
+    /// Extension headers are not walked; probes are sent without any.
+    fn quoted_ip(buffer: &[u8]) -> Option<(IpAddr, u8, usize)> {
+        let dst: [u8; 16] = buffer.get(24..40)?.try_into().ok()?;
+        Some((IpAddr::from(dst), buffer[6], 40))
+    }
 }
 
 pub struct EchoRequest<'a> {
@@ -45,7 +78,7 @@ impl<'a> EchoRequest<'a> {
         buffer[6] = (self.seq_cnt >> 8) as u8;
         buffer[7] = self.seq_cnt as u8;
 
-        if let Err(_) = (&mut buffer[8..]).write(self.payload) {
+        if (&mut buffer[8..]).write(self.payload).is_err() {
             return Err(anyhow!("invalid packet size"));
         }
 
@@ -85,6 +118,40 @@ impl<'a> EchoReply<'a> {
     }
 }
 
+/// An ICMP error about one of our echo requests, such as a destination
+/// unreachable or time exceeded.  `dst` is where the request was going.
+#[derive(Debug, PartialEq)]
+pub struct QuotedEcho {
+    pub type_: u8,
+    pub code: u8,
+    pub dst: IpAddr,
+    pub ident: u16,
+    pub seq_cnt: u16,
+}
+
+impl QuotedEcho {
+    /// None unless `buffer` (an ICMP message without the outer IP header) is an
+    /// error quoting an echo request.
+    pub fn decode<P: Proto>(buffer: &[u8]) -> Option<QuotedEcho> {
+        if buffer.len() < HEADER_SIZE || !P::is_error(buffer[0]) {
+            return None;
+        }
+        let quoted = &buffer[HEADER_SIZE..];
+        let (dst, next, header_size) = P::quoted_ip(quoted)?;
+        let echo = quoted.get(header_size..header_size + HEADER_SIZE)?;
+        if next != P::PROTOCOL || echo[0] != P::ECHO_REQUEST_TYPE || echo[1] != P::ECHO_REQUEST_CODE {
+            return None;
+        }
+        Some(QuotedEcho {
+            type_: buffer[0],
+            code: buffer[1],
+            dst,
+            ident: (u16::from(echo[4]) << 8) + u16::from(echo[5]),
+            seq_cnt: (u16::from(echo[6]) << 8) + u16::from(echo[7]),
+        })
+    }
+}
+
 fn write_checksum(buffer: &mut [u8]) {
     // we reset these for buffer reuse
     buffer[2] = 0;
@@ -108,3 +175,60 @@ fn write_checksum(buffer: &mut [u8]) {
     buffer[2] = (sum >> 8) as u8;
     buffer[3] = (sum & 0xff) as u8;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An error of `type_` quoting an echo request to `dst` behind `header`.
+    fn error(type_: u8, header: &[u8], request_type: u8) -> Vec<u8> {
+        let mut b = vec![type_, 1, 0, 0, 0, 0, 0, 0];
+        b.extend_from_slice(header);
+        b.extend_from_slice(&[request_type, 0, 0, 0, 0x12, 0x34, 0x00, 0x65]);
+        b
+    }
+
+    fn v4_header() -> Vec<u8> {
+        let mut h = vec![0u8; 20];
+        h[0] = 0x45;
+        h[9] = 1;
+        h[16..20].copy_from_slice(&[192, 0, 2, 7]);
+        h
+    }
+
+    fn v6_header() -> Vec<u8> {
+        let mut h = vec![0u8; 40];
+        h[0] = 0x60;
+        h[6] = 58;
+        h[24..40].copy_from_slice(&"2001:db8::7".parse::<std::net::Ipv6Addr>().unwrap().octets());
+        h
+    }
+
+    #[test]
+    fn quoted_v4_echo() {
+        let q = QuotedEcho::decode::<IcmpV4>(&error(3, &v4_header(), 8)).unwrap();
+        assert_eq!(q, QuotedEcho { type_: 3, code: 1, dst: "192.0.2.7".parse().unwrap(), ident: 0x1234, seq_cnt: 101 });
+        assert_eq!(QuotedEcho::decode::<IcmpV4>(&error(11, &v4_header(), 8)).unwrap().type_, 11);
+    }
+
+    #[test]
+    fn quoted_v6_echo() {
+        let q = QuotedEcho::decode::<IcmpV6>(&error(1, &v6_header(), 128)).unwrap();
+        assert_eq!(q.dst, "2001:db8::7".parse::<IpAddr>().unwrap());
+        assert_eq!((q.ident, q.seq_cnt), (0x1234, 101));
+    }
+
+    #[test]
+    fn ignores_other_messages() {
+        // echo reply, not an error
+        assert_eq!(QuotedEcho::decode::<IcmpV4>(&error(0, &v4_header(), 8)), None);
+        // error about something other than an echo request
+        assert_eq!(QuotedEcho::decode::<IcmpV4>(&error(3, &v4_header(), 0)), None);
+        let mut udp = v4_header();
+        udp[9] = 17;
+        assert_eq!(QuotedEcho::decode::<IcmpV4>(&error(3, &udp, 8)), None);
+        // quoted packet cut short
+        assert_eq!(QuotedEcho::decode::<IcmpV4>(&error(3, &v4_header(), 8)[..30]), None);
+        assert_eq!(QuotedEcho::decode::<IcmpV6>(&error(129, &v6_header(), 128)), None);
+    }
+}
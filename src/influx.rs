@@ -0,0 +1,391 @@
+#![allow(dead_code)]
+use std::collections::{HashMap, VecDeque};
+use std::fmt::Write as FmtWrite;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::path::PathBuf;
+use std::sync::{Arc, Condvar, Mutex, OnceLock};
+use std::time::{Duration, SystemTime};
+use anyhow::{anyhow, Context, Result};
+use log::{debug, info, warn};
+use crate::cli::{HostInfo, HostKey};
+use crate::events::ProbeEvent;
+use crate::http::HttpTarget;
+use crate::metrics::{Delta, Deltas, HostMetrics, IntervalExporter};
+
+/// Lines written per batch.
+const BATCH_LINES: usize = 1000;
+/// Longest a line waits before being written.
+const FLUSH_EVERY: Duration = Duration::from_secs(1);
+/// Lines kept while the endpoint is failing; the oldest are dropped past this.
+const MAX_PENDING: usize = 100_000;
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+/// Keep datagrams under a typical path MTU.
+const MAX_DATAGRAM: usize = 1400;
+
+enum Dest {
+    File(PathBuf),
+    Udp(UdpSocket),
+    Http { target: HttpTarget, headers: Vec<(String, String)> },
+}
+
+struct Error {
+    err: anyhow::Error,
+    /// Worth writing the same batch again.
+    retry: bool,
+}
+
+impl Dest {
+    fn parse(dest: &str, token: Option<&str>) -> Result<Dest> {
+        if let Some(rest) = dest.strip_prefix("udp://") {
+            let addr = resolve(rest)?;
+            let local: SocketAddr = if addr.is_ipv4() { "0.0.0.0:0".parse()? } else { "[::]:0".parse()? };
+            let sock = UdpSocket::bind(local).context("binding influx socket")?;
+            sock.connect(addr).with_context(|| format!("connecting influx socket to {}", addr))?;
+            Ok(Dest::Udp(sock))
+        } else if dest.starts_with("http://") {
+            let headers = token.map(|t| vec![(String::from("Authorization"), format!("Token {}", t))]).unwrap_or_default();
+            Ok(Dest::Http { target: HttpTarget::parse(dest, "/write")?, headers })
+        } else if dest.contains("://") {
+            Err(anyhow!("influx destination \"{}\" must be a file path, udp://host:port or http://host:port/path", dest))
+        } else {
+            Ok(Dest::File(PathBuf::from(dest.strip_prefix("file:").unwrap_or(dest))))
+        }
+    }
+
+    fn write(&self, body: &str) -> std::result::Result<(), Error> {
+        let io = |e: std::io::Error| Error { err: e.into(), retry: true };
+        match self {
+            Dest::File(path) => {
+                let mut f = OpenOptions::new().create(true).append(true).open(path).map_err(io)?;
+                f.write_all(body.as_bytes()).map_err(io)
+            }
+            Dest::Udp(sock) => {
+                // pack whole lines into datagrams
+                let mut start = 0;
+                while start < body.len() {
+                    let mut end = body.len().min(start + MAX_DATAGRAM);
+                    if end < body.len() {
+                        end = body[start..end].rfind('\n').map_or(end, |i| start + i + 1);
+                    }
+                    sock.send(&body.as_bytes()[start..end]).map_err(io)?;
+                    start = end;
+                }
+                Ok(())
+            }
+            Dest::Http { target, headers } => {
+                let (code, status) = target.post("text/plain; charset=utf-8", headers, body.as_bytes()).map_err(io)?;
+                match code {
+                    200..=299 => Ok(()),
+                    // bad data or credentials won't get better by sending it again
+                    400..=499 if code != 429 => Err(Error { err: anyhow!("http status: {}", status), retry: false }),
+                    _ => Err(Error { err: anyhow!("http status: {}", status), retry: true }),
+                }
+            }
+        }
+    }
+}
+
+fn resolve(addr: &str) -> Result<SocketAddr> {
+    addr.to_socket_addrs().with_context(|| format!("resolving \"{}\"", addr))?
+        .next().ok_or_else(|| anyhow!("no address for \"{}\"", addr))
+}
+
+/// Lines waiting for the writer thread.
+struct Queue {
+    lines: Mutex<VecDeque<String>>,
+    ready: Condvar,
+}
+
+static QUEUE: OnceLock<Arc<Queue>> = OnceLock::new();
+/// Interface each target's probes leave through, for the `interface` tag.
+static INTERFACES: OnceLock<Mutex<HashMap<HostKey, String>>> = OnceLock::new();
+
+pub fn enabled() -> bool {
+    QUEUE.get().is_some()
+}
+
+/// Records the interface a target's probes go out through, looked up by the
+/// prober when it sets up its socket.
+pub fn set_interface(key: HostKey, interface: String) {
+    INTERFACES.get_or_init(Default::default).lock().unwrap().insert(key, interface);
+}
+
+fn push(line: String) {
+    if let Some(q) = QUEUE.get() {
+        let mut lines = q.lines.lock().unwrap();
+        if lines.len() >= MAX_PENDING {
+            lines.pop_front();
+        }
+        lines.push_back(line);
+        if lines.len() >= BATCH_LINES {
+            q.ready.notify_one();
+        }
+    }
+}
+
+/// Batches queued lines to `dest` every FLUSH_EVERY or BATCH_LINES lines,
+/// putting a failed batch back at the front and backing off before the retry.
+fn writer_thread(q: Arc<Queue>, dest: Dest) {
+    let mut backoff = FLUSH_EVERY;
+    let mut failing = false;
+    loop {
+        let batch: Vec<String> = {
+            let mut lines = q.lines.lock().unwrap();
+            if lines.len() < BATCH_LINES {
+                lines = q.ready.wait_timeout(lines, FLUSH_EVERY).unwrap().0;
+            }
+            let n = lines.len().min(BATCH_LINES);
+            lines.drain(..n).collect()
+        };
+        if batch.is_empty() {
+            continue;
+        }
+        let mut body = batch.join("\n");
+        body.push('\n');
+        match dest.write(&body) {
+            Ok(()) => {
+                if failing {
+                    info!("influx writes working again");
+                    failing = false;
+                }
+                backoff = FLUSH_EVERY;
+            }
+            Err(e) if e.retry => {
+                if !failing {
+                    warn!("influx write failed, will retry: {:#}", e.err);
+                    failing = true;
+                }
+                debug!("influx write failed, retry in {:?}: {:#}", backoff, e.err);
+                {
+                    let mut lines = q.lines.lock().unwrap();
+                    for l in batch.into_iter().rev() {
+                        if lines.len() >= MAX_PENDING {
+                            break;
+                        }
+                        lines.push_front(l);
+                    }
+                }
+                // however much is queued, the endpoint gets a rest before the retry
+                std::thread::sleep(backoff);
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+            }
+            Err(e) => warn!("influx rejected {} lines, dropping them: {:#}", batch.len(), e.err),
+        }
+    }
+}
+
+fn escape_key(s: &str) -> String {
+    s.replace('\\', "\\\\").replace(',', "\\,").replace('=', "\\=").replace(' ', "\\ ")
+}
+
+fn escape_str(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// Tag set shared by both measurements: host, ip, probe type, the interface
+/// once known, and the netns / mark the probe is sent from when set.
+fn tags(h: &HostInfo) -> String {
+    let mut t = format!(",host={},ip={},probe={}",
+        escape_key(&h.host.clone().unwrap_or_else(|| h.ip.to_string())), h.ip,
+        if h.ip.is_ipv4() { "icmp" } else { "icmpv6" });
+    if let Some(interface) = INTERFACES.get().and_then(|i| i.lock().unwrap().get(&h.key()).cloned()) {
+        let _ = write!(t, ",interface={}", escape_key(&interface));
+    }
+    if let Some(netns) = &h.netns {
+        let _ = write!(t, ",netns={}", escape_key(netns));
+    }
+    if let Some(mark) = h.mark {
+        let _ = write!(t, ",mark={:#x}", mark);
+    }
+    t
+}
+
+fn nanos(t: SystemTime) -> u128 {
+    t.duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().as_nanos()
+}
+
+/// `sirping_probe` point for one probe result.
+pub fn probe(ev: &ProbeEvent) {
+    if enabled() {
+        push(probe_line(ev));
+    }
+}
+
+fn probe_line(ev: &ProbeEvent) -> String {
+    let mut line = format!("sirping_probe{} ok={},seq={}i,ident={}i,flow={}i",
+        tags(ev.host), ev.rtt_us.is_some(), ev.seq, ev.ident, ev.flow);
+    if let Some(us) = ev.rtt_us {
+        let _ = write!(line, ",rtt_ms={}", us as f64 / 1000.0);
+    }
+    if let Some(ttl) = ev.ttl {
+        let _ = write!(line, ",ttl={}i", ttl);
+    }
+    if let Some(e) = ev.error_class() {
+        let _ = write!(line, ",error=\"{}\"", escape_str(e));
+    }
+    let _ = write!(line, " {}", nanos(ev.sent));
+    line
+}
+
+/// Queues a `sirping_interval` point per host each stats interval; the loss
+/// and RTT fields cover the interval, the counts are since start.
+pub struct Influx {
+    deltas: Deltas,
+}
+
+impl IntervalExporter for Influx {
+    fn export(&mut self, hosts: &[HostMetrics]) {
+        for m in hosts {
+            push(interval_line(m, &self.deltas.update(m)));
+        }
+    }
+}
+
+/// `sirping_interval` point; `d` covers the interval just ended.
+fn interval_line(m: &HostMetrics, d: &Delta) -> String {
+    let mut line = format!("sirping_interval{} replies={}i,timeouts={}i,up={},outages={}i,downtime_s={}",
+        tags(&m.host), m.totals.reply, m.totals.timeout, m.reachable, m.outages, m.downtime.as_secs_f64());
+    if let Some(loss) = d.loss_pct() {
+        let _ = write!(line, ",loss_pct={}", loss);
+    }
+    if let Some(avg) = d.rtt_avg_ms() {
+        let _ = write!(line, ",rtt_avg_ms={}", avg);
+    }
+    if let Some(jitter) = m.jitter_ms {
+        let _ = write!(line, ",jitter_ms={}", jitter);
+    }
+    if let Some(q) = m.voice {
+        let _ = write!(line, ",r={},mos={}", q.r, q.mos);
+    }
+    let _ = write!(line, " {}", nanos(m.at));
+    line
+}
+
+/// Starts the writer thread for `dest` and returns the per-interval exporter.
+pub fn open(dest: &str, token: Option<&str>) -> Result<Influx> {
+    let d = Dest::parse(dest, token)?;
+    let q = Arc::new(Queue { lines: Mutex::new(VecDeque::new()), ready: Condvar::new() });
+    if QUEUE.set(q.clone()).is_err() {
+        return Err(anyhow!("influx output already open"));
+    }
+    std::thread::Builder::new()
+        .name(String::from("influx"))
+        .spawn(move || writer_thread(q, d))?;
+    info!("writing influx line protocol to {}", dest);
+    Ok(Influx { deltas: Deltas::default() })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Read};
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+    use std::time::Instant;
+    use crate::metrics::tests::host_metrics;
+
+    fn host() -> HostInfo {
+        let mut h = HostInfo::new(Some("web 1,a=b".to_string()), "192.0.2.9".parse().unwrap());
+        h.netns = Some("blue".to_string());
+        h.mark = Some(0x10);
+        h
+    }
+
+    #[test]
+    fn probe_and_interval_lines() {
+        let h = host();
+        set_interface(h.key(), "eth 0".to_string());
+        let sent = SystemTime::UNIX_EPOCH + Duration::from_millis(1_700_000_000_123);
+        let ev = ProbeEvent { host: &h, flow: 1, sent, seq: 7, ident: 42, rtt_us: Some(1500), ttl: Some(61), error: None };
+        assert_eq!(probe_line(&ev),
+            "sirping_probe,host=web\\ 1\\,a\\=b,ip=192.0.2.9,probe=icmp,interface=eth\\ 0,netns=blue,mark=0x10 \
+             ok=true,seq=7i,ident=42i,flow=1i,rtt_ms=1.5,ttl=61i 1700000000123000000");
+        let ev = ProbeEvent { rtt_us: None, ttl: None, error: Some("send"), ..ev };
+        assert!(probe_line(&ev).contains(" ok=false,seq=7i,ident=42i,flow=1i,error=\"send\" "));
+
+        let m = host_metrics(h, 9, 1);
+        let d = Deltas::default().update(&m);
+        assert_eq!(interval_line(&m, &d),
+            "sirping_interval,host=web\\ 1\\,a\\=b,ip=192.0.2.9,probe=icmp,interface=eth\\ 0,netns=blue,mark=0x10 \
+             replies=9i,timeouts=1i,up=true,outages=1i,downtime_s=1.5,loss_pct=10,rtt_avg_ms=2,jitter_ms=0.25,r=93,mos=4.4 \
+             1700000000000000000");
+    }
+
+    #[test]
+    fn udp_datagrams_hold_whole_lines() {
+        let sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+        sock.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let dest = Dest::parse(&format!("udp://{}", sock.local_addr().unwrap()), None).unwrap();
+        let line = format!("m f=\"{}\"\n", "x".repeat(600));
+        assert!(dest.write(&line.repeat(3)).is_ok());
+        let mut buf = [0u8; 2000];
+        let n = sock.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], line.repeat(2).as_bytes());
+        let n = sock.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], line.as_bytes());
+    }
+
+    /// Answers each POST with the next of `codes`, passing on the body and when it came.
+    fn http_server(codes: &'static [u16]) -> (String, mpsc::Receiver<(Instant, String)>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/api/v2/write", listener.local_addr().unwrap());
+        let (tx, rx) = mpsc::channel();
+        std::thread::spawn(move || {
+            for code in codes {
+                let (mut s, _) = listener.accept().unwrap();
+                let mut r = BufReader::new(s.try_clone().unwrap());
+                let mut len = 0;
+                loop {
+                    let mut l = String::new();
+                    r.read_line(&mut l).unwrap();
+                    if let Some(v) = l.to_ascii_lowercase().strip_prefix("content-length:") {
+                        len = v.trim().parse().unwrap();
+                    }
+                    if l == "\r\n" {
+                        break;
+                    }
+                }
+                let mut body = vec![0; len];
+                r.read_exact(&mut body).unwrap();
+                let _ = tx.send((Instant::now(), String::from_utf8(body).unwrap()));
+                let _ = write!(s, "HTTP/1.1 {} X\r\nContent-Length: 0\r\n\r\n", code);
+            }
+        });
+        (url, rx)
+    }
+
+    #[test]
+    fn full_batch_retried_after_backoff_on_5xx() {
+        let (url, rx) = http_server(&[503, 204]);
+        let q = Arc::new(Queue { lines: Mutex::new(VecDeque::new()), ready: Condvar::new() });
+        q.lines.lock().unwrap().extend((0..BATCH_LINES + 5).map(|i| format!("m v={}i", i)));
+        let dest = Dest::parse(&url, Some("tok")).unwrap();
+        let writer = q.clone();
+        std::thread::spawn(move || writer_thread(writer, dest));
+
+        let (first_at, first) = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        let (second_at, second) = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        // the failed batch comes back whole, and only after the backoff
+        assert_eq!(first, second);
+        assert_eq!(first.lines().count(), BATCH_LINES);
+        assert!(first.starts_with("m v=0i\nm v=1i\n"));
+        assert!(second_at - first_at >= FLUSH_EVERY);
+    }
+
+    #[test]
+    fn rejected_batch_is_dropped() {
+        let (url, rx) = http_server(&[400, 204]);
+        let q = Arc::new(Queue { lines: Mutex::new(VecDeque::new()), ready: Condvar::new() });
+        q.lines.lock().unwrap().extend((0..BATCH_LINES + 1).map(|i| format!("m v={}i", i)));
+        let dest = Dest::parse(&url, None).unwrap();
+        let writer = q.clone();
+        std::thread::spawn(move || writer_thread(writer, dest));
+
+        let (_, first) = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        let (_, second) = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(first.lines().count(), BATCH_LINES);
+        assert_eq!(second, format!("m v={}i\n", BATCH_LINES));
+    }
+}
@@ -1,5 +1,7 @@
 #![allow(dead_code)]
-use anyhow::{anyhow,Context};
+use anyhow::anyhow;
+use std::net::Ipv4Addr;
+use crate::cli::IpOptionKind;
 const MINIMUM_PACKET_SIZE: usize = 20;
 
 type ResultS<T> = std::result::Result<T, anyhow::Error>;
@@ -21,6 +23,8 @@ impl IpV4Protocol {
 pub struct IpV4Packet<'a> {
     pub protocol: IpV4Protocol,
     pub ttl: u8,
+    /// Raw option bytes between the fixed 20 byte header and the payload.
+    pub options: &'a [u8],
     pub data: &'a [u8],
 }
 
@@ -39,7 +43,7 @@ impl<'a> IpV4Packet<'a> {
             return Err(anyhow!("invalid version of {} expected {}", version, 4))
         }
 
-        if data.len() < header_size {
+        if header_size < MINIMUM_PACKET_SIZE || data.len() < header_size {
             return Err(anyhow!("data size less than header size: {} < {}", data.len(), header_size))
         }
 
@@ -50,9 +54,180 @@ impl<'a> IpV4Packet<'a> {
         };
 
         Ok(Self {
-            protocol: protocol,
+            protocol,
             ttl,
+            options: &data[MINIMUM_PACKET_SIZE..header_size],
             data: &data[header_size..],
         })
     }
 }
+
+const IPOPT_END: u8 = 0;
+const IPOPT_NOP: u8 = 1;
+const IPOPT_RR: u8 = 7;
+const IPOPT_TS: u8 = 68;
+/// Timestamp option flag: record (address, timestamp) pairs.
+const IPOPT_TS_TSANDADDR: u8 = 1;
+
+/// Builds the IP_OPTIONS payload for an echo request.  Options must be a
+/// multiple of 4 bytes and at most 40.
+pub fn build_option(kind: IpOptionKind) -> Vec<u8> {
+    let mut opt = match kind {
+        IpOptionKind::RecordRoute => {
+            // room for 9 addresses
+            let mut v = vec![IPOPT_RR, 39, 4];
+            v.resize(39, 0);
+            v
+        }
+        IpOptionKind::Timestamp => {
+            // room for 4 (address, timestamp) pairs
+            let mut v = vec![IPOPT_TS, 36, 5, IPOPT_TS_TSANDADDR];
+            v.resize(36, 0);
+            v
+        }
+    };
+    while opt.len() % 4 != 0 {
+        opt.push(IPOPT_END);
+    }
+    opt
+}
+
+/// An IP option decoded from a reply header.
+#[derive(Debug, Clone, PartialEq)]
+pub enum IpOption {
+    RecordRoute(Vec<Ipv4Addr>),
+    /// (address, milliseconds since midnight UT) pairs; the high bit of the
+    /// timestamp marks a non-standard value.
+    Timestamp(Vec<(Ipv4Addr, u32)>),
+}
+
+fn read_addr(b: &[u8]) -> Ipv4Addr {
+    Ipv4Addr::new(b[0], b[1], b[2], b[3])
+}
+
+/// Decodes the Record Route and Timestamp options found in `options`, ignoring
+/// any others.  Only the slots already filled in (before the pointer) are returned.
+pub fn parse_options(options: &[u8]) -> ResultS<Vec<IpOption>> {
+    let mut found = vec![];
+    let mut i = 0usize;
+    while i < options.len() {
+        let type_ = options[i];
+        if type_ == IPOPT_END {
+            break;
+        }
+        if type_ == IPOPT_NOP {
+            i += 1;
+            continue;
+        }
+        if i + 1 >= options.len() {
+            return Err(anyhow!("truncated ip option {} at offset {}", type_, i));
+        }
+        let len = options[i + 1] as usize;
+        if len < 2 || i + len > options.len() {
+            return Err(anyhow!("bad ip option length {} for option {} at offset {}", len, type_, i));
+        }
+        let opt = &options[i..i + len];
+        match type_ {
+            IPOPT_RR if len >= 3 => {
+                let filled = (opt[2] as usize).saturating_sub(4).min(len - 3);
+                let route = opt[3..3 + filled].chunks_exact(4).map(read_addr).collect();
+                found.push(IpOption::RecordRoute(route));
+            }
+            IPOPT_TS if len >= 4 => {
+                let filled = (opt[2] as usize).saturating_sub(5).min(len - 4);
+                let stamps = opt[4..4 + filled].chunks_exact(8)
+                    .map(|c| (read_addr(&c[..4]), u32::from_be_bytes([c[4], c[5], c[6], c[7]])))
+                    .collect();
+                found.push(IpOption::Timestamp(stamps));
+            }
+            _ => {}
+        }
+        i += len;
+    }
+    Ok(found)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Fills the next slot of a Record Route or Timestamp option the way a
+    /// router would, advancing its pointer.
+    fn stamp(opt: &mut [u8], addr: Ipv4Addr, ts: Option<u32>) {
+        let at = opt[2] as usize - 1;
+        opt[at..at + 4].copy_from_slice(&addr.octets());
+        let mut next = at + 4;
+        if let Some(ts) = ts {
+            opt[next..next + 4].copy_from_slice(&ts.to_be_bytes());
+            next += 4;
+        }
+        opt[2] = next as u8 + 1;
+    }
+
+    #[test]
+    fn record_route_layout() {
+        let opt = build_option(IpOptionKind::RecordRoute);
+        assert_eq!(opt.len(), 40);
+        assert_eq!(&opt[..3], &[IPOPT_RR, 39, 4]);
+        assert_eq!(opt[39], IPOPT_END);
+        assert_eq!(parse_options(&opt).unwrap(), vec![IpOption::RecordRoute(vec![])]);
+    }
+
+    #[test]
+    fn record_route_round_trip() {
+        let mut opt = build_option(IpOptionKind::RecordRoute);
+        let hops = [Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(192, 0, 2, 7), Ipv4Addr::new(198, 51, 100, 254)];
+        for a in hops {
+            stamp(&mut opt, a, None);
+        }
+        assert_eq!(opt[2], 16);
+        assert_eq!(parse_options(&opt).unwrap(), vec![IpOption::RecordRoute(hops.to_vec())]);
+    }
+
+    #[test]
+    fn record_route_full() {
+        let mut opt = build_option(IpOptionKind::RecordRoute);
+        for n in 1..=9 {
+            stamp(&mut opt, Ipv4Addr::new(10, 0, 0, n), None);
+        }
+        // the pointer ends past the option once every slot is used
+        assert_eq!(opt[2], 40);
+        let want: Vec<Ipv4Addr> = (1..=9).map(|n| Ipv4Addr::new(10, 0, 0, n)).collect();
+        assert_eq!(parse_options(&opt).unwrap(), vec![IpOption::RecordRoute(want)]);
+    }
+
+    #[test]
+    fn timestamp_layout() {
+        let opt = build_option(IpOptionKind::Timestamp);
+        assert_eq!(opt.len(), 36);
+        assert_eq!(&opt[..4], &[IPOPT_TS, 36, 5, IPOPT_TS_TSANDADDR]);
+        assert_eq!(parse_options(&opt).unwrap(), vec![IpOption::Timestamp(vec![])]);
+    }
+
+    #[test]
+    fn timestamp_round_trip() {
+        let mut opt = build_option(IpOptionKind::Timestamp);
+        let stamps = [(Ipv4Addr::new(10, 0, 0, 1), 1_000), (Ipv4Addr::new(192, 0, 2, 7), 0x8000_0001)];
+        for (a, ts) in stamps {
+            stamp(&mut opt, a, Some(ts));
+        }
+        assert_eq!(opt[2], 21);
+        assert_eq!(parse_options(&opt).unwrap(), vec![IpOption::Timestamp(stamps.to_vec())]);
+    }
+
+    #[test]
+    fn skips_nop_and_unknown_options() {
+        let mut opts = vec![IPOPT_NOP, 130, 4, 0, 0];
+        let mut rr = build_option(IpOptionKind::RecordRoute);
+        stamp(&mut rr, Ipv4Addr::new(10, 1, 2, 3), None);
+        opts.extend_from_slice(&rr[..39]);
+        assert_eq!(parse_options(&opts).unwrap(), vec![IpOption::RecordRoute(vec![Ipv4Addr::new(10, 1, 2, 3)])]);
+    }
+
+    #[test]
+    fn rejects_bad_lengths() {
+        assert!(parse_options(&[IPOPT_RR]).is_err());
+        assert!(parse_options(&[IPOPT_RR, 1, 4]).is_err());
+        assert!(parse_options(&[IPOPT_RR, 39, 4, 0]).is_err());
+    }
+}
@@ -0,0 +1,128 @@
+#![allow(dead_code)]
+use std::fmt::{Display, Write};
+
+/// Appends `s` to `out` as a JSON string literal.
+pub fn escape_into(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+/// Minimal builder for one flat-ish JSON object, fields in insertion order.
+pub struct JsonObject {
+    buf: String,
+}
+
+impl JsonObject {
+    pub fn new() -> JsonObject {
+        JsonObject { buf: String::from("{") }
+    }
+
+    fn key(&mut self, k: &str) {
+        if self.buf.len() > 1 {
+            self.buf.push(',');
+        }
+        escape_into(&mut self.buf, k);
+        self.buf.push(':');
+    }
+
+    pub fn str(mut self, k: &str, v: &str) -> JsonObject {
+        self.key(k);
+        escape_into(&mut self.buf, v);
+        self
+    }
+
+    /// A number (or anything whose Display is valid JSON).  Non-finite floats become null.
+    pub fn num<T: Display>(mut self, k: &str, v: T) -> JsonObject {
+        self.key(k);
+        let start = self.buf.len();
+        let _ = write!(self.buf, "{}", v);
+        if matches!(&self.buf[start..], "NaN" | "inf" | "-inf") {
+            self.buf.truncate(start);
+            self.buf.push_str("null");
+        }
+        self
+    }
+
+    pub fn bool(mut self, k: &str, v: bool) -> JsonObject {
+        self.key(k);
+        self.buf.push_str(if v { "true" } else { "false" });
+        self
+    }
+
+    pub fn null(mut self, k: &str) -> JsonObject {
+        self.key(k);
+        self.buf.push_str("null");
+        self
+    }
+
+    pub fn opt_str(self, k: &str, v: Option<&str>) -> JsonObject {
+        match v {
+            Some(v) => self.str(k, v),
+            None => self.null(k),
+        }
+    }
+
+    pub fn opt_num<T: Display>(self, k: &str, v: Option<T>) -> JsonObject {
+        match v {
+            Some(v) => self.num(k, v),
+            None => self.null(k),
+        }
+    }
+
+    /// Inserts already serialized JSON (an object or array) as the value.
+    pub fn raw(mut self, k: &str, json: &str) -> JsonObject {
+        self.key(k);
+        self.buf.push_str(json);
+        self
+    }
+
+    pub fn finish(mut self) -> String {
+        self.buf.push('}');
+        self.buf
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn escaped(s: &str) -> String {
+        let mut out = String::new();
+        escape_into(&mut out, s);
+        out
+    }
+
+    #[test]
+    fn escapes_quotes_backslashes_and_controls() {
+        assert_eq!(escaped(r#"a"b\c"#), r#""a\"b\\c""#);
+        assert_eq!(escaped("l1\nl2\r\t"), r#""l1\nl2\r\t""#);
+        assert_eq!(escaped("\u{1}\u{1f}"), r#""\u0001\u001f""#);
+        assert_eq!(escaped("blå,;"), "\"blå,;\"");
+    }
+
+    #[test]
+    fn object_fields_in_order() {
+        let o = JsonObject::new()
+            .str("host", "a\"b")
+            .num("n", 3)
+            .num("nan", f64::NAN)
+            .opt_num::<u8>("none", None)
+            .bool("up", true)
+            .raw("arr", "[1,2]")
+            .finish();
+        assert_eq!(o, r#"{"host":"a\"b","n":3,"nan":null,"none":null,"up":true,"arr":[1,2]}"#);
+    }
+}
@@ -0,0 +1,330 @@
+#![allow(dead_code)]
+use std::fmt::Write as FmtWrite;
+use std::io::Write;
+use std::net::{ToSocketAddrs, UdpSocket};
+use std::os::unix::net::UnixDatagram;
+use std::path::PathBuf;
+use std::time::SystemTime;
+use anyhow::{anyhow, Context, Result};
+use humantime::format_rfc3339_millis;
+use log::kv::{Key, Value, VisitSource};
+use log::{Level, Record};
+use crate::cli::{LogFormat, LogSink};
+use crate::json::{escape_into, JsonObject};
+
+/// RFC 5424 facility "daemon".
+const FACILITY: u8 = 3;
+/// Private enterprise number set aside for documentation (RFC 5612), used
+/// for the structured data ID.
+const SD_ID: &str = "sirping@32473";
+
+/// A structured field value, kept typed so JSON can write numbers as numbers.
+enum Field {
+    Num(String),
+    Bool(bool),
+    Str(String),
+}
+
+struct Fields(Vec<(String, Field)>);
+
+impl<'kvs> VisitSource<'kvs> for Fields {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), log::kv::Error> {
+        let v = if let Some(n) = value.to_u64() {
+            Field::Num(n.to_string())
+        } else if let Some(n) = value.to_i64() {
+            Field::Num(n.to_string())
+        } else if let Some(f) = value.to_f64() {
+            Field::Num(f.to_string())
+        } else if let Some(b) = value.to_bool() {
+            Field::Bool(b)
+        } else {
+            Field::Str(value.to_string())
+        };
+        self.0.push((key.to_string(), v));
+        Ok(())
+    }
+}
+
+fn fields(record: &Record) -> Vec<(String, Field)> {
+    let mut f = Fields(vec![]);
+    let _ = record.key_values().visit(&mut f);
+    f.0
+}
+
+fn thread_name() -> String {
+    std::thread::current().name().unwrap_or("unknown").to_string()
+}
+
+/// logfmt value: bare when it is safe to, quoted and escaped otherwise.
+fn logfmt_value(out: &mut String, s: &str) {
+    if !s.is_empty() && !s.chars().any(|c| c == ' ' || c == '=' || c == '"' || c.is_control()) {
+        out.push_str(s);
+    } else {
+        // same escapes as JSON strings
+        escape_into(out, s);
+    }
+}
+
+/// One log line in `format`, without the trailing newline.  `with_header` adds
+/// the timestamp, thread and level, which syslog carries in its own header.
+pub fn format_record(format: LogFormat, record: &Record, with_header: bool) -> String {
+    let msg = record.args().to_string();
+    let fields = fields(record);
+    match format {
+        LogFormat::Text => {
+            if with_header {
+                format!("{} [{:4}] {:>5} {} ", format_rfc3339_millis(SystemTime::now()), thread_name(), record.level(), msg)
+            } else {
+                msg
+            }
+        }
+        LogFormat::Logfmt => {
+            let mut out = String::new();
+            if with_header {
+                let _ = write!(out, "ts={} level={} thread=", format_rfc3339_millis(SystemTime::now()),
+                               record.level().as_str().to_lowercase());
+                logfmt_value(&mut out, &thread_name());
+                out.push(' ');
+            }
+            out.push_str("msg=");
+            logfmt_value(&mut out, msg.trim_end());
+            for (k, v) in &fields {
+                let _ = write!(out, " {}=", k);
+                match v {
+                    Field::Num(n) => out.push_str(n),
+                    Field::Bool(b) => out.push_str(if *b { "true" } else { "false" }),
+                    Field::Str(s) => logfmt_value(&mut out, s),
+                }
+            }
+            out
+        }
+        LogFormat::Json => {
+            let mut o = JsonObject::new();
+            if with_header {
+                o = o.str("ts", &format_rfc3339_millis(SystemTime::now()).to_string())
+                    .str("level", &record.level().as_str().to_lowercase())
+                    .str("thread", &thread_name());
+            }
+            o = o.str("msg", msg.trim_end());
+            for (k, v) in &fields {
+                o = match v {
+                    Field::Num(n) => o.raw(k, n),
+                    Field::Bool(b) => o.bool(k, *b),
+                    Field::Str(s) => o.str(k, s),
+                };
+            }
+            o.finish()
+        }
+    }
+}
+
+fn severity(level: Level) -> u8 {
+    match level {
+        Level::Error => 3,
+        Level::Warn => 4,
+        Level::Info => 6,
+        Level::Debug | Level::Trace => 7,
+    }
+}
+
+/// SD-PARAM value: '"', '\' and ']' are escaped with a backslash.
+fn sd_value(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        if c == '"' || c == '\\' || c == ']' {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+    out.push('"');
+}
+
+/// RFC 5424 header fields that do not change over the process lifetime.
+pub struct SyslogHeader {
+    hostname: String,
+    app: String,
+    pid: u32,
+}
+
+impl SyslogHeader {
+    pub fn new() -> SyslogHeader {
+        let mut buf = [0u8; 256];
+        // SAFETY: gethostname writes at most buf.len() bytes into buf
+        let rc = unsafe { libc::gethostname(buf.as_mut_ptr().cast(), buf.len()) };
+        let hostname = if rc == 0 {
+            let end = buf.iter().position(|b| *b == 0).unwrap_or(buf.len());
+            String::from_utf8_lossy(&buf[..end]).into_owned()
+        } else {
+            String::from("-")
+        };
+        let app = std::env::args().next()
+            .and_then(|a| PathBuf::from(a).file_name().map(|f| f.to_string_lossy().into_owned()))
+            .unwrap_or_else(|| String::from("sirpingsalot"));
+        SyslogHeader { hostname, app, pid: std::process::id() }
+    }
+
+    /// `<PRI>1 TIMESTAMP HOSTNAME APP-NAME PROCID - [SD] MSG` with the thread
+    /// and structured fields as SD-PARAMs.
+    pub fn format(&self, format: LogFormat, record: &Record) -> String {
+        let mut out = format!("<{}>1 {} {} {} {} - [{} thread=", FACILITY * 8 + severity(record.level()),
+                              format_rfc3339_millis(SystemTime::now()), self.hostname, self.app, self.pid, SD_ID);
+        sd_value(&mut out, &thread_name());
+        for (k, v) in fields(record) {
+            let _ = write!(out, " {}=", k);
+            match v {
+                Field::Num(n) => sd_value(&mut out, &n),
+                Field::Bool(b) => sd_value(&mut out, if b { "true" } else { "false" }),
+                Field::Str(s) => sd_value(&mut out, &s),
+            }
+        }
+        out.push_str("] ");
+        out.push_str(format_record(format, record, false).trim_end());
+        out
+    }
+}
+
+enum Socket {
+    Unix(UnixDatagram, PathBuf),
+    Udp(UdpSocket),
+}
+
+/// Sends each write as one syslog datagram.
+pub struct SyslogWriter {
+    sock: Socket,
+}
+
+impl SyslogWriter {
+    pub fn open(sink: &LogSink) -> Result<SyslogWriter> {
+        let sock = match sink {
+            LogSink::Unix(path) => {
+                let s = UnixDatagram::unbound().context("creating syslog socket")?;
+                s.connect(path).with_context(|| format!("connecting to syslog socket {}", path.display()))?;
+                Socket::Unix(s, path.clone())
+            }
+            LogSink::Udp(addr) => {
+                let addr = addr.to_socket_addrs().with_context(|| format!("resolving \"{}\"", addr))?
+                    .next().ok_or_else(|| anyhow!("no address for \"{}\"", addr))?;
+                let s = UdpSocket::bind(if addr.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" }).context("binding syslog socket")?;
+                s.connect(addr).with_context(|| format!("connecting syslog socket to {}", addr))?;
+                Socket::Udp(s)
+            }
+            LogSink::Stderr => return Err(anyhow!("stderr is not a syslog sink")),
+        };
+        Ok(SyslogWriter { sock })
+    }
+}
+
+impl Write for SyslogWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match &mut self.sock {
+            Socket::Unix(s, path) => {
+                if s.send(buf).is_err() {
+                    // the syslog daemon may have restarted and recreated its socket
+                    let fresh = UnixDatagram::unbound()?;
+                    fresh.connect(&*path)?;
+                    fresh.send(buf)?;
+                    *s = fresh;
+                }
+            }
+            Socket::Udp(s) => {
+                // nowhere to report a lost log line
+                let _ = s.send(buf);
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Runs `f` on a record with `msg` and the structured fields `kvs`.
+    fn with_record<R>(level: Level, msg: &str, kvs: &[(&str, Value)], f: impl FnOnce(&Record) -> R) -> R {
+        f(&Record::builder().args(format_args!("{}", msg)).level(level).key_values(&kvs).build())
+    }
+
+    #[test]
+    fn logfmt_quotes_only_when_needed() {
+        let kvs = [
+            ("host", Value::from("a.example")),
+            ("seq", Value::from(7u64)),
+            ("ok", Value::from(true)),
+            ("rtt_ms", Value::from(1.5)),
+            ("error", Value::from("no \"route\"")),
+            ("path", Value::from("a=b")),
+            ("empty", Value::from("")),
+            ("multi", Value::from("1\n2\t3\u{1}")),
+        ];
+        let out = with_record(Level::Info, "timeout for a.example \n", &kvs, |r| format_record(LogFormat::Logfmt, r, false));
+        assert_eq!(out, r#"msg="timeout for a.example" host=a.example seq=7 ok=true rtt_ms=1.5 error="no \"route\"" path="a=b" empty="" multi="1\n2\t3\u0001""#);
+        let out = with_record(Level::Warn, "x", &[], |r| format_record(LogFormat::Logfmt, r, true));
+        let ts = out.strip_prefix("ts=").unwrap().split(' ').next().unwrap();
+        assert!(humantime::parse_rfc3339(ts).is_ok(), "{}", out);
+        assert!(out.ends_with(&format!(" level=warn thread={} msg=x", thread_name())), "{}", out);
+    }
+
+    #[test]
+    fn json_escapes_control_characters() {
+        let kvs = [("error", Value::from("bad\r\n\"x\"\\\u{1b}")), ("seq", Value::from(-3i64)), ("up", Value::from(false))];
+        let out = with_record(Level::Error, "tab\there\u{7}\n", &kvs, |r| format_record(LogFormat::Json, r, false));
+        assert_eq!(out, r#"{"msg":"tab\there\u0007","error":"bad\r\n\"x\"\\\u001b","seq":-3,"up":false}"#);
+        assert!(!out.chars().any(|c| c.is_control()));
+    }
+
+    fn header() -> SyslogHeader {
+        SyslogHeader { hostname: String::from("box1"), app: String::from("sirpingsalot"), pid: 4242 }
+    }
+
+    #[test]
+    fn rfc5424_header_fields() {
+        let kvs = [("host", Value::from("a]b\"c\\")), ("seq", Value::from(9u64))];
+        let out = with_record(Level::Warn, "timeout for a\n", &kvs, |r| header().format(LogFormat::Text, r));
+        let mut parts = out.splitn(7, ' ');
+        // daemon facility (3) * 8 + warning (4)
+        assert_eq!(parts.next(), Some("<28>1"));
+        assert!(humantime::parse_rfc3339(parts.next().unwrap()).is_ok(), "{}", out);
+        assert_eq!(parts.next(), Some("box1"));
+        assert_eq!(parts.next(), Some("sirpingsalot"));
+        assert_eq!(parts.next(), Some("4242"));
+        assert_eq!(parts.next(), Some("-"));
+        assert_eq!(parts.next().unwrap(),
+                   format!(r#"[sirping@32473 thread="{}" host="a\]b\"c\\" seq="9"] timeout for a"#, thread_name()));
+
+        let pri = |level| with_record(level, "m", &[], |r| header().format(LogFormat::Text, r))[..4].to_string();
+        assert_eq!([pri(Level::Error), pri(Level::Info), pri(Level::Debug), pri(Level::Trace)], ["<27>", "<30>", "<31>", "<31>"]);
+        // the message part keeps the chosen format
+        let out = with_record(Level::Info, "m", &[("seq", Value::from(1u64))], |r| header().format(LogFormat::Json, r));
+        assert!(out.ends_with(r#"] {"msg":"m","seq":1}"#), "{}", out);
+    }
+
+    #[test]
+    fn one_datagram_per_line_without_newline() {
+        let line = with_record(Level::Info, "success\n", &[], |r| header().format(LogFormat::Logfmt, r));
+        assert!(!line.ends_with('\n'));
+
+        let udp = UdpSocket::bind("127.0.0.1:0").unwrap();
+        udp.set_read_timeout(Some(std::time::Duration::from_secs(5))).unwrap();
+        let mut w = SyslogWriter::open(&LogSink::Udp(udp.local_addr().unwrap().to_string())).unwrap();
+        w.write_all(line.as_bytes()).unwrap();
+        w.write_all(b"second").unwrap();
+        let mut buf = [0u8; 1024];
+        let n = udp.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], line.as_bytes());
+        let n = udp.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"second");
+
+        let path = std::env::temp_dir().join(format!("sirping-syslog-test-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let unix = UnixDatagram::bind(&path).unwrap();
+        let mut w = SyslogWriter::open(&LogSink::Unix(path.clone())).unwrap();
+        w.write_all(line.as_bytes()).unwrap();
+        let n = unix.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], line.as_bytes());
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,361 @@
+#![allow(dead_code)]
+use std::collections::BTreeMap;
+
+/// Loss burst statistics over a host's sequence of probe outcomes: the
+/// distribution of consecutive-loss run lengths, RFC 3357 loss period and loss
+/// distance metrics, and the pair / triplet counts a Gilbert-Elliott model is
+/// fitted from.
+#[derive(Clone, Default)]
+pub struct LossPattern {
+    /// Index of the next probe; kept across resets so loss distance spans them.
+    index: u64,
+    /// Probes and losses since the last reset.
+    probes: u64,
+    losses: u64,
+    /// Outcomes of the previous two probes (true = reply).
+    prev: Option<bool>,
+    prev2: Option<bool>,
+    /// Length of the loss run in progress.
+    run: u32,
+    /// Completed loss period length -> count.
+    periods: BTreeMap<u32, u64>,
+    /// Probe index of the previous loss, for loss distance.
+    last_loss_at: Option<u64>,
+    distance_sum: u64,
+    distance_count: u64,
+    // outcome transitions: good->bad, good->good, bad->good, bad->bad
+    gb: u64,
+    gg: u64,
+    bg: u64,
+    bb: u64,
+    /// Runs of three outcomes, those whose first and last were lost, and
+    /// those lost throughout.
+    triples: u64,
+    lost_lag2: u64,
+    lost_triple: u64,
+}
+
+/// Gilbert-Elliott model: a Good and a Bad state with transition
+/// probabilities `p` and `r`, each losing probes at its own rate.  The simple
+/// Gilbert model is the special case of a loss-free Good and an always-losing
+/// Bad state.
+#[derive(Debug)]
+pub struct GilbertFit {
+    /// P(Good -> Bad)
+    pub p: f64,
+    /// P(Bad -> Good)
+    pub r: f64,
+    /// Loss probability in the Good state, 1 - k.
+    pub loss_good: f64,
+    /// Loss probability in the Bad state, 1 - h.
+    pub loss_bad: f64,
+}
+
+impl GilbertFit {
+    /// Share of time in the Bad state, p / (p + r).
+    fn bad_share(&self) -> f64 {
+        if self.p + self.r > 0.0 { self.p / (self.p + self.r) } else { 0.0 }
+    }
+
+    /// Unconditional loss probability.
+    pub fn ulp(&self) -> f64 {
+        let b = self.bad_share();
+        (1.0 - b) * self.loss_good + b * self.loss_bad
+    }
+
+    /// Conditional loss probability: loss given the previous probe was lost.
+    pub fn clp(&self) -> f64 {
+        let b = self.bad_share();
+        let (eg, eb) = (self.loss_good, self.loss_bad);
+        let both = (1.0 - b) * eg * ((1.0 - self.p) * eg + self.p * eb)
+            + b * eb * (self.r * eg + (1.0 - self.r) * eb);
+        let ulp = self.ulp();
+        if ulp > 0.0 { both / ulp } else { 0.0 }
+    }
+}
+
+impl LossPattern {
+    pub fn new() -> LossPattern {
+        LossPattern::default()
+    }
+
+    pub fn record(&mut self, ok: bool) {
+        match (self.prev, ok) {
+            (Some(true), true) => self.gg += 1,
+            (Some(true), false) => self.gb += 1,
+            (Some(false), true) => self.bg += 1,
+            (Some(false), false) => self.bb += 1,
+            (None, _) => {}
+        }
+        if let (Some(first), Some(mid)) = (self.prev2, self.prev) {
+            self.triples += 1;
+            if !first && !ok {
+                self.lost_lag2 += 1;
+                if !mid {
+                    self.lost_triple += 1;
+                }
+            }
+        }
+        self.probes += 1;
+        if ok {
+            if self.run > 0 {
+                *self.periods.entry(self.run).or_insert(0) += 1;
+                self.run = 0;
+            }
+        } else {
+            self.losses += 1;
+            self.run += 1;
+            if let Some(at) = self.last_loss_at {
+                self.distance_sum += self.index - at;
+                self.distance_count += 1;
+            }
+            self.last_loss_at = Some(self.index);
+        }
+        self.index += 1;
+        self.prev2 = self.prev;
+        self.prev = Some(ok);
+    }
+
+    /// Copy for reporting; with `reset` the counters start over but the
+    /// in-progress run and previous outcomes carry on.
+    pub fn extract(&mut self, reset: bool) -> LossPattern {
+        let out = self.clone();
+        if reset {
+            *self = LossPattern {
+                index: self.index,
+                prev: self.prev,
+                prev2: self.prev2,
+                run: self.run,
+                last_loss_at: self.last_loss_at,
+                ..LossPattern::default()
+            };
+        }
+        out
+    }
+
+    /// Adds the counters of `other`, a pattern over a separate probe sequence
+    /// such as another flow.  Runs in progress are left as they are.
+    pub fn merge(&mut self, other: &LossPattern) {
+        self.probes += other.probes;
+        self.losses += other.losses;
+        for (len, c) in &other.periods {
+            *self.periods.entry(*len).or_insert(0) += c;
+        }
+        self.distance_sum += other.distance_sum;
+        self.distance_count += other.distance_count;
+        self.gb += other.gb;
+        self.gg += other.gg;
+        self.bg += other.bg;
+        self.bb += other.bb;
+        self.triples += other.triples;
+        self.lost_lag2 += other.lost_lag2;
+        self.lost_triple += other.lost_triple;
+    }
+
+    pub fn losses(&self) -> u64 {
+        self.losses
+    }
+
+    /// Number of completed loss periods (RFC 3357).
+    pub fn period_count(&self) -> u64 {
+        self.periods.values().sum()
+    }
+
+    pub fn period_avg(&self) -> Option<f64> {
+        let n = self.period_count();
+        (n > 0).then(|| self.periods.iter().map(|(len, c)| *len as u64 * c).sum::<u64>() as f64 / n as f64)
+    }
+
+    pub fn period_max(&self) -> Option<u32> {
+        self.periods.keys().next_back().copied()
+    }
+
+    /// Mean number of probes between successive losses (RFC 3357 loss distance).
+    pub fn distance_avg(&self) -> Option<f64> {
+        (self.distance_count > 0).then(|| self.distance_sum as f64 / self.distance_count as f64)
+    }
+
+    /// Loss period length -> count, shortest first.
+    pub fn periods(&self) -> &BTreeMap<u32, u64> {
+        &self.periods
+    }
+
+    /// Fits the Gilbert-Elliott model by matching the loss rate, the rate of
+    /// losses one and two probes apart and the rate of three losses in a row.
+    /// Needs both outcomes and a reply after a loss; without positive
+    /// correlation between losses the four parameters cannot be told apart,
+    /// and the simple Gilbert model is fitted from the transitions instead.
+    pub fn gilbert(&self) -> Option<GilbertFit> {
+        let from_good = self.gg + self.gb;
+        let from_bad = self.bg + self.bb;
+        if from_good == 0 || from_bad == 0 {
+            return None;
+        }
+        self.gilbert_elliott().or(Some(GilbertFit {
+            p: self.gb as f64 / from_good as f64,
+            r: self.bg as f64 / from_bad as f64,
+            loss_good: 0.0,
+            loss_bad: 1.0,
+        }))
+    }
+
+    /// With s the Bad share, d = loss_bad - loss_good and l = 1 - p - r, the
+    /// loss indicator has mean m, lag n autocovariance s(1-s)d²lⁿ and third
+    /// central moment over three in a row s(1-s)(1-2s)d³l².
+    fn gilbert_elliott(&self) -> Option<GilbertFit> {
+        if self.triples == 0 {
+            return None;
+        }
+        let pairs = (self.gg + self.gb + self.bg + self.bb) as f64;
+        let m = self.losses as f64 / self.probes as f64;
+        let p11 = self.bb as f64 / pairs;
+        let p1x1 = self.lost_lag2 as f64 / self.triples as f64;
+        let p111 = self.lost_triple as f64 / self.triples as f64;
+        let (c1, c2) = (p11 - m * m, p1x1 - m * m);
+        // the losses must be correlated beyond the 2/sqrt(n) band the sample
+        // autocorrelation of independent ones stays in
+        let noise = 2.0 * m * (1.0 - m) / (self.triples as f64).sqrt();
+        if c1 <= noise || c2 <= noise || c2 >= c1 {
+            return None;
+        }
+        let l = c2 / c1;
+        let v = c1 / l;
+        let t3 = p111 - m * (2.0 * p11 + p1x1) + 2.0 * m * m * m;
+        // d(1-2s), and d² = (d(1-2s))² + 4s(1-s)d²
+        let u = t3 / (l * l * v);
+        let d = (u * u + 4.0 * v).sqrt();
+        let s = (1.0 - u / d) / 2.0;
+        if !s.is_finite() || s <= 0.0 || s >= 1.0 {
+            return None;
+        }
+        let loss_good = (m - s * d).clamp(0.0, 1.0);
+        Some(GilbertFit {
+            p: s * (1.0 - l),
+            r: (1.0 - s) * (1.0 - l),
+            loss_good,
+            loss_bad: (loss_good + d).clamp(0.0, 1.0),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pattern(outcomes: &str) -> LossPattern {
+        let mut l = LossPattern::new();
+        for c in outcomes.chars() {
+            l.record(c == '+');
+        }
+        l
+    }
+
+    #[test]
+    fn periods_and_distance() {
+        let l = pattern("++-+--+---++");
+        assert_eq!(l.losses(), 6);
+        assert_eq!(l.periods().iter().map(|(k, v)| (*k, *v)).collect::<Vec<_>>(), vec![(1, 1), (2, 1), (3, 1)]);
+        assert_eq!(l.period_count(), 3);
+        assert_eq!(l.period_avg(), Some(2.0));
+        assert_eq!(l.period_max(), Some(3));
+        // losses at 2, 4, 5, 7, 8, 9
+        assert_eq!(l.distance_avg(), Some(1.4));
+    }
+
+    #[test]
+    fn gilbert_fit() {
+        // gg 2, gb 3, bg 3, bb 3
+        // too few probes to show correlation: the simple model
+        let g = pattern("++-+--+---++").gilbert().unwrap();
+        assert_eq!((g.p, g.r, g.loss_good, g.loss_bad), (0.6, 0.5, 0.0, 1.0));
+        assert!((g.ulp() - 0.6 / 1.1).abs() < 1e-12);
+        assert_eq!(g.clp(), 0.5);
+    }
+
+    /// Outcomes of `n` probes through a Gilbert-Elliott chain.
+    fn simulate(p: f64, r: f64, loss_good: f64, loss_bad: f64, n: usize) -> LossPattern {
+        use rand::{Rng, SeedableRng};
+        let mut rng = rand::rngs::StdRng::seed_from_u64(7);
+        let mut l = LossPattern::new();
+        let mut bad = false;
+        for _ in 0..n {
+            let loss = if bad { loss_bad } else { loss_good };
+            l.record(!rng.random_bool(loss));
+            bad = if bad { !rng.random_bool(r) } else { rng.random_bool(p) };
+        }
+        l
+    }
+
+    fn assert_fit(g: &GilbertFit, p: f64, r: f64, loss_good: f64, loss_bad: f64) {
+        let close = |a: f64, b: f64, tol: f64| (a - b).abs() <= tol;
+        assert!(close(g.p, p, 0.005) && close(g.r, r, 0.03), "{:?}", g);
+        assert!(close(g.loss_good, loss_good, 0.005) && close(g.loss_bad, loss_bad, 0.03), "{:?}", g);
+    }
+
+    #[test]
+    fn gilbert_elliott_fit() {
+        let l = simulate(0.02, 0.2, 0.01, 0.6, 1_000_000);
+        let g = l.gilbert().unwrap();
+        assert_fit(&g, 0.02, 0.2, 0.01, 0.6);
+        let ulp = l.losses() as f64 / 1_000_000.0;
+        assert!((g.ulp() - ulp).abs() < 1e-3, "{} vs {}", g.ulp(), ulp);
+        assert!((g.clp() - l.bb as f64 / (l.bb + l.bg) as f64).abs() < 0.01, "{:?}", g);
+    }
+
+    #[test]
+    fn gilbert_is_a_special_case() {
+        let g = simulate(0.05, 0.4, 0.0, 1.0, 1_000_000).gilbert().unwrap();
+        assert_fit(&g, 0.05, 0.4, 0.0, 1.0);
+        assert!((g.clp() - 0.6).abs() < 0.01);
+    }
+
+    #[test]
+    fn independent_losses_fall_back_to_simple_gilbert() {
+        let l = simulate(0.0, 1.0, 0.1, 0.1, 200_000);
+        let g = l.gilbert().unwrap();
+        assert_eq!((g.loss_good, g.loss_bad), (0.0, 1.0));
+        assert_eq!(g.p, l.gb as f64 / (l.gb + l.gg) as f64);
+        assert!((g.p - 0.1).abs() < 0.01 && (g.r - 0.9).abs() < 0.01, "{:?}", g);
+    }
+
+    #[test]
+    fn gilbert_needs_both_states() {
+        assert!(pattern("+++++").gilbert().is_none());
+        assert!(pattern("-----").gilbert().is_none());
+        assert!(pattern("+").gilbert().is_none());
+    }
+
+    #[test]
+    fn open_run_is_not_a_period() {
+        let l = pattern("+--");
+        assert_eq!(l.losses(), 2);
+        assert_eq!(l.period_count(), 0);
+    }
+
+    #[test]
+    fn reset_carries_the_open_run() {
+        let mut l = pattern("+--");
+        let before = l.extract(true);
+        assert_eq!(before.losses(), 2);
+        l.record(false);
+        l.record(true);
+        assert_eq!(l.losses(), 1);
+        assert_eq!(l.periods().get(&3), Some(&1));
+        // bad -> bad and bad -> good after the reset
+        let g = l.gilbert();
+        assert!(g.is_none());
+        assert_eq!((l.bb, l.bg), (1, 1));
+    }
+
+    #[test]
+    fn merged_flows_keep_their_bursts() {
+        // flow 0 loses three in a row while flow 1, interleaved with it, always answers
+        let mut total = pattern("+---+");
+        total.merge(&pattern("+++++"));
+        assert_eq!(total.losses(), 3);
+        assert_eq!(total.periods().iter().map(|(k, v)| (*k, *v)).collect::<Vec<_>>(), vec![(3, 1)]);
+        let g = total.gilbert().unwrap();
+        assert_eq!(g.p, 1.0 / 5.0);
+        assert_eq!(g.r, 1.0 / 3.0);
+    }
+}
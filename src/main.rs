@@ -3,7 +3,6 @@ use std::sync::atomic::{AtomicU64, AtomicBool, Ordering};
 
 use anyhow::Result;
 use humantime::format_rfc3339_millis;
-use rand::Rng;
 use clap::Parser;
 use log::{debug, error, info, trace, warn};
 
@@ -14,11 +13,34 @@ use crate::stop::Stop;
 use crate::ping::*;
 
 mod icmp;
+mod ipv4;
 mod ping;
 mod stats;
+mod histogram;
+mod emodel;
+mod window;
+mod baseline;
+mod health;
+mod sla;
+mod losspattern;
+mod correlate;
+mod diagnose;
+mod json;
+mod events;
+mod csv;
+mod metrics;
+mod prometheus;
+mod statsd;
+mod influx;
+mod http;
+mod otlp;
+mod mqtt;
+mod logging;
+mod pcap;
 mod util;
 mod cli;
 mod stop;
+mod netns;
 
 /// Total pings sent across all threads, for the live status line.
 static PING_COUNT: AtomicU64 = AtomicU64::new(0);
@@ -39,8 +61,21 @@ fn main() {
 
 
 fn run() -> Result<()> {
-    let cfg: Config = Config::parse();
-    init_log(cfg.log_level);
+    let mut cfg: Config = Config::parse();
+    init_log(cfg.log_level, &cfg.log_sink, cfg.log_format)?;
+    cfg.add_auto_gateway()?;
+    if let Some(path) = &cfg.events_json {
+        events::open(path)?;
+    }
+    if let Some(path) = &cfg.pcap {
+        pcap::open(path)?;
+    }
+    if let Some(path) = &cfg.csv_probes {
+        csv::open_probes(path, cfg.csv_delimiter)?;
+    }
+    if let Some(path) = &cfg.csv_stats {
+        csv::open_stats(path, cfg.csv_delimiter)?;
+    }
     debug!("options: \n{:#?}", &cfg);
     let stop = Stop::new();
 
@@ -75,16 +110,21 @@ fn run() -> Result<()> {
         format_rfc3339_millis(SystemTime::now()), cfg.interval, stat_interval);
 
     let tracker = Tracks::new(&cfg)?;
+    let exporters = metrics::setup(&cfg, &tracker)?;
 
     let mut threads = vec![];
     for (no, ip) in cfg.ips.iter().enumerate() {
-        let ip: HostInfo = ip.clone();
-        let (interval, timeout) = (cfg.interval, cfg.timeout);
-        let tracker = tracker.clone();
-        let stop = stop.clone();
-        threads.push(std::thread::Builder::new()
-            .name(format!("ping{}", no))
-            .spawn(move || ping_thread(ip, no, interval, timeout, tracker, stop))?);
+        // one thread (and socket) per flow, each with the ident Tracks gave the flow
+        for flow in 0..cfg.flows {
+            let ip: HostInfo = ip.clone();
+            let (interval, timeout, ip_option) = (cfg.interval, cfg.timeout, cfg.ip_option);
+            let tracker = tracker.clone();
+            let stop = stop.clone();
+            let name = if cfg.flows > 1 { format!("ping{}.{}", no, flow) } else { format!("ping{}", no) };
+            threads.push(std::thread::Builder::new()
+                .name(name)
+                .spawn(move || ping_thread(ip, no, flow, interval, timeout, ip_option, tracker, stop))?);
+        }
     }
     debug!("all ping threads started");
 
@@ -94,7 +134,7 @@ fn run() -> Result<()> {
         debug!("starting stats thread with interval {:?} reset={}", stat_interval, reset);
         let _tracker_h = std::thread::Builder::new()
             .name(String::from("stats"))
-            .spawn(move || stats::stats_thread(tracker, stop, stat_interval, reset, &PRINT_STATS_NOW))?;
+            .spawn(move || stats::stats_thread(tracker, stop, stat_interval, reset, &PRINT_STATS_NOW, exporters))?;
     }
 
     if util::STDERR_IS_TERMINAL.load(Ordering::Relaxed) {
@@ -125,12 +165,16 @@ fn status_thread(stop: Stop) {
     }
 }
 
-fn ping_thread(hostinfo: HostInfo, no: usize, interval: Duration, timeout: Duration, mut tracker: Tracks, mut stop: Stop) {
-    let ping_ident: u16 = rand::rng().random();
+#[allow(clippy::too_many_arguments)]
+fn ping_thread(hostinfo: HostInfo, no: usize, flow: usize, interval: Duration, timeout: Duration,
+               ip_option: Option<IpOptionKind>, mut tracker: Tracks, mut stop: Stop) {
+    let key = hostinfo.key();
+    // As in rawls, flows differ only by ident and send the same seq in the same round.
+    let ping_ident = tracker.ident_for(&key, flow);
     let mut seq_cnt = (100 + no * 100) as u16;
-    debug!("starting thread for {} ident={}", &hostinfo, ping_ident);
+    debug!("starting thread for {} flow={} ident={}", &hostinfo, flow, ping_ident);
 
-    let mut pinger = match Pinger::new(hostinfo.ip, timeout, hostinfo.to_string()) {
+    let mut pinger = match Pinger::new(&hostinfo, timeout, ip_option) {
         Err(e) => {
             error!("failed to setup ping for {} with error {:?}", hostinfo.ip, e);
             std::process::exit(10);
@@ -142,7 +186,7 @@ fn ping_thread(hostinfo: HostInfo, no: usize, interval: Duration, timeout: Durat
 
     loop {
         let now = Instant::now();
-        tracker.update_for_send(hostinfo.ip, now, ping_ident, seq_cnt);
+        tracker.update_for_send(&key, flow, now, ping_ident, seq_cnt);
         let res = pinger.ping1(ping_ident, seq_cnt, 255);
         let recv_instant = Instant::now();
         let dur = recv_instant - now;
@@ -166,21 +210,35 @@ fn ping_thread(hostinfo: HostInfo, no: usize, interval: Duration, timeout: Durat
                             if seq_cnt != ret_seq {
                                 let _ = writeln!(&mut buff, "\tseqcnt: sent: {}  return: {}", seq_cnt, ret_seq);
                             }
-                            warn!("{}", &buff);
+                            warn!(host:% = hostinfo, flow, seq = seq_cnt, error = "mismatch"; "{}", &buff);
+                            tracker.note_error(&key, flow, "mismatch");
                         } else {
-                            tracker.update_for_recv(hostinfo.ip, recv_instant, ret_ident, ret_seq);
-                            info!("success for {} in {:?}", hostinfo, dur);
+                            tracker.update_for_recv(&key, flow, recv_instant, ret_ident, ret_seq, pinger.reply_ttl());
+                            info!(host:% = hostinfo, flow, seq = ret_seq, rtt_us = dur.as_micros() as u64;
+                                  "success for {} in {:?}", hostinfo, dur);
+                            if ip_option.is_some() {
+                                record_ip_options(&pinger, &hostinfo, &key, flow, &mut tracker);
+                            }
                         }
                     },
-                    Err(e) => error!("error decoding return packet from {}, {}", hostinfo, e),
+                    Err(e) => {
+                        error!(host:% = hostinfo, flow, seq = seq_cnt, error = "decode"; "error decoding return packet from {}, {}", hostinfo, e);
+                        tracker.note_error(&key, flow, "decode");
+                    }
                 }
             },
             Err(e)=> {
+                let class = error_class(&e);
+                tracker.note_error(&key, flow, class);
+                if e.is::<NonReply>() {
+                    tracker.update_for_non_reply(&key, flow, seq_cnt);
+                }
                 let causes: Vec<String> = e.chain().skip(1).map(|c| c.to_string()).collect();
                 if causes.is_empty() {
-                    warn!("error for {} after {:?}, {}", hostinfo, dur, e);
+                    warn!(host:% = hostinfo, flow, seq = seq_cnt, error = class; "error for {} after {:?}, {}", hostinfo, dur, e);
                 } else {
-                    warn!("error for {} after {:?}, {} ({})", hostinfo, dur, e, causes.join("; "));
+                    warn!(host:% = hostinfo, flow, seq = seq_cnt, error = class;
+                          "error for {} after {:?}, {} ({})", hostinfo, dur, e, causes.join("; "));
                 }
             }
         }
@@ -194,3 +252,38 @@ fn ping_thread(hostinfo: HostInfo, no: usize, interval: Duration, timeout: Durat
 
 }
 
+
+/// Short class of a ping1 failure for the events stream.
+fn error_class(e: &anyhow::Error) -> &'static str {
+    match e.root_cause().downcast_ref::<std::io::Error>() {
+        Some(io) if matches!(io.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut) => "timeout",
+        Some(_) if e.to_string().starts_with("error from send_to") => "send",
+        Some(_) => "io",
+        None if e.is::<NonReply>() => "icmp_error",
+        None if e.to_string().starts_with("timeout") => "timeout",
+        None => "other",
+    }
+}
+
+
+/// Feeds the path carried by a reply's Record Route option, or the stamps of its
+/// Timestamp option, to the tracker.
+fn record_ip_options(pinger: &Pinger, hostinfo: &HostInfo, key: &HostKey, flow: usize, tracker: &mut Tracks) {
+    match pinger.ip_options() {
+        Ok(opts) => {
+            for opt in opts {
+                match opt {
+                    ipv4::IpOption::RecordRoute(route) => {
+                        debug!("{} recorded route: {:?}", hostinfo, route);
+                        tracker.update_route(key, flow, route);
+                    }
+                    ipv4::IpOption::Timestamp(stamps) => {
+                        debug!("{} timestamps (ms since midnight UT): {:?}", hostinfo, stamps);
+                        tracker.update_timestamps(key, flow, stamps);
+                    }
+                }
+            }
+        }
+        Err(e) => warn!("error decoding ip options from {}, {}", hostinfo, e),
+    }
+}
@@ -0,0 +1,225 @@
+#![allow(dead_code)]
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+use anyhow::Result;
+use log::warn;
+use crate::cli::{Config, HostInfo, HostKey};
+use crate::emodel::VoiceQuality;
+use crate::health::HealthState;
+use crate::window::WindowAgg;
+use crate::stats::Tracks;
+use crate::{influx, mqtt, otlp, prometheus, statsd};
+
+/// Upper bounds (seconds) of the exported RTT histogram buckets; +Inf is implied.
+pub const RTT_BUCKETS_S: &[f64] = &[
+    0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0,
+];
+
+/// RTT counts per RTT_BUCKETS_S bucket since start (not cumulative across
+/// buckets; the last slot is over the largest bound).  Never reset by -R.
+#[derive(Clone)]
+pub struct RttBuckets {
+    counts: Vec<u64>,
+}
+
+impl RttBuckets {
+    pub fn new() -> RttBuckets {
+        RttBuckets { counts: vec![0; RTT_BUCKETS_S.len() + 1] }
+    }
+
+    pub fn record(&mut self, micros: u64) {
+        let secs = micros as f64 / 1e6;
+        let no = RTT_BUCKETS_S.iter().position(|le| secs <= *le).unwrap_or(RTT_BUCKETS_S.len());
+        self.counts[no] += 1;
+    }
+
+    /// Count per bucket, the last being over the largest bound.
+    pub fn counts(&self) -> &[u64] {
+        &self.counts
+    }
+
+    /// (upper bound, cumulative count) pairs, ending with (+Inf, total).
+    pub fn cumulative(&self) -> Vec<(f64, u64)> {
+        let mut total = 0;
+        self.counts.iter().enumerate().map(|(no, c)| {
+            total += c;
+            (RTT_BUCKETS_S.get(no).copied().unwrap_or(f64::INFINITY), total)
+        }).collect()
+    }
+}
+
+/// Point in time view of one host for the metric exporters.  Everything in it
+/// counts from start, independent of the -R resets of the log report.
+pub struct HostMetrics {
+    pub host: HostInfo,
+    pub state: HealthState,
+    pub reachable: bool,
+    /// Replies, timeouts and RTT sums since start.
+    pub totals: WindowAgg,
+    /// ICMP errors about the host's probes since start.
+    pub non_replies: u64,
+    pub rtt: RttBuckets,
+    /// Completed outages and their downtime since start.
+    pub outages: u32,
+    pub downtime: Duration,
+    /// RTT of the latest reply, None after a loss.
+    pub last_rtt_us: Option<u64>,
+    /// Current RFC 3550 interarrival jitter estimate.
+    pub jitter_ms: Option<f64>,
+    /// E-model R factor and MOS over the current report period, once there
+    /// has been a reply.
+    pub voice: Option<VoiceQuality>,
+    pub at: SystemTime,
+}
+
+impl HostMetrics {
+    /// Host name, or the IP when the target was given as an address.
+    pub fn name(&self) -> String {
+        self.host.host.clone().unwrap_or_else(|| self.host.ip.to_string())
+    }
+}
+
+/// Replies, timeouts and RTT sum over one push interval.
+pub struct Delta {
+    pub replies: u64,
+    pub timeouts: u64,
+    pub sum_us: u64,
+}
+
+impl Delta {
+    pub fn loss_pct(&self) -> Option<f64> {
+        let sent = self.replies + self.timeouts;
+        (sent > 0).then(|| self.timeouts as f64 * 100.0 / sent as f64)
+    }
+
+    pub fn rtt_avg_ms(&self) -> Option<f64> {
+        (self.replies > 0).then(|| self.sum_us as f64 / self.replies as f64 / 1000.0)
+    }
+}
+
+/// Turns the since-start totals into per-interval deltas for push exporters,
+/// which each keep their own so they can push on different schedules.
+#[derive(Default)]
+pub struct Deltas {
+    prev: HashMap<HostKey, (u64, u64, u64)>,
+}
+
+impl Deltas {
+    pub fn update(&mut self, m: &HostMetrics) -> Delta {
+        let t = &m.totals;
+        let (reply, timeout, sum) = self.prev.insert(m.host.key(), (t.reply, t.timeout, t.sum_us)).unwrap_or_default();
+        Delta { replies: t.reply - reply, timeouts: t.timeout - timeout, sum_us: t.sum_us - sum }
+    }
+}
+
+/// Path component for a target in names whose levels are split by `sep`, '.'
+/// for StatsD / Graphite and '/' for MQTT topics: the name with `sep` and
+/// anything but letters, digits, '-', '_' and '.' turned into '_', plus the
+/// netns / mark when set.
+pub fn metric_path(h: &HostInfo, sep: char) -> String {
+    let keep = |c: char| c != sep && (c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+    let clean = |s: &str| s.chars().map(|c| if keep(c) { c } else { '_' }).collect::<String>();
+    let mut p = clean(&h.host.clone().unwrap_or_else(|| h.ip.to_string()));
+    if let Some(netns) = &h.netns {
+        p.push_str("_netns_");
+        p.push_str(&clean(netns));
+    }
+    if let Some(mark) = h.mark {
+        p.push_str(&format!("_mark_{:x}", mark));
+    }
+    p
+}
+
+/// Fed the since-start metrics once per stats interval by `stats_thread`.
+pub trait IntervalExporter: Send {
+    fn export(&mut self, hosts: &[HostMetrics]);
+}
+
+struct Textfile(PathBuf);
+
+impl IntervalExporter for Textfile {
+    fn export(&mut self, hosts: &[HostMetrics]) {
+        if let Err(e) = prometheus::write_textfile(&self.0, hosts) {
+            warn!("error updating metrics textfile: {:#}", e);
+        }
+    }
+}
+
+/// Starts the configured metric outputs: the scrape endpoint and per-probe
+/// senders run on their own, the rest are returned for `stats_thread`.
+pub fn setup(cfg: &Config, tracker: &Tracks) -> Result<Vec<Box<dyn IntervalExporter>>> {
+    let mut v: Vec<Box<dyn IntervalExporter>> = vec![];
+    if let Some(addr) = cfg.prometheus {
+        prometheus::serve(addr, tracker.clone())?;
+    }
+    if let Some(path) = &cfg.textfile {
+        v.push(Box::new(Textfile(path.clone())));
+    }
+    if let Some(addr) = &cfg.statsd {
+        v.push(Box::new(statsd::Statsd::open(addr, &cfg.metrics_prefix)?));
+    }
+    if let Some(addr) = &cfg.graphite {
+        v.push(Box::new(statsd::Graphite::new(addr, &cfg.metrics_prefix)?));
+    }
+    if let Some(dest) = &cfg.influx {
+        v.push(Box::new(influx::open(dest, cfg.influx_token.as_deref())?));
+    }
+    if let Some(url) = &cfg.otlp {
+        v.push(Box::new(otlp::open(url, cfg.otlp_json, &cfg.otlp_header)?));
+    }
+    if let Some(addr) = &cfg.mqtt {
+        v.push(Box::new(mqtt::open(addr, &cfg.mqtt_prefix, cfg.mqtt_user.as_deref(), cfg.mqtt_password.as_deref())?));
+    }
+    Ok(v)
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    /// An up host with `reply` replies of 2ms each and `timeout` timeouts,
+    /// taken at 1700000000s, for the exporter tests.
+    pub fn host_metrics(host: HostInfo, reply: u64, timeout: u64) -> HostMetrics {
+        let mut rtt = RttBuckets::new();
+        for _ in 0..reply {
+            rtt.record(2000);
+        }
+        HostMetrics {
+            host,
+            state: HealthState::Up,
+            reachable: true,
+            totals: WindowAgg {
+                reply,
+                timeout,
+                sum_us: reply * 2000,
+                sum_sq_us: reply * 2000 * 2000,
+                min_us: 2000,
+                max_us: 2000,
+            },
+            non_replies: 0,
+            rtt,
+            outages: 1,
+            downtime: Duration::from_millis(1500),
+            last_rtt_us: Some(2000),
+            jitter_ms: Some(0.25),
+            voice: Some(VoiceQuality { r: 93.0, mos: 4.4 }),
+            at: SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+        }
+    }
+
+    #[test]
+    fn deltas_cover_the_interval() {
+        let h = HostInfo::new(None, "192.0.2.1".parse().unwrap());
+        let mut deltas = Deltas::default();
+        let d = deltas.update(&host_metrics(h.clone(), 8, 2));
+        assert_eq!((d.replies, d.timeouts), (8, 2));
+        assert_eq!(d.loss_pct(), Some(20.0));
+        let d = deltas.update(&host_metrics(h.clone(), 11, 3));
+        assert_eq!((d.replies, d.timeouts), (3, 1));
+        assert_eq!(d.loss_pct(), Some(25.0));
+        assert_eq!(d.rtt_avg_ms(), Some(2.0));
+        let d = deltas.update(&host_metrics(h, 11, 3));
+        assert_eq!((d.loss_pct(), d.rtt_avg_ms()), (None, None));
+    }
+}
@@ -0,0 +1,401 @@
+#![allow(dead_code)]
+use std::collections::{BTreeMap, VecDeque};
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Condvar, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use anyhow::{anyhow, bail, Context, Result};
+use humantime::format_rfc3339_millis;
+use log::{debug, info, warn};
+use crate::cli::HostInfo;
+use crate::health::HealthState;
+use crate::json::JsonObject;
+use crate::metrics::{metric_path, Delta, Deltas, HostMetrics, IntervalExporter};
+
+const KEEPALIVE: Duration = Duration::from_secs(60);
+/// Messages queued between writer wakeups; stats past this are dropped.
+const MAX_PENDING: usize = 10_000;
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+struct Message {
+    topic: String,
+    payload: Vec<u8>,
+    retain: bool,
+}
+
+struct Pending {
+    queue: VecDeque<Message>,
+    /// Latest retained payload per topic, republished after every (re)connect.
+    retained: BTreeMap<String, Vec<u8>>,
+}
+
+struct Shared {
+    pending: Mutex<Pending>,
+    ready: Condvar,
+    prefix: String,
+}
+
+static CLIENT: OnceLock<Arc<Shared>> = OnceLock::new();
+
+/// Broker connection settings for the writer thread.
+struct Broker {
+    addr: SocketAddr,
+    client_id: String,
+    user: Option<String>,
+    password: Option<String>,
+    /// `<prefix>/status`: "online" while connected, "offline" as the last will.
+    status_topic: String,
+}
+
+fn remaining_len(out: &mut Vec<u8>, mut len: usize) {
+    loop {
+        let mut b = (len % 128) as u8;
+        len /= 128;
+        if len > 0 {
+            b |= 0x80;
+        }
+        out.push(b);
+        if len == 0 {
+            break;
+        }
+    }
+}
+
+fn put_str(out: &mut Vec<u8>, s: &[u8]) {
+    out.extend_from_slice(&(s.len() as u16).to_be_bytes());
+    out.extend_from_slice(s);
+}
+
+fn packet(kind: u8, body: &[u8]) -> Vec<u8> {
+    let mut p = vec![kind];
+    remaining_len(&mut p, body.len());
+    p.extend_from_slice(body);
+    p
+}
+
+/// MQTT 3.1.1 CONNECT with a clean session and a retained QoS 0 will.
+fn connect_packet(b: &Broker) -> Vec<u8> {
+    let mut flags = 0x02 | 0x04 | 0x20;
+    if b.user.is_some() {
+        flags |= 0x80;
+    }
+    if b.password.is_some() {
+        flags |= 0x40;
+    }
+    let mut body = vec![];
+    put_str(&mut body, b"MQTT");
+    body.push(4);
+    body.push(flags);
+    body.extend_from_slice(&(KEEPALIVE.as_secs() as u16).to_be_bytes());
+    put_str(&mut body, b.client_id.as_bytes());
+    put_str(&mut body, b.status_topic.as_bytes());
+    put_str(&mut body, b"offline");
+    if let Some(u) = &b.user {
+        put_str(&mut body, u.as_bytes());
+    }
+    if let Some(p) = &b.password {
+        put_str(&mut body, p.as_bytes());
+    }
+    packet(0x10, &body)
+}
+
+/// QoS 0 PUBLISH.
+fn publish_packet(topic: &str, payload: &[u8], retain: bool) -> Vec<u8> {
+    let mut body = vec![];
+    put_str(&mut body, topic.as_bytes());
+    body.extend_from_slice(payload);
+    packet(0x30 | retain as u8, &body)
+}
+
+fn connect(b: &Broker) -> Result<TcpStream> {
+    let mut s = TcpStream::connect_timeout(&b.addr, Duration::from_secs(5))?;
+    s.set_read_timeout(Some(Duration::from_secs(10)))?;
+    s.set_write_timeout(Some(Duration::from_secs(10)))?;
+    s.set_nodelay(true)?;
+    s.write_all(&connect_packet(b))?;
+    let mut ack = [0u8; 4];
+    s.read_exact(&mut ack).context("waiting for CONNACK")?;
+    match ack {
+        [0x20, 2, _, 0] => Ok(s),
+        [0x20, 2, _, 4 | 5] => bail!("broker refused the credentials (code {})", ack[3]),
+        [0x20, 2, _, rc] => bail!("broker refused the connection (code {})", rc),
+        _ => bail!("unexpected reply to CONNECT: {:02x?}", ack),
+    }
+}
+
+/// PINGREQ and wait for the PINGRESP.  Nothing is subscribed, so that is all
+/// the broker ever sends after the CONNACK.
+fn ping(s: &mut TcpStream) -> std::io::Result<()> {
+    s.write_all(&[0xc0, 0])?;
+    let mut resp = [0u8; 2];
+    s.read_exact(&mut resp)?;
+    if resp != [0xd0, 0] {
+        return Err(std::io::Error::other(format!("unexpected packet {:02x?}", resp)));
+    }
+    Ok(())
+}
+
+/// Publishes queued messages until the connection fails.
+fn session(sh: &Shared, b: &Broker, s: &mut TcpStream) -> std::io::Result<()> {
+    let mut out = publish_packet(&b.status_topic, b"online", true);
+    {
+        // anything queued while disconnected is either stale stats or already
+        // in the retained set
+        let mut p = sh.pending.lock().unwrap();
+        p.queue.clear();
+        for (topic, payload) in &p.retained {
+            out.extend(publish_packet(topic, payload, true));
+        }
+    }
+    s.write_all(&out)?;
+    let mut last_write = Instant::now();
+    loop {
+        let batch: Vec<Message> = {
+            let mut p = sh.pending.lock().unwrap();
+            if p.queue.is_empty() {
+                let wait = (KEEPALIVE / 2).saturating_sub(last_write.elapsed());
+                p = sh.ready.wait_timeout(p, wait).unwrap().0;
+            }
+            p.queue.drain(..).collect()
+        };
+        if batch.is_empty() {
+            if last_write.elapsed() >= KEEPALIVE / 2 {
+                ping(s)?;
+                last_write = Instant::now();
+            }
+            continue;
+        }
+        let mut out = vec![];
+        for m in &batch {
+            out.extend(publish_packet(&m.topic, &m.payload, m.retain));
+        }
+        s.write_all(&out)?;
+        last_write = Instant::now();
+    }
+}
+
+fn writer_thread(sh: Arc<Shared>, b: Broker) {
+    let mut backoff = Duration::from_secs(1);
+    let mut failing = false;
+    loop {
+        match connect(&b) {
+            Ok(mut s) => {
+                info!("connected to mqtt broker {}", b.addr);
+                failing = false;
+                backoff = Duration::from_secs(1);
+                if let Err(e) = session(&sh, &b, &mut s) {
+                    warn!("mqtt connection to {} lost, reconnecting: {}", b.addr, e);
+                }
+            }
+            Err(e) => {
+                if !failing {
+                    warn!("cannot connect to mqtt broker {}, will retry: {:#}", b.addr, e);
+                    failing = true;
+                }
+                debug!("mqtt connect failed, retry in {:?}: {:#}", backoff, e);
+                std::thread::sleep(backoff);
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+            }
+        }
+    }
+}
+
+fn enqueue(sh: &Shared, m: Message) {
+    let mut p = sh.pending.lock().unwrap();
+    if m.retain {
+        p.retained.insert(m.topic.clone(), m.payload.clone());
+    }
+    if p.queue.len() >= MAX_PENDING {
+        p.queue.pop_front();
+    }
+    p.queue.push_back(m);
+    sh.ready.notify_one();
+}
+
+/// Topic level for a target; the '+' and '#' wildcards are replaced along
+/// with the level separator.
+pub fn topic_name(h: &HostInfo) -> String {
+    metric_path(h, '/')
+}
+
+/// Retained `<prefix>/<host>/state`, only sent when it differs from the last one.
+pub fn state(h: &HostInfo, state: HealthState) {
+    let Some(sh) = CLIENT.get() else {
+        return;
+    };
+    let topic = format!("{}/{}/state", sh.prefix, topic_name(h));
+    let payload = state.to_string().into_bytes();
+    if sh.pending.lock().unwrap().retained.get(&topic) == Some(&payload) {
+        return;
+    }
+    enqueue(sh, Message { topic, payload, retain: true });
+}
+
+/// Publishes `<prefix>/<host>/stats` JSON each stats interval, and refreshes
+/// the retained state so every host has one even before its first change.
+pub struct Mqtt {
+    deltas: Deltas,
+}
+
+impl IntervalExporter for Mqtt {
+    fn export(&mut self, hosts: &[HostMetrics]) {
+        let Some(sh) = CLIENT.get() else {
+            return;
+        };
+        for m in hosts {
+            state(&m.host, m.state);
+            let d = self.deltas.update(m);
+            let payload = stats_payload(m, &d);
+            enqueue(sh, Message {
+                topic: format!("{}/{}/stats", sh.prefix, topic_name(&m.host)),
+                payload: payload.into_bytes(),
+                retain: false,
+            });
+        }
+    }
+}
+
+/// JSON body of the per-interval `<prefix>/<host>/stats` message.
+fn stats_payload(m: &HostMetrics, d: &Delta) -> String {
+    JsonObject::new()
+        .str("ts", &format_rfc3339_millis(m.at).to_string())
+        .str("host", &m.name())
+        .str("ip", &m.host.ip.to_string())
+        .str("state", &m.state.to_string())
+        .bool("up", m.reachable)
+        .num("replies", m.totals.reply)
+        .num("timeouts", m.totals.timeout)
+        .opt_num("loss_pct", d.loss_pct())
+        .opt_num("rtt_avg_ms", d.rtt_avg_ms())
+        .opt_num("last_rtt_ms", m.last_rtt_us.map(|us| us as f64 / 1000.0))
+        .opt_num("jitter_ms", m.jitter_ms)
+        .opt_num("r", m.voice.map(|q| q.r))
+        .opt_num("mos", m.voice.map(|q| q.mos))
+        .num("outages", m.outages)
+        .num("downtime_s", m.downtime.as_secs_f64())
+        .finish()
+}
+
+/// Starts the publisher thread for the broker at `addr` (port 1883 unless given).
+pub fn open(addr: &str, prefix: &str, user: Option<&str>, password: Option<&str>) -> Result<Mqtt> {
+    if password.is_some() && user.is_none() {
+        // MQTT 3.1.1 does not allow the password flag without the user name flag
+        bail!("--mqtt-password needs --mqtt-user");
+    }
+    let with_port = if addr.rsplit_once(':').is_some_and(|(_, p)| p.parse::<u16>().is_ok()) && !addr.ends_with(']') {
+        addr.to_string()
+    } else {
+        format!("{}:1883", addr)
+    };
+    let sock_addr = with_port.to_socket_addrs().with_context(|| format!("resolving \"{}\"", with_port))?
+        .next().ok_or_else(|| anyhow!("no address for \"{}\"", with_port))?;
+    let prefix = prefix.trim_end_matches('/').to_string();
+    let broker = Broker {
+        addr: sock_addr,
+        client_id: format!("sirpingsalot-{}", std::process::id()),
+        user: user.map(String::from),
+        password: password.map(String::from),
+        status_topic: format!("{}/status", prefix),
+    };
+    let sh = Arc::new(Shared {
+        pending: Mutex::new(Pending { queue: VecDeque::new(), retained: BTreeMap::new() }),
+        ready: Condvar::new(),
+        prefix,
+    });
+    if CLIENT.set(sh.clone()).is_err() {
+        return Err(anyhow!("mqtt output already open"));
+    }
+    info!("publishing mqtt to {}", sock_addr);
+    std::thread::Builder::new()
+        .name(String::from("mqtt"))
+        .spawn(move || writer_thread(sh, broker))?;
+    Ok(Mqtt { deltas: Deltas::default() })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::tests::host_metrics;
+
+    #[test]
+    fn topic_level_replaces_separator_and_wildcards() {
+        let mut h = HostInfo::new(Some("a/b+c#d.example".to_string()), "192.0.2.1".parse().unwrap());
+        assert_eq!(topic_name(&h), "a_b_c_d.example");
+        h.netns = Some("blue/x".to_string());
+        h.mark = Some(0x1f);
+        assert_eq!(topic_name(&h), "a_b_c_d.example_netns_blue_x_mark_1f");
+        // the dotted form keeps '/' out too, and the dots
+        assert_eq!(metric_path(&h, '.'), "a_b_c_d_example_netns_blue_x_mark_1f");
+    }
+
+    fn broker(user: Option<&str>, password: Option<&str>) -> Broker {
+        Broker {
+            addr: "127.0.0.1:1883".parse().unwrap(),
+            client_id: "c1".to_string(),
+            user: user.map(String::from),
+            password: password.map(String::from),
+            status_topic: "sp/status".to_string(),
+        }
+    }
+
+    #[test]
+    fn connect_bytes() {
+        let header = [0, 4, b'M', b'Q', b'T', b'T', 4];
+        let tail: Vec<u8> = [&[0, 2][..], b"c1", &[0, 9], b"sp/status", &[0, 7], b"offline"].concat();
+        // clean session, will, will retain; 60s keepalive
+        let plain = [&[0x10, 34][..], &header, &[0x26, 0, 60], &tail].concat();
+        assert_eq!(connect_packet(&broker(None, None)), plain);
+        let login = [&[0x10, 44][..], &header, &[0xe6, 0, 60], &tail, &[0, 3], b"bob", &[0, 3], b"pw1"].concat();
+        assert_eq!(connect_packet(&broker(Some("bob"), Some("pw1"))), login);
+        let user_only = connect_packet(&broker(Some("bob"), None));
+        assert_eq!((user_only[1], user_only[9]), (39, 0xa6));
+    }
+
+    #[test]
+    fn publish_bytes() {
+        assert_eq!(publish_packet("a/b", b"up", false), [&[0x30, 7, 0, 3][..], b"a/b", b"up"].concat());
+        assert_eq!(publish_packet("a/b", b"up", true)[0], 0x31);
+    }
+
+    #[test]
+    fn remaining_length_boundaries() {
+        for (len, expect) in [
+            (0, &[0x00][..]),
+            (127, &[0x7f]),
+            (128, &[0x80, 0x01]),
+            (16383, &[0xff, 0x7f]),
+            (16384, &[0x80, 0x80, 0x01]),
+            (2_097_151, &[0xff, 0xff, 0x7f]),
+            (2_097_152, &[0x80, 0x80, 0x80, 0x01]),
+        ] {
+            let mut out = vec![];
+            remaining_len(&mut out, len);
+            assert_eq!(out, expect, "length {}", len);
+        }
+        // a one-byte topic takes three bytes of the body
+        for (body, header) in [(127, &[0x30, 0x7f][..]), (128, &[0x30, 0x80, 0x01]),
+                               (16383, &[0x30, 0xff, 0x7f]), (16384, &[0x30, 0x80, 0x80, 0x01])] {
+            let p = publish_packet("t", &vec![b'x'; body - 3], false);
+            assert_eq!(&p[..header.len()], header, "body {}", body);
+            assert_eq!(&p[header.len()..header.len() + 3], &[0, 1, b't']);
+            assert_eq!(p.len(), header.len() + body);
+        }
+    }
+
+    #[test]
+    fn stats_payload_fields() {
+        let mut m = host_metrics(HostInfo::new(Some("web".to_string()), "192.0.2.1".parse().unwrap()), 9, 1);
+        let d = Deltas::default().update(&m);
+        assert_eq!(stats_payload(&m, &d),
+            r#"{"ts":"2023-11-14T22:13:20.000Z","host":"web","ip":"192.0.2.1","state":"up","up":true,"replies":9,"timeouts":1,"#.to_string() +
+            r#""loss_pct":10,"rtt_avg_ms":2,"last_rtt_ms":2,"jitter_ms":0.25,"r":93,"mos":4.4,"outages":1,"downtime_s":1.5}"#);
+        m.voice = None;
+        assert!(stats_payload(&m, &d).contains(r#""r":null,"mos":null"#));
+    }
+
+    #[test]
+    fn password_needs_user() {
+        let err = open("127.0.0.1", "sirping", None, Some("secret")).err().unwrap();
+        assert!(err.to_string().contains("--mqtt-user"));
+    }
+}
@@ -0,0 +1,29 @@
+use std::fs::File;
+
+use anyhow::{Context, Result};
+use nix::sched::{setns, CloneFlags};
+use log::debug;
+
+const NETNS_RUN_DIR: &str = "/var/run/netns";
+
+/// Runs `f` on a short-lived thread that has joined the named network namespace.
+/// setns only moves the calling thread, so sockets created by `f` live in that
+/// namespace while the caller's thread stays where it was.
+pub fn run_in<T, F>(name: &str, f: F) -> Result<T>
+    where T: Send + 'static,
+          F: FnOnce() -> Result<T> + Send + 'static,
+{
+    let path = format!("{}/{}", NETNS_RUN_DIR, name);
+    let ns = File::open(&path)
+        .with_context(|| format!("cannot open network namespace {}", path))?;
+
+    let h = std::thread::Builder::new()
+        .name(format!("netns-{}", name))
+        .spawn(move || -> Result<T> {
+            setns(&ns, CloneFlags::CLONE_NEWNET)
+                .with_context(|| format!("setns into {} failed", path))?;
+            debug!("entered network namespace {}", path);
+            f()
+        })?;
+    h.join().map_err(|_| anyhow::anyhow!("netns thread for {} panicked", name))?
+}
@@ -0,0 +1,471 @@
+#![allow(dead_code)]
+use std::time::SystemTime;
+use anyhow::{anyhow, Result};
+use log::{info, warn};
+use crate::emodel::VoiceQuality;
+use crate::http::HttpTarget;
+use crate::json::JsonObject;
+use crate::metrics::{HostMetrics, IntervalExporter, RTT_BUCKETS_S};
+
+/// AGGREGATION_TEMPORALITY_CUMULATIVE: every push carries totals since start,
+/// so a failed push is made up for by the next one.
+const CUMULATIVE: u64 = 2;
+
+enum Value {
+    Int(i64),
+    Double(f64),
+}
+
+struct NumPoint {
+    attrs: Vec<(&'static str, String)>,
+    value: Value,
+}
+
+struct HistPoint {
+    attrs: Vec<(&'static str, String)>,
+    count: u64,
+    sum: f64,
+    /// Per bucket, one more than RTT_BUCKETS_S.
+    buckets: Vec<u64>,
+}
+
+enum Data {
+    Gauge(Vec<NumPoint>),
+    /// Monotonic cumulative sum.
+    Counter(Vec<NumPoint>),
+    Histogram(Vec<HistPoint>),
+}
+
+struct Metric {
+    name: &'static str,
+    description: &'static str,
+    unit: &'static str,
+    data: Data,
+}
+
+fn attrs(m: &HostMetrics) -> Vec<(&'static str, String)> {
+    let mut a = vec![("host", m.name()), ("ip", m.host.ip.to_string())];
+    if let Some(netns) = &m.host.netns {
+        a.push(("netns", netns.clone()));
+    }
+    if let Some(mark) = m.host.mark {
+        a.push(("mark", format!("{:#x}", mark)));
+    }
+    a
+}
+
+fn build(hosts: &[HostMetrics]) -> Vec<Metric> {
+    let points = |f: &dyn Fn(&HostMetrics) -> Value| -> Vec<NumPoint> {
+        hosts.iter().map(|m| NumPoint { attrs: attrs(m), value: f(m) }).collect()
+    };
+    let mut v = vec![
+        Metric { name: "sirping.replies", description: "Echo replies received.", unit: "{reply}",
+                 data: Data::Counter(points(&|m| Value::Int(m.totals.reply as i64))) },
+        Metric { name: "sirping.timeouts", description: "Probes that got no reply in time.", unit: "{probe}",
+                 data: Data::Counter(points(&|m| Value::Int(m.totals.timeout as i64))) },
+        Metric { name: "sirping.outages", description: "Completed outages.", unit: "{outage}",
+                 data: Data::Counter(points(&|m| Value::Int(m.outages as i64))) },
+        Metric { name: "sirping.downtime", description: "Time spent in completed outages.", unit: "s",
+                 data: Data::Counter(points(&|m| Value::Double(m.downtime.as_secs_f64()))) },
+        Metric { name: "sirping.up", description: "1 while the host is reachable, 0 once it is considered down.", unit: "1",
+                 data: Data::Gauge(points(&|m| Value::Int(m.reachable as i64))) },
+    ];
+    let jitter: Vec<NumPoint> = hosts.iter().filter_map(|m| {
+        m.jitter_ms.map(|j| NumPoint { attrs: attrs(m), value: Value::Double(j / 1000.0) })
+    }).collect();
+    if !jitter.is_empty() {
+        v.push(Metric { name: "sirping.jitter", description: "RFC 3550 interarrival jitter estimate.", unit: "s",
+                        data: Data::Gauge(jitter) });
+    }
+    let voice = |f: &dyn Fn(&VoiceQuality) -> f64| -> Vec<NumPoint> {
+        hosts.iter().filter_map(|m| m.voice.as_ref().map(|q| NumPoint { attrs: attrs(m), value: Value::Double(f(q)) })).collect()
+    };
+    let (r, mos) = (voice(&|q| q.r), voice(&|q| q.mos));
+    if !r.is_empty() {
+        v.push(Metric { name: "sirping.r_factor", description: "E-model transmission rating factor R over the report period.", unit: "1",
+                        data: Data::Gauge(r) });
+        v.push(Metric { name: "sirping.mos", description: "Mean opinion score estimated from R.", unit: "1",
+                        data: Data::Gauge(mos) });
+    }
+    v.push(Metric { name: "sirping.rtt", description: "Round trip time of echo replies.", unit: "s",
+                    data: Data::Histogram(hosts.iter().map(|m| HistPoint {
+                        attrs: attrs(m),
+                        count: m.totals.reply,
+                        sum: m.totals.sum_us as f64 / 1e6,
+                        buckets: m.rtt.counts().to_vec(),
+                    }).collect()) });
+    v
+}
+
+fn nanos(t: SystemTime) -> u64 {
+    t.duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().as_nanos() as u64
+}
+
+/// Just enough of the protobuf wire format for ExportMetricsServiceRequest.
+struct Pb(Vec<u8>);
+
+impl Pb {
+    fn varint(&mut self, mut v: u64) {
+        while v >= 0x80 {
+            self.0.push((v as u8) | 0x80);
+            v >>= 7;
+        }
+        self.0.push(v as u8);
+    }
+
+    fn key(&mut self, field: u32, wire: u8) {
+        self.varint(((field as u64) << 3) | wire as u64);
+    }
+
+    fn uint(&mut self, field: u32, v: u64) {
+        self.key(field, 0);
+        self.varint(v);
+    }
+
+    fn fixed64(&mut self, field: u32, v: u64) {
+        self.key(field, 1);
+        self.0.extend_from_slice(&v.to_le_bytes());
+    }
+
+    fn double(&mut self, field: u32, v: f64) {
+        self.fixed64(field, v.to_bits());
+    }
+
+    fn bytes(&mut self, field: u32, b: &[u8]) {
+        self.key(field, 2);
+        self.varint(b.len() as u64);
+        self.0.extend_from_slice(b);
+    }
+
+    fn string(&mut self, field: u32, s: &str) {
+        self.bytes(field, s.as_bytes());
+    }
+
+    fn message(&mut self, field: u32, f: impl FnOnce(&mut Pb)) {
+        let mut inner = Pb(Vec::new());
+        f(&mut inner);
+        self.bytes(field, &inner.0);
+    }
+
+    fn packed_fixed64(&mut self, field: u32, vs: impl Iterator<Item = u64>) {
+        let b: Vec<u8> = vs.flat_map(|v| v.to_le_bytes()).collect();
+        self.bytes(field, &b);
+    }
+
+    /// KeyValue with a string AnyValue.
+    fn attr(&mut self, field: u32, k: &str, v: &str) {
+        self.message(field, |kv| {
+            kv.string(1, k);
+            kv.message(2, |any| any.string(1, v));
+        });
+    }
+}
+
+fn encode_pb(metrics: &[Metric], start: u64, now: u64) -> Vec<u8> {
+    let mut req = Pb(Vec::new());
+    // ExportMetricsServiceRequest.resource_metrics
+    req.message(1, |rm| {
+        rm.message(1, |res| {
+            res.attr(1, "service.name", "sirpingsalot");
+            res.attr(1, "service.version", env!("CARGO_PKG_VERSION"));
+        });
+        // ResourceMetrics.scope_metrics
+        rm.message(2, |sm| {
+            sm.message(1, |scope| {
+                scope.string(1, "sirpingsalot");
+                scope.string(2, env!("CARGO_PKG_VERSION"));
+            });
+            for m in metrics {
+                sm.message(2, |mp| {
+                    mp.string(1, m.name);
+                    mp.string(2, m.description);
+                    mp.string(3, m.unit);
+                    let num_point = |dp: &mut Pb, p: &NumPoint| {
+                        for (k, v) in &p.attrs {
+                            dp.attr(7, k, v);
+                        }
+                        dp.fixed64(2, start);
+                        dp.fixed64(3, now);
+                        match p.value {
+                            Value::Double(d) => dp.double(4, d),
+                            // sfixed64
+                            Value::Int(i) => dp.fixed64(6, i as u64),
+                        }
+                    };
+                    match &m.data {
+                        Data::Gauge(points) => mp.message(5, |g| {
+                            for p in points {
+                                g.message(1, |dp| num_point(dp, p));
+                            }
+                        }),
+                        Data::Counter(points) => mp.message(7, |s| {
+                            for p in points {
+                                s.message(1, |dp| num_point(dp, p));
+                            }
+                            s.uint(2, CUMULATIVE);
+                            s.uint(3, 1);
+                        }),
+                        Data::Histogram(points) => mp.message(9, |h| {
+                            for p in points {
+                                h.message(1, |dp| {
+                                    for (k, v) in &p.attrs {
+                                        dp.attr(9, k, v);
+                                    }
+                                    dp.fixed64(2, start);
+                                    dp.fixed64(3, now);
+                                    dp.fixed64(4, p.count);
+                                    dp.double(5, p.sum);
+                                    dp.packed_fixed64(6, p.buckets.iter().copied());
+                                    dp.packed_fixed64(7, RTT_BUCKETS_S.iter().map(|b| b.to_bits()));
+                                });
+                            }
+                            h.uint(2, CUMULATIVE);
+                        }),
+                    }
+                });
+            }
+        });
+    });
+    req.0
+}
+
+fn json_attrs(attrs: &[(&str, String)]) -> String {
+    let v: Vec<String> = attrs.iter().map(|(k, v)| {
+        JsonObject::new().str("key", k).raw("value", &JsonObject::new().str("stringValue", v).finish()).finish()
+    }).collect();
+    format!("[{}]", v.join(","))
+}
+
+fn json_array(items: impl Iterator<Item = String>) -> String {
+    format!("[{}]", items.collect::<Vec<_>>().join(","))
+}
+
+/// The OTLP/JSON mapping: lowerCamelCase names, 64 bit integers as strings.
+fn encode_json(metrics: &[Metric], start: u64, now: u64) -> Vec<u8> {
+    let version = env!("CARGO_PKG_VERSION");
+    let num_point = |p: &NumPoint| {
+        let dp = JsonObject::new()
+            .raw("attributes", &json_attrs(&p.attrs))
+            .str("startTimeUnixNano", &start.to_string())
+            .str("timeUnixNano", &now.to_string());
+        match p.value {
+            Value::Double(d) => dp.num("asDouble", d),
+            Value::Int(i) => dp.str("asInt", &i.to_string()),
+        }.finish()
+    };
+    let metrics = json_array(metrics.iter().map(|m| {
+        let o = JsonObject::new().str("name", m.name).str("description", m.description).str("unit", m.unit);
+        match &m.data {
+            Data::Gauge(points) => o.raw("gauge", &JsonObject::new()
+                .raw("dataPoints", &json_array(points.iter().map(num_point))).finish()),
+            Data::Counter(points) => o.raw("sum", &JsonObject::new()
+                .raw("dataPoints", &json_array(points.iter().map(num_point)))
+                .num("aggregationTemporality", CUMULATIVE)
+                .bool("isMonotonic", true).finish()),
+            Data::Histogram(points) => o.raw("histogram", &JsonObject::new()
+                .raw("dataPoints", &json_array(points.iter().map(|p| JsonObject::new()
+                    .raw("attributes", &json_attrs(&p.attrs))
+                    .str("startTimeUnixNano", &start.to_string())
+                    .str("timeUnixNano", &now.to_string())
+                    .str("count", &p.count.to_string())
+                    .num("sum", p.sum)
+                    .raw("bucketCounts", &json_array(p.buckets.iter().map(|c| format!("\"{}\"", c))))
+                    .raw("explicitBounds", &json_array(RTT_BUCKETS_S.iter().map(|b| b.to_string())))
+                    .finish())))
+                .num("aggregationTemporality", CUMULATIVE).finish()),
+        }.finish()
+    }));
+    let resource = JsonObject::new().raw("attributes", &json_attrs(&[
+        ("service.name", String::from("sirpingsalot")),
+        ("service.version", String::from(version)),
+    ])).finish();
+    let scope = JsonObject::new().str("name", "sirpingsalot").str("version", version).finish();
+    let scope_metrics = JsonObject::new().raw("scope", &scope).raw("metrics", &metrics).finish();
+    let rm = JsonObject::new().raw("resource", &resource).raw("scopeMetrics", &format!("[{}]", scope_metrics)).finish();
+    JsonObject::new().raw("resourceMetrics", &format!("[{}]", rm)).finish().into_bytes()
+}
+
+/// Pushes all hosts' metrics to an OTLP/HTTP collector each stats interval.
+pub struct Otlp {
+    target: HttpTarget,
+    json: bool,
+    headers: Vec<(String, String)>,
+    start: SystemTime,
+    failing: bool,
+}
+
+/// `url` defaults to the /v1/metrics path; `headers` are "name=value" pairs.
+pub fn open(url: &str, json: bool, headers: &[String]) -> Result<Otlp> {
+    let target = HttpTarget::parse(url, "/v1/metrics")?;
+    let headers = headers.iter().map(|h| {
+        h.split_once('=').map(|(k, v)| (k.trim().to_string(), v.trim().to_string()))
+            .ok_or_else(|| anyhow!("otlp header \"{}\" must be name=value", h))
+    }).collect::<Result<Vec<_>>>()?;
+    info!("exporting otlp metrics ({}) to {}", if json { "json" } else { "protobuf" }, target);
+    Ok(Otlp { target, json, headers, start: SystemTime::now(), failing: false })
+}
+
+impl IntervalExporter for Otlp {
+    fn export(&mut self, hosts: &[HostMetrics]) {
+        let now = hosts.first().map_or(SystemTime::now(), |m| m.at);
+        let metrics = build(hosts);
+        let (content_type, body) = if self.json {
+            ("application/json", encode_json(&metrics, nanos(self.start), nanos(now)))
+        } else {
+            ("application/x-protobuf", encode_pb(&metrics, nanos(self.start), nanos(now)))
+        };
+        let res = match self.target.post(content_type, &self.headers, &body) {
+            Ok((200..=299, _)) => Ok(()),
+            Ok((_, status)) => Err(anyhow!("http status: {}", status)),
+            Err(e) => Err(e.into()),
+        };
+        match res {
+            Ok(()) if self.failing => {
+                info!("otlp export to {} working again", self.target);
+                self.failing = false;
+            }
+            Ok(()) => {}
+            Err(e) => {
+                if !self.failing {
+                    warn!("otlp export to {} failed, next interval will carry the totals: {:#}", self.target, e);
+                }
+                self.failing = true;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pb(f: impl FnOnce(&mut Pb)) -> Vec<u8> {
+        let mut p = Pb(Vec::new());
+        f(&mut p);
+        p.0
+    }
+
+    /// Splits a message into (field, wire type, payload); varints come back as
+    /// their encoded bytes.
+    fn fields(mut b: &[u8]) -> Vec<(u32, u8, &[u8])> {
+        fn varint(b: &mut &[u8]) -> u64 {
+            let mut v = 0;
+            for shift in (0..64).step_by(7) {
+                let byte = b[0];
+                *b = &b[1..];
+                v |= u64::from(byte & 0x7f) << shift;
+                if byte < 0x80 {
+                    break;
+                }
+            }
+            v
+        }
+        let mut out = vec![];
+        while !b.is_empty() {
+            let key = varint(&mut b);
+            let (field, wire) = ((key >> 3) as u32, (key & 7) as u8);
+            let len = match wire {
+                0 => b.iter().position(|x| *x < 0x80).unwrap() + 1,
+                1 => 8,
+                2 => varint(&mut b) as usize,
+                _ => panic!("unexpected wire type {}", wire),
+            };
+            out.push((field, wire, &b[..len]));
+            b = &b[len..];
+        }
+        out
+    }
+
+    #[test]
+    fn varints() {
+        assert_eq!(pb(|p| p.varint(0)), [0]);
+        assert_eq!(pb(|p| p.varint(127)), [0x7f]);
+        assert_eq!(pb(|p| p.varint(128)), [0x80, 0x01]);
+        assert_eq!(pb(|p| p.varint(300)), [0xac, 0x02]);
+        assert_eq!(pb(|p| p.varint(u64::MAX)), [0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01]);
+        // field 2, varint
+        assert_eq!(pb(|p| p.uint(2, 150)), [0x10, 0x96, 0x01]);
+        // field 16 no longer fits the key in one byte
+        assert_eq!(pb(|p| p.uint(16, 1)), [0x80, 0x01, 0x01]);
+    }
+
+    #[test]
+    fn fixed_width_little_endian() {
+        assert_eq!(pb(|p| p.fixed64(1, 0x0102_0304_0506_0708)), [0x09, 8, 7, 6, 5, 4, 3, 2, 1]);
+        assert_eq!(pb(|p| p.double(4, 1.0)), [0x21, 0, 0, 0, 0, 0, 0, 0xf0, 0x3f]);
+    }
+
+    #[test]
+    fn length_delimited() {
+        assert_eq!(pb(|p| p.string(2, "testing")), [0x12, 7, b't', b'e', b's', b't', b'i', b'n', b'g']);
+        assert_eq!(pb(|p| p.string(1, "")), [0x0a, 0]);
+        assert_eq!(pb(|p| p.message(3, |m| m.uint(1, 150))), [0x1a, 3, 0x08, 0x96, 0x01]);
+        let packed = pb(|p| p.packed_fixed64(6, [1u64, 2].into_iter()));
+        assert_eq!(packed[..2], [0x32, 16]);
+        assert_eq!(packed[2..10], 1u64.to_le_bytes());
+        assert_eq!(packed[10..], 2u64.to_le_bytes());
+        // a 200 byte payload needs a two byte length
+        assert_eq!(pb(|p| p.bytes(1, &[0; 200]))[..3], [0x0a, 0xc8, 0x01]);
+    }
+
+    #[test]
+    fn attribute_key_value() {
+        let b = pb(|p| p.attr(7, "host", "a"));
+        let kv = fields(&b);
+        assert_eq!(kv.len(), 1);
+        assert_eq!((kv[0].0, kv[0].1), (7, 2));
+        let inner = fields(kv[0].2);
+        assert_eq!(inner[0], (1, 2, &b"host"[..]));
+        assert_eq!(fields(inner[1].2), [(1, 2, &b"a"[..])]);
+    }
+
+    #[test]
+    fn voice_quality_gauges() {
+        use crate::cli::HostInfo;
+        use crate::metrics::tests::host_metrics;
+        let gauge = |metrics: &[Metric], name: &str| -> Option<Vec<f64>> {
+            metrics.iter().find(|m| m.name == name).map(|m| match &m.data {
+                Data::Gauge(points) => points.iter().map(|p| match p.value {
+                    Value::Double(v) => v,
+                    Value::Int(v) => v as f64,
+                }).collect(),
+                _ => panic!("{} is not a gauge", name),
+            })
+        };
+        let mut quiet = host_metrics(HostInfo::new(None, "192.0.2.2".parse().unwrap()), 0, 3);
+        quiet.voice = None;
+        let hosts = [host_metrics(HostInfo::new(None, "192.0.2.1".parse().unwrap()), 5, 0), quiet];
+        let metrics = build(&hosts);
+        // only the host with replies gets a point
+        assert_eq!(gauge(&metrics, "sirping.r_factor"), Some(vec![93.0]));
+        assert_eq!(gauge(&metrics, "sirping.mos"), Some(vec![4.4]));
+        assert_eq!(gauge(&build(&hosts[1..]), "sirping.r_factor"), None);
+    }
+
+    #[test]
+    fn request_nesting() {
+        let metrics = vec![Metric {
+            name: "sirping.up", description: "d", unit: "1",
+            data: Data::Counter(vec![NumPoint { attrs: vec![("host", "h".to_string())], value: Value::Int(-1) }]),
+        }];
+        let req = encode_pb(&metrics, 5, 6);
+        let rm = fields(&req);
+        assert_eq!(rm.len(), 1);
+        let rm = fields(rm[0].2);
+        // resource, then scope metrics
+        assert_eq!(rm.iter().map(|f| f.0).collect::<Vec<_>>(), [1, 2]);
+        let sm = fields(rm[1].2);
+        assert_eq!(sm.iter().map(|f| f.0).collect::<Vec<_>>(), [1, 2]);
+        let metric = fields(sm[1].2);
+        assert_eq!(metric[0], (1, 2, &b"sirping.up"[..]));
+        let sum = fields(metric[3].2);
+        assert_eq!(metric[3].0, 7);
+        // data point, temporality, monotonic
+        assert_eq!(sum.iter().map(|f| f.0).collect::<Vec<_>>(), [1, 2, 3]);
+        assert_eq!(sum[1].2, [CUMULATIVE as u8]);
+        let dp = fields(sum[0].2);
+        assert_eq!(dp.iter().map(|f| f.0).collect::<Vec<_>>(), [7, 2, 3, 6]);
+        assert_eq!(dp[1].2, 5u64.to_le_bytes());
+        assert_eq!(dp[3].2, (-1i64).to_le_bytes());
+    }
+}
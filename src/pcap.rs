@@ -0,0 +1,263 @@
+#![allow(dead_code)]
+use std::fs::File;
+use std::io::Write;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::Path;
+use std::sync::{Mutex, OnceLock};
+use std::time::SystemTime;
+use anyhow::{Context, Result};
+use log::warn;
+
+/// pcap magic for nanosecond timestamps.
+const MAGIC_NANOS: u32 = 0xa1b2_3c4d;
+/// LINKTYPE_RAW: each record is a bare IPv4 or IPv6 packet.
+const LINKTYPE_RAW: u32 = 101;
+const SNAPLEN: u32 = 65535;
+const PROTO_ICMP: u8 = 1;
+const PROTO_ICMPV6: u8 = 58;
+
+/// Capture file for --pcap; unset means capturing is off.
+static PCAP: OnceLock<Mutex<File>> = OnceLock::new();
+
+fn file_header() -> Vec<u8> {
+    let mut hdr = Vec::with_capacity(24);
+    hdr.extend_from_slice(&MAGIC_NANOS.to_le_bytes());
+    hdr.extend_from_slice(&2u16.to_le_bytes());
+    hdr.extend_from_slice(&4u16.to_le_bytes());
+    // thiszone, sigfigs
+    hdr.extend_from_slice(&[0u8; 8]);
+    hdr.extend_from_slice(&SNAPLEN.to_le_bytes());
+    hdr.extend_from_slice(&LINKTYPE_RAW.to_le_bytes());
+    hdr
+}
+
+/// Creates `path` and writes the pcap file header.
+pub fn open(path: &Path) -> Result<()> {
+    let mut f = File::create(path).with_context(|| format!("creating pcap file \"{}\"", path.display()))?;
+    f.write_all(&file_header()).with_context(|| format!("writing pcap file \"{}\"", path.display()))?;
+    let _ = PCAP.set(Mutex::new(f));
+    Ok(())
+}
+
+pub fn enabled() -> bool {
+    PCAP.get().is_some()
+}
+
+/// Writes one record, unbuffered so the file is complete whenever the process exits.
+fn record(at: SystemTime, packet: &[u8]) {
+    let Some(f) = PCAP.get() else {
+        return;
+    };
+    if let Err(e) = f.lock().unwrap().write_all(&record_bytes(at, packet)) {
+        warn!("error writing pcap record: {}", e);
+    }
+}
+
+/// Record header and packet, cut to SNAPLEN.
+fn record_bytes(at: SystemTime, packet: &[u8]) -> Vec<u8> {
+    let ts = at.duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default();
+    let len = packet.len().min(SNAPLEN as usize);
+    let mut rec = Vec::with_capacity(16 + len);
+    rec.extend_from_slice(&(ts.as_secs() as u32).to_le_bytes());
+    rec.extend_from_slice(&ts.subsec_nanos().to_le_bytes());
+    rec.extend_from_slice(&(len as u32).to_le_bytes());
+    rec.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+    rec.extend_from_slice(&packet[..len]);
+    rec
+}
+
+fn fold(mut sum: u32) -> u16 {
+    while (sum >> 16) > 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !sum as u16
+}
+
+fn sum_words(data: &[u8]) -> u32 {
+    data.chunks(2).map(|w| (u32::from(w[0]) << 8) + u32::from(*w.get(1).unwrap_or(&0))).fold(0u32, u32::wrapping_add)
+}
+
+fn ipv4_header(src: Ipv4Addr, dst: Ipv4Addr, ttl: u8, options: &[u8], payload_len: usize) -> Vec<u8> {
+    let opt_len = options.len().div_ceil(4) * 4;
+    let hlen = 20 + opt_len;
+    let mut h = vec![0u8; hlen];
+    h[0] = 0x40 | (hlen / 4) as u8;
+    h[2..4].copy_from_slice(&((hlen + payload_len) as u16).to_be_bytes());
+    h[8] = ttl;
+    h[9] = PROTO_ICMP;
+    h[12..16].copy_from_slice(&src.octets());
+    h[16..20].copy_from_slice(&dst.octets());
+    h[20..20 + options.len()].copy_from_slice(options);
+    let sum = fold(sum_words(&h));
+    h[10..12].copy_from_slice(&sum.to_be_bytes());
+    h
+}
+
+fn ipv6_header(src: Ipv6Addr, dst: Ipv6Addr, hop_limit: u8, payload_len: usize) -> Vec<u8> {
+    let mut h = vec![0u8; 40];
+    h[0] = 0x60;
+    h[4..6].copy_from_slice(&(payload_len as u16).to_be_bytes());
+    h[6] = PROTO_ICMPV6;
+    h[7] = hop_limit;
+    h[8..24].copy_from_slice(&src.octets());
+    h[24..40].copy_from_slice(&dst.octets());
+    h
+}
+
+/// ICMPv6 checksum over the pseudo header; the kernel fills it in on send.
+fn icmpv6_checksum(src: Ipv6Addr, dst: Ipv6Addr, icmp: &mut [u8]) {
+    icmp[2] = 0;
+    icmp[3] = 0;
+    let mut sum = sum_words(&src.octets()).wrapping_add(sum_words(&dst.octets()));
+    sum = sum.wrapping_add(icmp.len() as u32).wrapping_add(u32::from(PROTO_ICMPV6));
+    sum = sum.wrapping_add(sum_words(icmp));
+    icmp[2..4].copy_from_slice(&fold(sum).to_be_bytes());
+}
+
+/// An ICMP message handed to a raw socket, recorded with the IP header the
+/// kernel puts in front of it: `options` are the IPv4 options set on the socket.
+pub fn sent(src: IpAddr, dst: IpAddr, ttl: u8, options: &[u8], icmp: &[u8]) {
+    if !enabled() {
+        return;
+    }
+    let at = SystemTime::now();
+    let mut pkt = match (src, dst) {
+        (IpAddr::V4(s), IpAddr::V4(d)) => ipv4_header(s, d, ttl, options, icmp.len()),
+        (IpAddr::V6(s), IpAddr::V6(d)) => ipv6_header(s, d, ttl, icmp.len()),
+        _ => return,
+    };
+    let start = pkt.len();
+    pkt.extend_from_slice(icmp);
+    if let (IpAddr::V6(s), IpAddr::V6(d)) = (src, dst) {
+        icmpv6_checksum(s, d, &mut pkt[start..]);
+    }
+    record(at, &pkt);
+}
+
+/// Whatever a raw socket returned: a whole IPv4 packet, or for IPv6 just the
+/// ICMPv6 message, which gets a header from `from`, `local` and the hop limit.
+pub fn received(from: IpAddr, local: IpAddr, hop_limit: Option<u8>, data: &[u8]) {
+    if !enabled() {
+        return;
+    }
+    let at = SystemTime::now();
+    match (from, local) {
+        (IpAddr::V4(_), _) => record(at, data),
+        (IpAddr::V6(s), IpAddr::V6(d)) => {
+            let mut pkt = ipv6_header(s, d, hop_limit.unwrap_or(0), data.len());
+            pkt.extend_from_slice(data);
+            record(at, &pkt);
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn u16_be(b: &[u8]) -> u16 {
+        u16::from_be_bytes([b[0], b[1]])
+    }
+
+    fn u32_le(b: &[u8]) -> u32 {
+        u32::from_le_bytes([b[0], b[1], b[2], b[3]])
+    }
+
+    #[test]
+    fn global_header() {
+        let h = file_header();
+        assert_eq!(h.len(), 24);
+        // nanosecond magic, little endian as written
+        assert_eq!(h[..4], [0x4d, 0x3c, 0xb2, 0xa1]);
+        assert_eq!(h[4..8], [2, 0, 4, 0]);
+        assert_eq!(h[8..16], [0; 8]);
+        assert_eq!(u32_le(&h[16..]), 65535);
+        assert_eq!(u32_le(&h[20..]), 101);
+    }
+
+    #[test]
+    fn record_header() {
+        let at = SystemTime::UNIX_EPOCH + Duration::new(1_700_000_000, 123_456_789);
+        let r = record_bytes(at, &[1, 2, 3]);
+        assert_eq!(r.len(), 16 + 3);
+        assert_eq!(u32_le(&r[0..]), 1_700_000_000);
+        assert_eq!(u32_le(&r[4..]), 123_456_789);
+        assert_eq!((u32_le(&r[8..]), u32_le(&r[12..])), (3, 3));
+        assert_eq!(r[16..], [1, 2, 3]);
+        // captured length stops at the snap length, the original is kept
+        let r = record_bytes(at, &vec![0; 70_000]);
+        assert_eq!((u32_le(&r[8..]), u32_le(&r[12..])), (65535, 70_000));
+        assert_eq!(r.len(), 16 + 65535);
+    }
+
+    #[test]
+    fn ipv4_header_fields() {
+        let (src, dst) = (Ipv4Addr::new(192, 0, 2, 1), Ipv4Addr::new(198, 51, 100, 7));
+        let h = ipv4_header(src, dst, 61, &[], 64);
+        assert_eq!(h.len(), 20);
+        assert_eq!(h[0], 0x45);
+        assert_eq!(u16_be(&h[2..]), 84);
+        assert_eq!((h[8], h[9]), (61, 1));
+        assert_eq!((&h[12..16], &h[16..20]), (&src.octets()[..], &dst.octets()[..]));
+        assert_eq!(fold(sum_words(&h)), 0, "checksum {:04x}", u16_be(&h[10..]));
+        // 4500 + 0054 + 3d01 + c000 + 0201 + c633 + 6407 = 0x26e90, folded 0x6e92
+        assert_eq!(u16_be(&h[10..]), 0x916d);
+
+        // 7 bytes of Record Route are padded to 8: IHL 7
+        let opts = [7, 7, 4, 0, 0, 0, 0];
+        let h = ipv4_header(src, dst, 64, &opts, 64);
+        assert_eq!(h.len(), 28);
+        assert_eq!(h[0], 0x47);
+        assert_eq!(u16_be(&h[2..]), 28 + 64);
+        assert_eq!(h[20..27], opts);
+        assert_eq!(h[27], 0);
+        assert_eq!(fold(sum_words(&h)), 0);
+    }
+
+    #[test]
+    fn ipv6_header_fields() {
+        let src: Ipv6Addr = "2001:db8::1".parse().unwrap();
+        let dst: Ipv6Addr = "2001:db8::2".parse().unwrap();
+        let h = ipv6_header(src, dst, 57, 64);
+        assert_eq!(h.len(), 40);
+        assert_eq!(h[..4], [0x60, 0, 0, 0]);
+        assert_eq!(u16_be(&h[4..]), 64);
+        assert_eq!((h[6], h[7]), (58, 57));
+        assert_eq!((&h[8..24], &h[24..40]), (&src.octets()[..], &dst.octets()[..]));
+
+        // echo request, ident 1 seq 2; the checksum covers the pseudo header
+        let mut icmp = [128, 0, 0xff, 0xff, 0, 1, 0, 2, b'h', b'i'];
+        icmpv6_checksum(src, dst, &mut icmp);
+        let pseudo = sum_words(&src.octets()) + sum_words(&dst.octets()) + icmp.len() as u32 + 58;
+        assert_eq!(fold(pseudo + sum_words(&icmp)), 0);
+    }
+
+    #[test]
+    fn capture_file() {
+        let path = std::env::temp_dir().join(format!("sirping-pcap-test-{}.pcap", std::process::id()));
+        open(&path).unwrap();
+        let icmp = [8, 0, 0, 0, 0, 1, 0, 2];
+        sent("192.0.2.1".parse().unwrap(), "192.0.2.9".parse().unwrap(), 64, &[], &icmp);
+        let reply = [129, 0, 0, 0, 0, 1, 0, 2];
+        received("2001:db8::9".parse().unwrap(), "2001:db8::1".parse().unwrap(), Some(60), &reply);
+        // different families: nothing to record
+        sent("192.0.2.1".parse().unwrap(), "2001:db8::9".parse().unwrap(), 64, &[], &icmp);
+        let data = std::fs::read(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+
+        assert_eq!(data[..24], file_header());
+        let first = &data[24..];
+        let len = u32_le(&first[8..]) as usize;
+        assert_eq!(len, 28);
+        assert_eq!(first[16], 0x45);
+        assert_eq!(first[16 + 20..16 + len], icmp);
+        let second = &first[16 + len..];
+        let len = u32_le(&second[8..]) as usize;
+        assert_eq!(len, 48);
+        assert_eq!((second[16], second[16 + 7]), (0x60, 60));
+        assert_eq!(second[16 + 40..16 + len], reply);
+        assert_eq!(second.len(), 16 + len);
+    }
+}
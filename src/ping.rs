@@ -6,8 +6,16 @@ use socket2::{Domain, Protocol, SockAddr, Socket, Type};
 
 use log::trace;
 
+use crate::cli::{HostInfo, IpOptionKind};
+use crate::icmp::{IcmpV4, IcmpV6, QuotedEcho};
+use crate::ipv4::{self, IpOption, IpV4Packet};
+use crate::influx;
+use crate::netns;
+use crate::pcap;
+use crate::util;
+use std::os::fd::AsRawFd;
+
 use std::io::Write;
-use std::mem::MaybeUninit;
 
 const ICMP_HEADER_SIZE: usize = 8;
 const TOKEN_SIZE: usize = 0;
@@ -36,6 +44,22 @@ const ICMPV6_CONST: ProtoTypeConsts = ProtoTypeConsts {
     echo_reply_code: 0,
 };
 
+/// ping1 got an ICMP error, such as unreachable or time exceeded, for its
+/// probe instead of an echo reply.
+#[derive(Debug)]
+pub struct NonReply {
+    pub from: IpAddr,
+    pub type_: u8,
+    pub code: u8,
+}
+
+impl std::fmt::Display for NonReply {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "icmp error type {} code {} from {}", self.type_, self.code, self.from)
+    }
+}
+
+impl std::error::Error for NonReply {}
 
 pub struct Pinger {
     dest: SocketAddr,
@@ -46,6 +70,15 @@ pub struct Pinger {
     send_buffer: [u8; ECHO_REQUEST_BUFFER_SIZE],
     proto: ProtoTypeConsts,
     recv_buffer: [u8;1024],
+    recv_size: usize,
+    /// IPv6 hop limit of the last reply, from IPV6_RECVHOPLIMIT ancillary data.
+    recv_hop_limit: Option<u8>,
+    /// Our address towards dest, for the IP headers of captured packets.
+    pcap_local: IpAddr,
+    /// IPv4 options set on the socket, also for the captured headers.
+    ip_options: Vec<u8>,
+    /// Hop limit IPv6 echo requests go out with.
+    hop_limit_v6: u8,
 }
 
 impl Pinger {
@@ -54,31 +87,109 @@ impl Pinger {
         &self.recv_buffer[..size]
     }
 
-    pub fn new(addr: IpAddr, timeout: Duration, label: String) -> Result<Pinger> {
-        let dest = SocketAddr::new(addr, 0);
+    pub fn new(hostinfo: &HostInfo, timeout: Duration, ip_option: Option<IpOptionKind>) -> Result<Pinger> {
+        let dest = SocketAddr::new(hostinfo.ip, 0);
 
-        let (socket, proto) = if dest.is_ipv4() {
-            (Socket::new(Domain::IPV4, Type::RAW, Some(Protocol::ICMPV4))
-                 .with_context(|| format!("error from Socket::new ipv4: {}:{}", file!(), line!()))?,
-             ICMPV4_CONST)
+        // the socket stays bound to the namespace it was created in
+        let socket = match &hostinfo.netns {
+            None => Self::open_socket(dest)?,
+            Some(netns) => netns::run_in(netns, move || Self::open_socket(dest))?,
+        };
+        if let Some(mark) = hostinfo.mark {
+            socket.set_mark(mark)
+                .with_context(|| format!("error from set_mark({:#x}) for {}: {}:{}", mark, hostinfo, file!(), line!()))?;
+        }
+        let mut ip_options = vec![];
+        if let (Some(kind), true) = (ip_option, dest.is_ipv4()) {
+            ip_options = ipv4::build_option(kind);
+            Self::set_ip_options(&socket, &ip_options)
+                .with_context(|| format!("error setting ip option {:?} for {}: {}:{}", kind, hostinfo, file!(), line!()))?;
+        }
+        let mut hop_limit_v6 = 0;
+        if dest.is_ipv6() {
+            socket.set_recv_hoplimit_v6(true)
+                .with_context(|| format!("error from set_recv_hoplimit_v6: {}:{}", file!(), line!()))?;
+            hop_limit_v6 = socket.unicast_hops_v6().map_or(64, |h| h as u8);
+        }
+        let pcap_local = if !pcap::enabled() {
+            util::unspecified(dest.ip())
         } else {
-            (Socket::new(Domain::IPV6, Type::RAW, Some(Protocol::ICMPV6))
-                 .with_context(|| format!("error from Socket::new ipv6: {}:{}", file!(), line!()))?,
-             ICMPV6_CONST)
+            let (ip, mark) = (dest.ip(), hostinfo.mark);
+            match &hostinfo.netns {
+                None => util::route_source(ip, mark),
+                Some(netns) => netns::run_in(netns, move || Ok(util::route_source(ip, mark)))?,
+            }
         };
+        if influx::enabled() {
+            let (ip, mark) = (dest.ip(), hostinfo.mark);
+            let interface = match &hostinfo.netns {
+                None => util::route_interface(ip, mark),
+                Some(netns) => netns::run_in(netns, move || Ok(util::route_interface(ip, mark)))?,
+            };
+            if let Some(interface) = interface {
+                influx::set_interface(hostinfo.key(), interface);
+            }
+        }
+        let proto = if dest.is_ipv4() { ICMPV4_CONST } else { ICMPV6_CONST };
+        let label = hostinfo.to_string();
 
         Ok(Pinger {
             dest,
             label,
             timeout,
             token: [0u8; TOKEN_SIZE],
-            socket: socket,
+            socket,
             send_buffer: [0u8; ECHO_REQUEST_BUFFER_SIZE],
             proto,
             recv_buffer: [0u8; 1024],
+            recv_size: 0,
+            recv_hop_limit: None,
+            pcap_local,
+            ip_options,
+            hop_limit_v6,
         })
     }
 
+    fn set_ip_options(socket: &Socket, opt: &[u8]) -> std::io::Result<()> {
+        // SAFETY: opt is a valid buffer of opt.len() bytes for the duration of the call
+        let ret = unsafe {
+            libc::setsockopt(socket.as_raw_fd(), libc::IPPROTO_IP, libc::IP_OPTIONS,
+                             opt.as_ptr() as *const libc::c_void, opt.len() as libc::socklen_t)
+        };
+        if ret != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// TTL (IPv4) or hop limit (IPv6) of the last received reply.
+    pub fn reply_ttl(&self) -> Option<u8> {
+        if self.dest.is_ipv4() {
+            (self.recv_size > 8).then(|| self.recv_buffer[8])
+        } else {
+            self.recv_hop_limit
+        }
+    }
+
+    /// Record Route / Timestamp options carried in the last received IPv4 reply.
+    pub fn ip_options(&self) -> Result<Vec<IpOption>> {
+        if !self.dest.is_ipv4() {
+            return Ok(vec![]);
+        }
+        let pkt = IpV4Packet::decode(&self.recv_buffer[..self.recv_size])?;
+        ipv4::parse_options(pkt.options)
+    }
+
+    fn open_socket(dest: SocketAddr) -> Result<Socket> {
+        if dest.is_ipv4() {
+            Socket::new(Domain::IPV4, Type::RAW, Some(Protocol::ICMPV4))
+                .with_context(|| format!("error from Socket::new ipv4: {}:{}", file!(), line!()))
+        } else {
+            Socket::new(Domain::IPV6, Type::RAW, Some(Protocol::ICMPV6))
+                .with_context(|| format!("error from Socket::new ipv6: {}:{}", file!(), line!()))
+        }
+    }
+
     fn encode(&mut self, ident: u16, seq: u16) -> Result<()> {
         self.send_buffer[0] = self.proto.echo_request_type;
         self.send_buffer[1] = self.proto.echo_request_code;
@@ -87,7 +198,7 @@ impl Pinger {
         self.send_buffer[5] = ident as u8;
         self.send_buffer[6] = (seq >> 8) as u8;
         self.send_buffer[7] = seq as u8;
-        if let Err(_) = (&mut self.send_buffer[ICMP_HEADER_SIZE..]).write(&self.token[..]) {
+        if (&mut self.send_buffer[ICMP_HEADER_SIZE..]).write(&self.token[..]).is_err() {
             return Err(anyhow!("invalid packet size"));
         }
 
@@ -109,6 +220,8 @@ impl Pinger {
         trace!("{} sending buff: {:02X?}", self.label, &self.send_buffer);
         self.socket.send_to(&self.send_buffer, &self.dest.into())
             .with_context(|| format!("error from send_to: {}:{}", file!(), line!()))?;
+        let sent_ttl = if self.dest.is_ipv4() { ttl as u8 } else { self.hop_limit_v6 };
+        pcap::sent(self.pcap_local, self.dest.ip(), sent_ttl, &self.ip_options, &self.send_buffer);
 
         let deadline = Instant::now() + self.timeout;
         loop {
@@ -120,16 +233,13 @@ impl Pinger {
             self.socket.set_read_timeout(Some(remaining))
                 .with_context(|| format!("error from set_read_timeout: {}:{}", file!(), line!()))?;
 
-            let mut recv_buf = [MaybeUninit::<u8>::uninit(); 1024];
-            let (ret_size, ret_sockaddr) = self.socket.recv_from(&mut recv_buf)
+            let (ret_size, ret_sockaddr, hop_limit) = util::recv_from_with_hop_limit(&self.socket, &mut self.recv_buffer)
                 .with_context(|| format!("error from recv_from: {}:{}", file!(), line!()))?;
-            // SAFETY: recv_from initialized ret_size bytes
-            unsafe {
-                std::ptr::copy_nonoverlapping(
-                    recv_buf.as_ptr() as *const u8,
-                    self.recv_buffer.as_mut_ptr(),
-                    ret_size,
-                );
+            self.recv_size = ret_size;
+            self.recv_hop_limit = hop_limit;
+            // every packet the socket sees, including the ones discarded below
+            if let Some(from) = ret_sockaddr.as_socket() {
+                pcap::received(from.ip(), self.pcap_local, hop_limit, &self.recv_buffer[..ret_size]);
             }
 
             // Decode and check if this reply matches our ident — if not, discard and keep waiting
@@ -143,6 +253,11 @@ impl Pinger {
                     continue;
                 }
                 Err(_) => {
+                    if let (Some(q), Some(from)) = (self.quoted_echo(), ret_sockaddr.as_socket()) {
+                        if q.dst == self.dest.ip() && q.ident == ident && q.seq_cnt == seq {
+                            return Err(NonReply { from: from.ip(), type_: q.type_, code: q.code }.into());
+                        }
+                    }
                     trace!("{} discarding non-echo-reply packet", self.label);
                     continue;
                 }
@@ -150,6 +265,17 @@ impl Pinger {
         }
     }
 
+    /// The last packet received as an ICMP error about an echo request.
+    fn quoted_echo(&self) -> Option<QuotedEcho> {
+        let pkt = &self.recv_buffer[..self.recv_size];
+        if self.dest.is_ipv4() {
+            let header_size = 4 * usize::from(pkt.first()? & 0x0f);
+            QuotedEcho::decode::<IcmpV4>(pkt.get(header_size..)?)
+        } else {
+            QuotedEcho::decode::<IcmpV6>(pkt)
+        }
+    }
+
     pub fn decode(&mut self) -> Result<(u8,u8,u16,u16)> {
         let mut header_size = 0usize;
         if self.dest.is_ipv4() {
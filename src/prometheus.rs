@@ -0,0 +1,215 @@
+#![allow(dead_code)]
+use std::fmt::Write as FmtWrite;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::Path;
+use std::time::Duration;
+use anyhow::{Context, Result};
+use log::{debug, info, warn};
+use crate::emodel::VoiceQuality;
+use crate::health::HealthState;
+use crate::metrics::HostMetrics;
+use crate::stats::Tracks;
+
+const STATES: &[HealthState] = &[HealthState::Up, HealthState::Degraded, HealthState::Down, HealthState::Flapping];
+
+fn escape_label(v: &str) -> String {
+    v.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+/// `host="..",ip=".."` plus netns / mark when the target has them.
+fn labels(m: &HostMetrics) -> String {
+    let mut l = format!("host=\"{}\",ip=\"{}\"", escape_label(&m.name()), m.host.ip);
+    if let Some(netns) = &m.host.netns {
+        let _ = write!(l, ",netns=\"{}\"", escape_label(netns));
+    }
+    if let Some(mark) = m.host.mark {
+        let _ = write!(l, ",mark=\"{:#x}\"", mark);
+    }
+    l
+}
+
+fn family(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+/// Text exposition format (version 0.0.4) for all hosts.
+pub fn render(hosts: &[HostMetrics]) -> String {
+    let mut out = String::new();
+    let per_host = |out: &mut String, name: &str, kind: &str, help: &str, value: &dyn Fn(&HostMetrics) -> String| {
+        family(out, name, kind, help);
+        for m in hosts {
+            let _ = writeln!(out, "{}{{{}}} {}", name, labels(m), value(m));
+        }
+    };
+    per_host(&mut out, "sirping_replies_total", "counter", "Echo replies received.",
+             &|m| m.totals.reply.to_string());
+    per_host(&mut out, "sirping_timeouts_total", "counter", "Probes that got no reply in time.",
+             &|m| m.totals.timeout.to_string());
+    per_host(&mut out, "sirping_nonreplies_total", "counter", "ICMP errors such as unreachable or time exceeded about the probes.",
+             &|m| m.non_replies.to_string());
+    per_host(&mut out, "sirping_up", "gauge", "1 while the host is reachable, 0 once it is considered down.",
+             &|m| (m.reachable as u8).to_string());
+    per_host(&mut out, "sirping_outages_total", "counter", "Completed outages.",
+             &|m| m.outages.to_string());
+    per_host(&mut out, "sirping_downtime_seconds_total", "counter", "Time spent in completed outages.",
+             &|m| format!("{:.3}", m.downtime.as_secs_f64()));
+
+    // the E-model needs a reply, hosts without one are left out
+    let voice = |out: &mut String, name: &str, help: &str, value: &dyn Fn(&VoiceQuality) -> f64| {
+        family(out, name, "gauge", help);
+        for m in hosts {
+            if let Some(q) = &m.voice {
+                let _ = writeln!(out, "{}{{{}}} {:.2}", name, labels(m), value(q));
+            }
+        }
+    };
+    voice(&mut out, "sirping_r_factor", "E-model transmission rating factor R over the report period.", &|q| q.r);
+    voice(&mut out, "sirping_mos", "Mean opinion score estimated from R.", &|q| q.mos);
+
+    family(&mut out, "sirping_state", "gauge", "Current health state, 1 for the active one.");
+    for m in hosts {
+        for s in STATES {
+            let _ = writeln!(out, "sirping_state{{{},state=\"{}\"}} {}", labels(m), s, (m.state == *s) as u8);
+        }
+    }
+
+    family(&mut out, "sirping_rtt_seconds", "histogram", "Round trip time of echo replies.");
+    for m in hosts {
+        let l = labels(m);
+        for (le, count) in m.rtt.cumulative() {
+            let le = if le.is_infinite() { "+Inf".to_string() } else { le.to_string() };
+            let _ = writeln!(out, "sirping_rtt_seconds_bucket{{{},le=\"{}\"}} {}", l, le, count);
+        }
+        let _ = writeln!(out, "sirping_rtt_seconds_sum{{{}}} {}", l, m.totals.sum_us as f64 / 1e6);
+        let _ = writeln!(out, "sirping_rtt_seconds_count{{{}}} {}", l, m.totals.reply);
+    }
+    out
+}
+
+fn handle(mut stream: TcpStream, tracker: &Tracks) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request = String::new();
+    reader.read_line(&mut request)?;
+    // drain headers up to the blank line
+    let mut line = String::new();
+    while reader.read_line(&mut line)? > 2 {
+        line.clear();
+    }
+    debug!("metrics request: {}", request.trim_end());
+    let mut parts = request.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", render(&tracker.metrics())),
+        (Some("GET"), _) => ("404 Not Found", String::from("try /metrics\n")),
+        _ => ("405 Method Not Allowed", String::new()),
+    };
+    write!(stream, "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+           status, body.len(), body)?;
+    stream.flush()
+}
+
+/// Serves /metrics on `addr` from a background thread, one request at a time.
+pub fn serve(addr: SocketAddr, tracker: Tracks) -> Result<()> {
+    let listener = TcpListener::bind(addr).with_context(|| format!("binding metrics listener to {}", addr))?;
+    info!("serving prometheus metrics on http://{}/metrics", listener.local_addr()?);
+    std::thread::Builder::new()
+        .name(String::from("metrics"))
+        .spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(s) => {
+                        if let Err(e) = handle(s, &tracker) {
+                            debug!("metrics connection error: {}", e);
+                        }
+                    }
+                    Err(e) => warn!("metrics accept error: {}", e),
+                }
+            }
+        })?;
+    Ok(())
+}
+
+/// Rewrites `path` for node_exporter's textfile collector: the metrics go to a
+/// temp file next to it which is then renamed over it, so readers never see a
+/// partial file.
+pub fn write_textfile(path: &Path, hosts: &[HostMetrics]) -> Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let body = render(hosts);
+    let mut f = std::fs::File::create(&tmp).with_context(|| format!("creating {:?}", tmp))?;
+    f.write_all(body.as_bytes()).and_then(|_| f.sync_all()).with_context(|| format!("writing {:?}", tmp))?;
+    std::fs::rename(&tmp, path).with_context(|| format!("renaming {:?} to {:?}", tmp, path))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::HostInfo;
+    use crate::metrics::tests::host_metrics;
+
+    fn lines_of<'a>(out: &'a str, prefix: &str) -> Vec<&'a str> {
+        out.lines().filter(|l| l.starts_with(prefix)).collect()
+    }
+
+    #[test]
+    fn exposition() {
+        let mut h = HostInfo::new(Some("we\"b\\1".to_string()), "192.0.2.1".parse().unwrap());
+        h.netns = Some("blue".to_string());
+        h.mark = Some(0x10);
+        let mut down = host_metrics(HostInfo::new(None, "2001:db8::1".parse().unwrap()), 0, 4);
+        down.state = HealthState::Down;
+        down.reachable = false;
+        down.non_replies = 3;
+        down.voice = None;
+        let out = render(&[host_metrics(h, 4, 1), down]);
+
+        let l = r#"host="we\"b\\1",ip="192.0.2.1",netns="blue",mark="0x10""#;
+        assert!(out.contains("# HELP sirping_replies_total Echo replies received.\n# TYPE sirping_replies_total counter\n"));
+        assert_eq!(lines_of(&out, "sirping_replies_total{"), [
+            format!("sirping_replies_total{{{}}} 4", l),
+            r#"sirping_replies_total{host="2001:db8::1",ip="2001:db8::1"} 0"#.to_string(),
+        ]);
+        assert_eq!(lines_of(&out, "sirping_nonreplies_total{")[1], r#"sirping_nonreplies_total{host="2001:db8::1",ip="2001:db8::1"} 3"#);
+        assert_eq!(lines_of(&out, "sirping_up{")[1], r#"sirping_up{host="2001:db8::1",ip="2001:db8::1"} 0"#);
+        assert!(out.contains(&format!("sirping_downtime_seconds_total{{{}}} 1.500\n", l)));
+        // no R / MOS for the host without replies
+        assert!(out.contains("# TYPE sirping_r_factor gauge\n"));
+        assert_eq!(lines_of(&out, "sirping_r_factor{"), [format!("sirping_r_factor{{{}}} 93.00", l)]);
+        assert_eq!(lines_of(&out, "sirping_mos{"), [format!("sirping_mos{{{}}} 4.40", l)]);
+
+        assert_eq!(lines_of(&out, r#"sirping_state{host="2001:db8::1""#), [
+            r#"sirping_state{host="2001:db8::1",ip="2001:db8::1",state="up"} 0"#,
+            r#"sirping_state{host="2001:db8::1",ip="2001:db8::1",state="degraded"} 0"#,
+            r#"sirping_state{host="2001:db8::1",ip="2001:db8::1",state="down"} 1"#,
+            r#"sirping_state{host="2001:db8::1",ip="2001:db8::1",state="flapping"} 0"#,
+        ]);
+
+        assert!(out.contains("# TYPE sirping_rtt_seconds histogram\n"));
+        let buckets = lines_of(&out, &format!("sirping_rtt_seconds_bucket{{{}", l));
+        assert_eq!(buckets.len(), crate::metrics::RTT_BUCKETS_S.len() + 1);
+        // the 2ms replies land in the 0.0025 bucket and stay counted above it
+        assert!(buckets.contains(&format!("sirping_rtt_seconds_bucket{{{},le=\"0.001\"}} 0", l).as_str()));
+        assert!(buckets.contains(&format!("sirping_rtt_seconds_bucket{{{},le=\"0.0025\"}} 4", l).as_str()));
+        assert_eq!(*buckets.last().unwrap(), format!("sirping_rtt_seconds_bucket{{{},le=\"+Inf\"}} 4", l));
+        assert!(out.contains(&format!("sirping_rtt_seconds_sum{{{}}} 0.008\n", l)));
+        assert!(out.contains(&format!("sirping_rtt_seconds_count{{{}}} 4\n", l)));
+    }
+
+    #[test]
+    fn textfile_is_replaced_whole() {
+        let path = std::env::temp_dir().join(format!("sirping-prom-test-{}.prom", std::process::id()));
+        std::fs::write(&path, "stale\n").unwrap();
+        let hosts = [host_metrics(HostInfo::new(None, "192.0.2.1".parse().unwrap()), 3, 1)];
+        write_textfile(&path, &hosts).unwrap();
+        let content = std::fs::read_to_string(&path).unwrap();
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        let tmp_left = Path::new(&tmp).exists();
+        std::fs::remove_file(&path).unwrap();
+        assert!(!tmp_left);
+        assert_eq!(content, render(&hosts));
+    }
+}
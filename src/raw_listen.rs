@@ -13,6 +13,27 @@ mod icmp;
 mod util;
 mod stop;
 mod stats;
+mod histogram;
+mod emodel;
+mod window;
+mod baseline;
+mod health;
+mod sla;
+mod losspattern;
+mod correlate;
+mod diagnose;
+mod json;
+mod events;
+mod csv;
+mod metrics;
+mod prometheus;
+mod statsd;
+mod influx;
+mod http;
+mod otlp;
+mod mqtt;
+mod logging;
+mod pcap;
 
 use icmp::*;
 use std::net::{SocketAddr, SocketAddrV4, Shutdown, IpAddr};
@@ -41,8 +62,21 @@ fn main() {
 
 
 fn run() -> Result<(), anyhow::Error> {
-    let cfg: Config = Config::parse();
-    init_log(cfg.log_level);
+    let mut cfg: Config = Config::parse();
+    init_log(cfg.log_level, &cfg.log_sink, cfg.log_format)?;
+    cfg.add_auto_gateway()?;
+    if let Some(path) = &cfg.events_json {
+        events::open(path)?;
+    }
+    if let Some(path) = &cfg.pcap {
+        pcap::open(path)?;
+    }
+    if let Some(path) = &cfg.csv_probes {
+        csv::open_probes(path, cfg.csv_delimiter)?;
+    }
+    if let Some(path) = &cfg.csv_stats {
+        csv::open_stats(path, cfg.csv_delimiter)?;
+    }
 
     error!("starting...");
 
@@ -56,7 +90,14 @@ fn run() -> Result<(), anyhow::Error> {
             .expect("failed to install SIGQUIT handler");
     }
 
+    if let Some(h) = cfg.ips.iter().find(|h| h.netns.is_some()) {
+        return Err(anyhow!("rawls sends from a single socket per family and cannot probe {} in a network namespace", h));
+    }
+    if cfg.ip_option.is_some() {
+        return Err(anyhow!("rawls does not set IP options on its probes, --ip-option needs sirpingsalot"));
+    }
     let mut tracker = Tracks::new(&cfg)?;
+    let exporters = metrics::setup(&cfg, &tracker)?;
 
     let recv4 = {
         let mut tracker = tracker.clone();
@@ -88,10 +129,10 @@ fn run() -> Result<(), anyhow::Error> {
 
     if cfg.stat_interval.as_millis() > 0 {
         debug!("starting stats thread");
-        let (mut stop, stats_interval) = (stop.clone(), cfg.stat_interval.clone());
+        let (mut stop, stats_interval) = (stop.clone(), cfg.stat_interval);
         let tracker_h = std::thread::Builder::new()
             .name(String::from("stats"))
-            .spawn(move || stats_thread(tracker, stop, stats_interval, cfg.reset_stats, &PRINT_STATS_NOW))?;
+            .spawn(move || stats_thread(tracker, stop, stats_interval, cfg.reset_stats, &PRINT_STATS_NOW, exporters))?;
     } else {
         info!("no stats tracking started");
         while !stop.sleep(Duration::from_secs(60)) {}
@@ -117,37 +158,64 @@ fn _send_icmp(cfg: Config, mut tracker: Tracks, mut stop: Stop) -> Result<(), an
 
     let soc6 = Socket::new(Domain::IPV6, Type::RAW, Some(Protocol::ICMPV6))
         .with_context(|| format!("error from Socket::new ipv6: {}:{}", file!(), line!()))?;
+    let hop_limit_v6 = soc6.unicast_hops_v6().map_or(64, |h| h as u8);
 
     let mut buffer = [0u8; 32];
     let mut buf = [0u8; 32];
 
     let mut seq = 11000u16;
-    let ident = 22000u16;
+    let marked = cfg.ips.iter().any(|h| h.mark.is_some());
 
     // pre compute to save time in actual loop?
+    // idents come from Tracks so replies can be matched back to a target and flow
     let mut v = vec![];
-    for (n, addr) in cfg.ips.iter().enumerate() {
-        let n = n as u16;
-        v.push(UpdateSendIteration {
-            ident: ident + n,
-            ip: addr.ip,
-            sa: SockAddr::from(SocketAddr::new(addr.ip, 0)),
-            now: Instant::now(),
-        });
+    for addr in cfg.ips.iter() {
+        for flow in 0..cfg.flows {
+            v.push(UpdateSendIteration {
+                ident: tracker.ident_for(&addr.key(), flow),
+                ip: addr.ip,
+                key: addr.key(),
+                flow,
+                mark: addr.mark,
+                sa: SockAddr::from(SocketAddr::new(addr.ip, 0)),
+                now: Instant::now(),
+            });
+        }
+    }
+
+    // our address towards each target, for the IP headers of captured packets
+    let locals: Vec<IpAddr> = v.iter()
+        .map(|i| if pcap::enabled() { util::route_source(i.ip, i.mark) } else { util::unspecified(i.ip) })
+        .collect();
+
+    if influx::enabled() {
+        for addr in cfg.ips.iter() {
+            if let Some(interface) = util::route_interface(addr.ip, addr.mark) {
+                influx::set_interface(addr.key(), interface);
+            }
+        }
     }
 
     loop {
-        for i in v.iter_mut() {
+        for (i, local) in v.iter_mut().zip(&locals) {
+            if marked {
+                // SO_MARK is per socket, so switch it for each target; unmarked targets go out with 0
+                let soc = if i.ip.is_ipv4() { &soc4 } else { &soc6 };
+                soc.set_mark(i.mark.unwrap_or(0))
+                    .with_context(|| format!("error in set_mark: {}:{}", file!(), line!()))?;
+            }
             if i.ip.is_ipv4() {
-                let _ = encode(&ICMPV4_CONST, &mut buf, i.ident, seq as u16);
+                let _ = encode(&ICMPV4_CONST, &mut buf, i.ident, seq);
                 trace!("sending... {:?} seq: {}", &i.sa, seq);
                 soc4.send_to(&buf, &i.sa)
                     .with_context(|| format!("error in send_to4: {}:{}", file!(), line!()))?;
+                pcap::sent(*local, i.ip, 255, &[], &buf);
             } else {
-                let _ = encode(&ICMPV6_CONST, &mut buf, i.ident, seq as u16);
+                let _ = encode(&ICMPV6_CONST, &mut buf, i.ident, seq);
                 trace!("sending... {:?} seq: {}", &i.sa, seq);
                 soc6.send_to(&buf, &i.sa)
                     .with_context(|| format!("error in send_to6: {}:{}", file!(), line!()))?;
+                pcap::sent(*local, i.ip, hop_limit_v6, &[], &buf);
             }
             i.now = Instant::now();
         }
@@ -177,18 +245,22 @@ fn _listen_icmp(proto: &ProtoTypeConsts, mut tracking: Tracks) -> Result<(), any
         let _ = soc.set_ttl(255);
         soc
     } else {
-        Socket::new(Domain::IPV6, Type::RAW, Some(Protocol::ICMPV6))
-            .with_context(|| format!("error from Socket::new ipv4: {}:{}", file!(), line!()))?
+        let soc = Socket::new(Domain::IPV6, Type::RAW, Some(Protocol::ICMPV6))
+            .with_context(|| format!("error from Socket::new ipv4: {}:{}", file!(), line!()))?;
+        soc.set_recv_hoplimit_v6(true)
+            .with_context(|| format!("error from set_recv_hoplimit_v6: {}:{}", file!(), line!()))?;
+        soc
     };
     soc.set_read_timeout(Some(Duration::from_secs(60)))?;
 
     let mut buffer = [0u8; 1024];
-    let mut recv_buf = [MaybeUninit::<u8>::uninit(); 1024];
+    // our address per IPv6 peer, for the headers of captured replies
+    let mut locals: HashMap<IpAddr, IpAddr> = HashMap::new();
 
     loop {
         trace!("waiting...");
 
-        let res = soc.recv_from(&mut recv_buf);
+        let res = recv_from_with_hop_limit(&soc, &mut buffer);
         match res {
             Err(e) => {
                 match e.kind() {
@@ -196,17 +268,18 @@ fn _listen_icmp(proto: &ProtoTypeConsts, mut tracking: Tracks) -> Result<(), any
                     _ => panic!("thread death - error: {:#?}", e),
                 }
             }
-            Ok((size, ret_addr)) => {
-                // SAFETY: recv_from initialized size bytes
-                unsafe {
-                    std::ptr::copy_nonoverlapping(
-                        recv_buf.as_ptr() as *const u8,
-                        buffer.as_mut_ptr(),
-                        size,
-                    );
-                }
-                let r = IcmpEchoReply::decode(&buffer[0..], &proto).unwrap();
+            Ok((size, ret_addr, hop_limit)) => {
                 let ip = ret_addr.as_socket().unwrap().ip();
+                if pcap::enabled() {
+                    // captured before decoding so foreign and undecodable packets are in it too
+                    let local = if ip.is_ipv6() {
+                        *locals.entry(ip).or_insert_with(|| util::route_source(ip, None))
+                    } else {
+                        util::unspecified(ip)
+                    };
+                    pcap::received(ip, local, hop_limit, &buffer[..size]);
+                }
+                let r = IcmpEchoReply::decode(&buffer[0..], proto).unwrap();
                 let ver = if ip.is_ipv4() {
                     "V4"
                 } else if ip.is_ipv6(){
@@ -214,13 +287,24 @@ fn _listen_icmp(proto: &ProtoTypeConsts, mut tracking: Tracks) -> Result<(), any
                 } else {
                     "V?"
                 };
-                if let Some(r) = r {
+                if let Some(mut r) = r {
+                    if r.ttl.is_none() {
+                        r.ttl = hop_limit;
+                    }
                     let now = Instant::now();
-                    if !tracking.update_for_recv(ip, now, r.ident, r.seq) {
+                    let (key, flow) = tracking.key_for_reply(ip, r.ident).unwrap_or_else(|| (HostKey::from(ip), 0));
+                    if !tracking.update_for_recv(&key, flow, now, r.ident, r.seq, r.ttl) {
                         trace!("{} PACKET from unexpected ip: {} size: {}  raw: {:02X?}\n reply: {:?}", ver, ip, size, &buffer[..size], &r);
                     } else {
                         trace!("{} PACKET size: {}  raw: {:02X?}\n reply: {:?}", ver, size, &buffer[..size], &r);
                     }
+                } else if let Some(q) = quoted_echo(&buffer[..size], proto) {
+                    // an error about one of our probes, e.g. from a router on the way
+                    let (key, flow) = tracking.key_for_reply(q.dst, q.ident).unwrap_or_else(|| (HostKey::from(q.dst), 0));
+                    debug!("icmp error type {} code {} from {} for {} seq {}", q.type_, q.code, ip, q.dst, q.seq_cnt);
+                    if !tracking.update_for_non_reply(&key, flow, q.seq_cnt) {
+                        trace!("{} icmp error about unexpected ip: {}", ver, q.dst);
+                    }
                 } else {
                     trace!("bad reply code from {}", ip);
                     trace!("{} PACKET size: {}  raw: {:02X?}\n reply: NONE", ver, size, &buffer[..size]);
@@ -233,6 +317,17 @@ fn _listen_icmp(proto: &ProtoTypeConsts, mut tracking: Tracks) -> Result<(), any
 }
 
 
+/// An ICMP error in `buf` quoting an echo request, like `IcmpEchoReply::decode`
+/// with the IPv4 header still in front.
+fn quoted_echo(buf: &[u8], proto: &ProtoTypeConsts) -> Option<QuotedEcho> {
+    if proto.is_v4 {
+        let header_size = 4 * usize::from(buf.first()? & 0x0f);
+        QuotedEcho::decode::<IcmpV4>(buf.get(header_size..)?)
+    } else {
+        QuotedEcho::decode::<IcmpV6>(buf)
+    }
+}
+
 fn encode(proto: &ProtoTypeConsts, buff: &mut [u8], ident: u16, seq: u16) -> Result<(), anyhow::Error> {
     buff[0] = proto.echo_request_type;
     buff[1] = proto.echo_request_code;
@@ -304,16 +399,19 @@ struct IcmpEchoReply {
     pub code: u8,
     pub ident: u16,
     pub seq: u16,
+    /// IPv4 TTL from the header, or the IPv6 hop limit filled in by the caller.
+    pub ttl: Option<u8>,
 }
 
 impl IcmpEchoReply {
     pub fn decode(buf: &[u8], proto: &ProtoTypeConsts) -> Result<Option<Self>, anyhow::Error> {
         let mut header_size = 0usize;
+        let mut ttl = None;
         if proto.is_v4 {
             let byte0 = buf[0];
             let version = (byte0 & 0xf0) >> 4;
             header_size = 4 * ((byte0 & 0x0f) as usize);
-            let ttl = buf[8];
+            ttl = Some(buf[8]);
         }
 
         let icmp_data = &buf[header_size..];
@@ -331,6 +429,7 @@ impl IcmpEchoReply {
             code,
             ident,
             seq,
+            ttl,
         }))
     }
 }
@@ -0,0 +1,118 @@
+#![allow(dead_code)]
+use std::time::{Duration, SystemTime};
+
+/// Outage totals for one host over a span of time.  An outage belongs to the
+/// span it ended in; if it started before the span, only the part inside it
+/// counts as downtime, while MTTR uses its full duration.
+pub struct Availability {
+    since: SystemTime,
+    outages: u32,
+    downtime: Duration,
+    /// Full duration of the completed outages, for MTTR.
+    repair: Duration,
+}
+
+pub struct SlaSummary {
+    pub period: Duration,
+    pub downtime: Duration,
+    /// Completed outages plus an ongoing one.
+    pub outages: u32,
+    pub availability_pct: f64,
+    /// Mean time to recover, over completed outages.
+    pub mttr: Option<Duration>,
+    /// Mean time between failures: uptime divided by outages.
+    pub mtbf: Option<Duration>,
+}
+
+impl Availability {
+    pub fn new(since: SystemTime) -> Availability {
+        Availability { since, outages: 0, downtime: Duration::ZERO, repair: Duration::ZERO }
+    }
+
+    fn clipped(&self, start: SystemTime, end: SystemTime) -> Duration {
+        end.duration_since(start.max(self.since)).unwrap_or_default()
+    }
+
+    pub fn record_outage(&mut self, start: SystemTime, end: SystemTime) {
+        self.outages += 1;
+        self.downtime += self.clipped(start, end);
+        self.repair += end.duration_since(start).unwrap_or_default();
+    }
+
+    /// Completed outages recorded so far.
+    pub fn outages(&self) -> u32 {
+        self.outages
+    }
+
+    /// Downtime of the completed outages.
+    pub fn downtime(&self) -> Duration {
+        self.downtime
+    }
+
+    /// Summary up to `now`, counting `open_outage` (its start) as down until now.
+    pub fn summary(&self, now: SystemTime, open_outage: Option<SystemTime>) -> SlaSummary {
+        let period = now.duration_since(self.since).unwrap_or_default();
+        let open = open_outage.map(|s| self.clipped(s, now)).unwrap_or_default();
+        let downtime = (self.downtime + open).min(period);
+        let outages = self.outages + open_outage.is_some() as u32;
+        let availability_pct = if period.is_zero() {
+            100.0
+        } else {
+            100.0 * (1.0 - downtime.as_secs_f64() / period.as_secs_f64())
+        };
+        SlaSummary {
+            period,
+            downtime,
+            outages,
+            availability_pct,
+            mttr: (self.outages > 0).then(|| self.repair / self.outages),
+            mtbf: (outages > 0).then(|| (period - downtime) / outages),
+        }
+    }
+}
+
+impl SlaSummary {
+    /// Availability as a count of nines, e.g. 99.9% is 3.0.  None at 100%.
+    pub fn nines(&self) -> Option<f64> {
+        let unavailable = 1.0 - self.availability_pct / 100.0;
+        (unavailable > 0.0).then(|| -unavailable.log10())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(secs: u64) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn outage_spanning_intervals() {
+        // a 60s outage from 90s to 150s, with reports at 100s and 200s
+        let first = Availability::new(at(0));
+        let s = first.summary(at(100), Some(at(90)));
+        assert_eq!((s.downtime, s.outages, s.mttr), (Duration::from_secs(10), 1, None));
+
+        let mut second = Availability::new(at(100));
+        second.record_outage(at(90), at(150));
+        let s = second.summary(at(200), None);
+        assert_eq!(s.downtime, Duration::from_secs(50));
+        assert_eq!(s.availability_pct, 50.0);
+        assert_eq!(s.mttr, Some(Duration::from_secs(60)));
+        assert_eq!(s.mtbf, Some(Duration::from_secs(50)));
+    }
+
+    #[test]
+    fn mttr_averages_completed_outages() {
+        let mut a = Availability::new(at(0));
+        a.record_outage(at(10), at(20));
+        a.record_outage(at(50), at(80));
+        let s = a.summary(at(100), Some(at(95)));
+        assert_eq!(s.downtime, Duration::from_secs(45));
+        assert_eq!(s.outages, 3);
+        assert_eq!(s.mttr, Some(Duration::from_secs(20)));
+        assert_eq!(s.mtbf, Some(Duration::from_secs(55) / 3));
+        assert!((s.nines().unwrap() - -(0.45f64).log10()).abs() < 1e-9);
+    }
+}
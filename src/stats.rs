@@ -2,7 +2,7 @@
 use humantime::format_rfc3339_millis;
 use std::sync::atomic::{AtomicU64, Ordering, AtomicBool};
 use std::time::{Duration, SystemTime, Instant};
-use std::net::IpAddr;
+use std::net::{IpAddr, Ipv4Addr};
 use std::sync::{Arc, Mutex};
 use log::{debug, error, info, trace, warn};
 use anyhow::{Context, anyhow};
@@ -10,9 +10,23 @@ use anyhow::{Context, anyhow};
 
 use crate::util::{sleep_until_next_interval_on, sleep_until_next_interval_or_trigger};
 use std::fmt;
-use crate::cli::{HostInfo, Config};
+use crate::cli::{HostInfo, HostKey, Config};
 use crate::stop::Stop;
-use std::collections::HashMap;
+use crate::histogram::{Histogram, HistogramSnapShot};
+use crate::emodel::{self, VoiceQuality};
+use crate::window::{WindowAgg, Windows, BUCKET_SECS, WINDOWS};
+use crate::baseline::{BaselineEvent, LatencyBaseline};
+use crate::health::{Health, HealthConfig, HealthState};
+use crate::sla::{Availability, SlaSummary};
+use crate::losspattern::LossPattern;
+use crate::correlate::{CommonOutage, Correlator};
+use crate::diagnose::{self, Verdict};
+use crate::events::{self, ProbeEvent};
+use crate::csv;
+use crate::metrics::{HostMetrics, IntervalExporter, RttBuckets};
+use crate::{influx, mqtt, statsd};
+use std::collections::{BTreeMap, HashMap};
+use std::collections::hash_map::Entry;
 use tabular::{Table, Row};
 use socket2::SockAddr;
 
@@ -24,6 +38,20 @@ pub struct Stats {
     time_sum_sq_us: AtomicU64,
     time_min_us: AtomicU64,
     time_max_us: AtomicU64,
+    /// TTL / hop limit of the most recent reply, 0 if none seen.
+    ttl_last: AtomicU64,
+    hops_min: AtomicU64,
+    hops_max: AtomicU64,
+    latency: Histogram,
+    /// RFC 3550 interarrival jitter, scaled by 16 as in the RFC's reference code.
+    jitter_x16: AtomicU64,
+    /// Reply pairs fed to the jitter estimator since start; until the first
+    /// one there is no jitter to report.
+    jitter_pairs: AtomicU64,
+    /// RFC 3393 IPDV between consecutive replies: count, sum and max of |delta|.
+    ipdv_count: AtomicU64,
+    ipdv_sum_us: AtomicU64,
+    ipdv_max_us: AtomicU64,
 }
 
 pub struct StatsSnapShot {
@@ -34,6 +62,86 @@ pub struct StatsSnapShot {
     time_sum_sq_us: u64,
     time_min_us: u64,
     time_max_us: u64,
+    ttl_last: u64,
+    hops_min: u64,
+    hops_max: u64,
+    latency: HistogramSnapShot,
+    jitter_x16: u64,
+    jitter_pairs: u64,
+    ipdv_count: u64,
+    ipdv_sum_us: u64,
+    ipdv_max_us: u64,
+}
+
+impl StatsSnapShot {
+    /// Replies that carried a round trip time.  ICMP errors are only counted:
+    /// their time is to whichever router sent them, not to the host.
+    fn rtt_count(&self) -> u64 {
+        self.reply
+    }
+
+    pub fn avg_ms(&self) -> Option<f64> {
+        let count = self.rtt_count();
+        (count > 0).then(|| (self.time_sum_us as f64 / count as f64) / 1000.0)
+    }
+
+    pub fn min_ms(&self) -> Option<f64> {
+        (self.rtt_count() > 0).then(|| self.time_min_us as f64 / 1000.0)
+    }
+
+    pub fn max_ms(&self) -> Option<f64> {
+        (self.rtt_count() > 0).then(|| self.time_max_us as f64 / 1000.0)
+    }
+
+    pub fn stdev_ms(&self) -> Option<f64> {
+        let count = self.rtt_count();
+        (count >= 2).then(|| {
+            let avg_us = self.time_sum_us as f64 / count as f64;
+            let mean_sq = self.time_sum_sq_us as f64 / count as f64;
+            let var = (mean_sq - avg_us * avg_us).max(0.0);
+            var.sqrt() / 1000.0
+        })
+    }
+
+    /// Running RFC 3550 jitter, once at least one pair of consecutive replies was seen.
+    pub fn jitter_ms(&self) -> Option<f64> {
+        (self.jitter_pairs > 0).then(|| self.jitter_x16 as f64 / 16.0 / 1000.0)
+    }
+
+    pub fn ipdv_avg_ms(&self) -> Option<f64> {
+        (self.ipdv_count > 0).then(|| self.ipdv_sum_us as f64 / self.ipdv_count as f64 / 1000.0)
+    }
+
+    pub fn ipdv_max_ms(&self) -> Option<f64> {
+        (self.ipdv_count > 0).then(|| self.ipdv_max_us as f64 / 1000.0)
+    }
+
+    /// Bucket edges can overshoot the largest sample, so this is capped at the observed max.
+    pub fn percentile_ms(&self, pct: f64) -> Option<f64> {
+        self.latency.percentile(pct).map(|us| us.min(self.time_max_us) as f64 / 1000.0)
+    }
+
+    /// Share of probes that timed out.
+    pub fn loss_pct(&self) -> Option<f64> {
+        let sent = self.rtt_count() + self.timeout;
+        (sent > 0).then(|| self.timeout as f64 * 100.0 / sent as f64)
+    }
+
+    /// E-model voice quality for this interval; needs at least one reply.
+    pub fn voice_quality(&self) -> Option<VoiceQuality> {
+        let avg = self.avg_ms()?;
+        Some(emodel::estimate(avg, self.jitter_ms().unwrap_or(0.0), self.loss_pct().unwrap_or(0.0)))
+    }
+
+    /// TTL / hop limit of the most recent reply.
+    pub fn ttl(&self) -> Option<u8> {
+        (self.ttl_last != 0).then_some(self.ttl_last as u8)
+    }
+
+    /// Min and max inferred hop count over replies in this snapshot.
+    pub fn hops_range(&self) -> Option<(u64, u64)> {
+        (self.hops_min != u64::MAX).then_some((self.hops_min, self.hops_max))
+    }
 }
 
 /// One contiguous run of consecutive timeouts/non-replies for a single host.
@@ -43,6 +151,23 @@ pub struct OutageRange {
     pub count: u32,
 }
 
+/// Infers the hop count of a reply by assuming the sender started from the
+/// nearest common initial TTL (32, 64, 128 or 255) at or above the one seen.
+pub fn hops_from_ttl(ttl: u8) -> u8 {
+    let initial = [32u8, 64, 128, 255].into_iter().find(|&i| i >= ttl).unwrap_or(255);
+    initial - ttl
+}
+
+/// A period during which a host's short-term median RTT sat above its baseline.
+#[derive(Clone)]
+pub struct LatencyEpisode {
+    pub start: SystemTime,
+    pub end: Option<SystemTime>,  // None = still degraded
+    pub baseline_us: u64,
+    /// Largest RTT seen during the episode.
+    pub peak_us: u64,
+}
+
 fn instant_to_system_time(t: Instant) -> SystemTime {
     let now_i = Instant::now();
     let now_s = SystemTime::now();
@@ -63,6 +188,15 @@ impl Stats {
             time_sum_sq_us: AtomicU64::new(0),
             time_min_us: AtomicU64::new(u64::MAX),
             time_max_us: AtomicU64::new(0),
+            ttl_last: AtomicU64::new(0),
+            hops_min: AtomicU64::new(u64::MAX),
+            hops_max: AtomicU64::new(0),
+            latency: Histogram::new(),
+            jitter_x16: AtomicU64::new(0),
+            jitter_pairs: AtomicU64::new(0),
+            ipdv_count: AtomicU64::new(0),
+            ipdv_sum_us: AtomicU64::new(0),
+            ipdv_max_us: AtomicU64::new(0),
         }
     }
 
@@ -75,6 +209,17 @@ impl Stats {
             time_sum_sq_us: self.time_sum_sq_us.swap(0, Ordering::Relaxed),
             time_min_us: self.time_min_us.swap(u64::MAX, Ordering::Relaxed),
             time_max_us: self.time_max_us.swap(0, Ordering::Relaxed),
+            // the last TTL is current state rather than an interval total, so keep it
+            ttl_last: self.ttl_last.load(Ordering::Relaxed),
+            hops_min: self.hops_min.swap(u64::MAX, Ordering::Relaxed),
+            hops_max: self.hops_max.swap(0, Ordering::Relaxed),
+            latency: self.latency.zero_extract(),
+            // jitter is a running estimate, not an interval total
+            jitter_x16: self.jitter_x16.load(Ordering::Relaxed),
+            jitter_pairs: self.jitter_pairs.load(Ordering::Relaxed),
+            ipdv_count: self.ipdv_count.swap(0, Ordering::Relaxed),
+            ipdv_sum_us: self.ipdv_sum_us.swap(0, Ordering::Relaxed),
+            ipdv_max_us: self.ipdv_max_us.swap(0, Ordering::Relaxed),
         }
     }
 
@@ -87,6 +232,15 @@ impl Stats {
             time_sum_sq_us: self.time_sum_sq_us.load(Ordering::Relaxed),
             time_min_us: self.time_min_us.load(Ordering::Relaxed),
             time_max_us: self.time_max_us.load(Ordering::Relaxed),
+            ttl_last: self.ttl_last.load(Ordering::Relaxed),
+            hops_min: self.hops_min.load(Ordering::Relaxed),
+            hops_max: self.hops_max.load(Ordering::Relaxed),
+            latency: self.latency.snapshot(),
+            jitter_x16: self.jitter_x16.load(Ordering::Relaxed),
+            jitter_pairs: self.jitter_pairs.load(Ordering::Relaxed),
+            ipdv_count: self.ipdv_count.load(Ordering::Relaxed),
+            ipdv_sum_us: self.ipdv_sum_us.load(Ordering::Relaxed),
+            ipdv_max_us: self.ipdv_max_us.load(Ordering::Relaxed),
         }
     }
 
@@ -96,14 +250,30 @@ impl Stats {
         self.time_sum_sq_us.fetch_add(micros.saturating_mul(micros), Ordering::Relaxed);
         self.time_min_us.fetch_min(micros, Ordering::Relaxed);
         self.time_max_us.fetch_max(micros, Ordering::Relaxed);
+        self.latency.record(micros);
     }
 
-    pub fn update_micros_non_reply(&self, micros: u64) {
+    pub fn update_non_reply(&self) {
         self.non_reply.fetch_add(1, Ordering::Relaxed);
-        self.time_sum_us.fetch_add(micros, Ordering::Relaxed);
-        self.time_sum_sq_us.fetch_add(micros.saturating_mul(micros), Ordering::Relaxed);
-        self.time_min_us.fetch_min(micros, Ordering::Relaxed);
-        self.time_max_us.fetch_max(micros, Ordering::Relaxed);
+    }
+
+    /// Feeds the RTTs of two consecutive replies into the jitter estimator and IPDV totals.
+    pub fn update_delay_variation(&self, prev_us: u64, cur_us: u64) {
+        let d = prev_us.abs_diff(cur_us);
+        // J += (|D| - J) / 16, in the RFC 3550 fixed point form
+        let _ = self.jitter_x16.fetch_update(Ordering::Relaxed, Ordering::Relaxed,
+            |j| Some((j + d).saturating_sub((j + 8) >> 4)));
+        self.jitter_pairs.fetch_add(1, Ordering::Relaxed);
+        self.ipdv_count.fetch_add(1, Ordering::Relaxed);
+        self.ipdv_sum_us.fetch_add(d, Ordering::Relaxed);
+        self.ipdv_max_us.fetch_max(d, Ordering::Relaxed);
+    }
+
+    pub fn update_ttl(&self, ttl: u8) {
+        let hops = hops_from_ttl(ttl) as u64;
+        self.ttl_last.store(ttl as u64, Ordering::Relaxed);
+        self.hops_min.fetch_min(hops, Ordering::Relaxed);
+        self.hops_max.fetch_max(hops, Ordering::Relaxed);
     }
 
     pub fn update_fail(&self) {
@@ -114,11 +284,18 @@ impl Stats {
 impl Stats {
 }
 
-pub fn stats_thread(mut tracker: Tracks, mut running: Stop, interval: Duration, reset: bool, trigger: &'static AtomicBool) {
+pub fn stats_thread(mut tracker: Tracks, mut running: Stop, interval: Duration, reset: bool, trigger: &'static AtomicBool,
+                    mut exporters: Vec<Box<dyn IntervalExporter>>) {
     loop {
         let (stopped, triggered) = sleep_until_next_interval_or_trigger(&mut running, interval, trigger);
 
         let report = tracker.create_report(reset);
+        if !exporters.is_empty() {
+            let hosts = tracker.metrics();
+            for e in exporters.iter_mut() {
+                e.export(&hosts);
+            }
+        }
         if stopped {
             error!("FINAL/EARLY dump of STATS:\n{}", report);
             std::process::exit(0);
@@ -130,25 +307,402 @@ pub fn stats_thread(mut tracker: Tracks, mut running: Stop, interval: Duration,
     }
 }
 
-struct TrackPerHost {
-    host: HostInfo,
-    last_time: Option<Instant>,
+/// Rounds a host may have waiting on a stalled flow before the oldest is
+/// settled with the outcomes it has.
+const MAX_OPEN_ROUNDS: usize = 4;
+
+/// Outcomes so far of one probe round, in which every flow of a host sends once.
+#[derive(Default)]
+struct Round {
+    /// Flows whose probe was answered or counted as missed.
+    resolved: usize,
+    replied: bool,
+    /// Earliest send time among the round's missed probes.
+    first_missed: Option<SystemTime>,
+    /// Arrival of the round's first reply.
+    first_reply: Option<SystemTime>,
+}
+
+/// Probe state for one flow (ICMP ident) of a host.  Each flow may hash onto a
+/// different ECMP path, so its loss and latency are kept separately.
+struct FlowTrack {
     ident: u16,
+    last_time: Option<Instant>,
     last_seq: Option<u16>,
     mark: bool,
-    stats: Stats,
+    /// Probes sent on this flow; the outstanding one belongs to round `sent - 1`.
+    sent: u64,
     /// SystemTime of the most recent send (used as outage start when a miss is first detected).
     last_send_stime: Option<SystemTime>,
-    /// Start time of the current open outage streak (None if no active streak).
+    stats: Stats,
+    /// Most recent path recorded by the Record Route IP option.
+    route: Option<Vec<Ipv4Addr>>,
+    /// Times the recorded path changed since the last reset.
+    route_changes: u32,
+    /// Most recent (hop, ms since midnight UT) pairs from the Timestamp IP
+    /// option.  Hops may decline to stamp, so these are not a path.
+    stamps: Option<Vec<(Ipv4Addr, u32)>>,
+    /// Hop count inferred from the last reply's TTL.
+    hops: Option<u8>,
+    /// RTT of the previous reply if it directly preceded (no loss in between).
+    last_rtt_us: Option<u64>,
+    /// Error class reported by the prober for the outstanding probe, if it failed.
+    last_error: Option<&'static str>,
+    /// Burst structure of this flow's losses, reset by -R.  Kept per flow so
+    /// bursts on one path are not broken up by replies on another.
+    loss: LossPattern,
+}
+
+/// Per-probe output built while `Tracks` is locked and written once it is
+/// released, so a slow file or socket never holds up the sender and receiver threads.
+#[derive(Default)]
+struct Pending {
+    events: Vec<String>,
+    csv: Vec<String>,
+    statsd: Vec<String>,
+}
+
+impl Pending {
+    fn probe(&mut self, ev: &ProbeEvent) {
+        self.events.extend(events::probe(ev));
+        self.csv.extend(csv::probe(ev));
+        self.statsd.extend(statsd::probe(ev));
+        influx::probe(ev);
+    }
+
+    fn write(self) {
+        events::write(&self.events);
+        csv::write_probes(&self.csv);
+        statsd::send_probes(&self.statsd);
+    }
+}
+
+struct TrackPerHost {
+    host: HostInfo,
+    /// Position of the host on the command line, used to keep report order stable.
+    order: usize,
+    /// Totals across all flows.
+    stats: Stats,
+    flows: Vec<FlowTrack>,
+    /// Rounds with outcomes still to come, by round number.
+    rounds: BTreeMap<u64, Round>,
+    /// Rounds before this one are settled; late outcomes for them are ignored.
+    next_round: u64,
+    /// Start time of the current miss streak (None if no active streak).  It is
+    /// an open outage once `health` says the host is unreachable.
     outage_streak_start: Option<SystemTime>,
-    /// Number of consecutive missed pings in the current open streak.
+    /// Number of rounds in the current streak in which every flow missed.
     outage_streak_count: u32,
+    /// First reply of the answered rounds in a row while the host is down;
+    /// the outage ends there once `health` has seen enough of them.
+    recovery_start: Option<SystemTime>,
     /// Completed (closed) outage ranges.
     completed_outages: Vec<OutageRange>,
+    /// RTT of the host's previous reply on any flow, for host level delay variation.
+    last_rtt_us: Option<u64>,
+    /// Sliding window history, unaffected by -R.
+    windows: Windows,
+    baseline: LatencyBaseline,
+    health: Health,
+    /// Outage totals since start, and since the last report.
+    sla_run: Availability,
+    sla_window: Availability,
+    /// Current latency degradation, if any.
+    open_episode: Option<LatencyEpisode>,
+    completed_episodes: Vec<LatencyEpisode>,
+    /// RTT distribution since start for the metric exporters.
+    rtt_buckets: RttBuckets,
+    /// ICMP errors about our probes since start, for the metric exporters.
+    non_replies: u64,
+    /// Output of the probe being recorded, taken by `Tracks` before unlocking.
+    pending: Pending,
+}
+
+impl TrackPerHost {
+    /// Records a new probe on `flow`, first counting the previous one as missed
+    /// if no reply came back for it.  Returns whether the previous probe was answered.
+    fn record_send(&mut self, flow: usize, now: Instant, now_s: SystemTime, ident: u16, seq: u16) -> bool {
+        let multi_flow = self.flows.len() > 1;
+        let f = &mut self.flows[flow];
+        let last_mark = f.mark;
+        let mut missed = None;
+        if let (false, Some(last_seq)) = (f.mark, f.last_seq) {
+            if multi_flow {
+                info!(host:% = self.host, flow, seq = last_seq, error = "timeout"; "timeout for {} flow {} missed seq {}", self.host, flow, last_seq);
+            } else {
+                info!(host:% = self.host, flow, seq = last_seq, error = "timeout"; "timeout for {} missed seq {}", self.host, last_seq);
+            }
+            let ev = ProbeEvent {
+                host: &self.host,
+                flow,
+                sent: f.last_send_stime.unwrap_or(now_s),
+                seq: last_seq,
+                ident: f.ident,
+                rtt_us: None,
+                ttl: None,
+                error: f.last_error,
+            };
+            self.pending.probe(&ev);
+            f.stats.update_fail();
+            self.stats.update_fail();
+            self.windows.record_timeout(now_s);
+            // a loss breaks the run of consecutive replies delay variation is measured over
+            f.last_rtt_us = None;
+            self.last_rtt_us = None;
+            f.loss.record(false);
+            missed = Some((f.sent - 1, f.last_send_stime));
+        }
+        f.ident = ident;
+        f.last_error = None;
+        f.last_send_stime = Some(now_s);
+        f.last_seq = Some(seq);
+        f.last_time = Some(now);
+        f.mark = false;
+        f.sent += 1;
+        if let Some((round, sent)) = missed {
+            self.round_outcome(round, false, sent, now_s);
+        }
+        last_mark
+    }
+
+    fn record_recv(&mut self, flow: usize, now: Instant, ident: u16, seq: u16, ttl: Option<u8>) {
+        let f = &mut self.flows[flow];
+        if f.ident != ident {
+            info!("ident difference for {} expected: {} got {}", self.host,
+                  f.ident, ident);
+        }
+        if f.last_seq.expect("should get a seqence") != seq {
+            info!("seq out of order for {}: sent: {} but just got {}", self.host,
+                  f.last_seq.unwrap(), seq);
+        }
+        let dur = now - f.last_time.expect("was supposed to have sometime");
+        let rtt_us = dur.as_micros() as u64;
+        f.stats.update_micros_working(rtt_us);
+        // a duplicate reply does not answer the round a second time
+        let round = (!f.mark).then(|| f.sent - 1);
+        if round.is_some() {
+            f.loss.record(true);
+        }
+        f.mark = true;
+        self.stats.update_micros_working(rtt_us);
+        self.windows.record_reply(SystemTime::now(), rtt_us);
+        self.rtt_buckets.record(rtt_us);
+        if let Some(prev) = f.last_rtt_us.replace(rtt_us) {
+            f.stats.update_delay_variation(prev, rtt_us);
+        }
+        if let Some(prev) = self.last_rtt_us.replace(rtt_us) {
+            self.stats.update_delay_variation(prev, rtt_us);
+        }
+        debug!("success for {} time: {:?} ttl: {:?}", self.host, dur, ttl);
+        let ev = ProbeEvent {
+            host: &self.host,
+            flow,
+            sent: f.last_send_stime.unwrap_or_else(SystemTime::now),
+            seq,
+            ident,
+            rtt_us: Some(rtt_us),
+            ttl,
+            error: None,
+        };
+        self.pending.probe(&ev);
+        if let Some(ttl) = ttl {
+            let hops = hops_from_ttl(ttl);
+            if let Some(prev) = f.hops.filter(|&h| h != hops) {
+                warn!(host:% = self.host, flow, from = prev, to = hops, ttl;
+                      "path length changed for {}: {} -> {} hops (reply ttl {})", self.host, prev, hops, ttl);
+            }
+            f.hops = Some(hops);
+            f.stats.update_ttl(ttl);
+            self.stats.update_ttl(ttl);
+        }
+        if let Some(round) = round {
+            self.round_outcome(round, true, None, instant_to_system_time(now));
+        }
+        self.update_baseline(rtt_us);
+    }
+
+    /// Counts an ICMP error (unreachable, time exceeded, ...) about the probe
+    /// `seq` on `flow`.  The probe still counts as missed once the next one is sent.
+    fn record_non_reply(&mut self, flow: usize, seq: u16) {
+        let f = &mut self.flows[flow];
+        if f.last_seq != Some(seq) {
+            debug!("icmp error for {} about an earlier seq {}", self.host, seq);
+            return;
+        }
+        f.stats.update_non_reply();
+        self.stats.update_non_reply();
+        self.non_replies += 1;
+    }
+
+    /// Counts the outcome of one flow's probe in `round`, then settles the rounds
+    /// that are decided, oldest first.  The host answered a round if any flow got
+    /// a reply, and missed it only once every flow has missed; only a missed
+    /// round opens or extends the miss streak.
+    fn round_outcome(&mut self, round: u64, ok: bool, sent: Option<SystemTime>, now_s: SystemTime) {
+        if round < self.next_round {
+            return;
+        }
+        let r = self.rounds.entry(round).or_default();
+        r.resolved += 1;
+        r.replied |= ok;
+        if ok {
+            r.first_reply = r.first_reply.or(Some(now_s));
+        } else {
+            r.first_missed = match (r.first_missed, sent) {
+                (Some(a), Some(b)) => Some(a.min(b)),
+                (a, b) => a.or(b),
+            };
+        }
+        let flows = self.flows.len();
+        loop {
+            let open = self.rounds.len();
+            let Some(e) = self.rounds.first_entry() else {
+                break;
+            };
+            let r = e.get();
+            if !r.replied && r.resolved < flows && open <= MAX_OPEN_ROUNDS {
+                break;
+            }
+            self.next_round = e.key() + 1;
+            let r = e.remove();
+            if !r.replied {
+                // Open or extend the miss streak; it only becomes an outage if the host goes down.
+                if self.outage_streak_start.is_none() {
+                    self.outage_streak_start = r.first_missed.or(Some(now_s));
+                }
+                self.outage_streak_count += 1;
+                self.recovery_start = None;
+            } else if !self.health.is_reachable() && self.recovery_start.is_none() {
+                self.recovery_start = r.first_reply.or(Some(now_s));
+            }
+            self.update_health(r.replied, now_s);
+        }
+    }
+
+    /// Reachability and health state, to tell what a probe changed.
+    fn health_now(&self) -> (bool, HealthState) {
+        (self.health.is_reachable(), self.health.state())
+    }
+
+    fn update_health(&mut self, ok: bool, now_s: SystemTime) {
+        let was_reachable = self.health.is_reachable();
+        if let Some(t) = self.health.on_probe(ok, now_s) {
+            if t.to == HealthState::Up {
+                info!(host:% = self.host, from:% = t.from, to:% = t.to;
+                      "state change for {} at {}: {} -> {}", self.host, format_rfc3339_millis(t.at), t.from, t.to);
+            } else {
+                warn!(host:% = self.host, from:% = t.from, to:% = t.to;
+                      "state change for {} at {}: {} -> {}", self.host, format_rfc3339_millis(t.at), t.from, t.to);
+            }
+            self.pending.events.extend(events::state_change(&self.host, t.at, &t.from.to_string(), &t.to.to_string()));
+            mqtt::state(&self.host, t.to);
+        }
+        if was_reachable && !self.health.is_reachable() {
+            let start = self.outage_streak_start.unwrap_or(now_s);
+            self.pending.events.extend(events::outage_start(&self.host, start, now_s, self.outage_streak_count));
+        }
+        if !ok || !self.health.is_reachable() {
+            return;
+        }
+        if !was_reachable {
+            // Back up: the miss streak that took the host down is a completed
+            // outage, which ended with the first reply of the streak that
+            // brought it back, not with the `up_after`th.
+            let end = self.recovery_start.take().unwrap_or(now_s);
+            if let Some(start) = self.outage_streak_start.take() {
+                self.sla_run.record_outage(start, end);
+                self.sla_window.record_outage(start, end);
+                self.pending.events.extend(events::outage(&self.host, start, end, self.outage_streak_count));
+                self.completed_outages.push(OutageRange {
+                    start,
+                    end: Some(end),
+                    count: self.outage_streak_count,
+                });
+            }
+        } else {
+            // Too few misses to take the host down, so not an outage.
+            self.outage_streak_start = None;
+        }
+        self.outage_streak_count = 0;
+    }
+
+    fn update_baseline(&mut self, rtt_us: u64) {
+        if let Some(ep) = self.open_episode.as_mut() {
+            ep.peak_us = ep.peak_us.max(rtt_us);
+        }
+        match self.baseline.update(rtt_us) {
+            Some(BaselineEvent::Degraded { median_us, baseline_us }) => {
+                warn!(host:% = self.host, median_us, baseline_us; "latency degraded for {}: median {:.3}ms vs baseline {:.3}ms",
+                      self.host, median_us as f64 / 1000.0, baseline_us as f64 / 1000.0);
+                self.open_episode = Some(LatencyEpisode {
+                    start: SystemTime::now(),
+                    end: None,
+                    baseline_us,
+                    peak_us: rtt_us.max(median_us),
+                });
+            }
+            Some(BaselineEvent::Recovered { median_us, baseline_us }) => {
+                info!(host:% = self.host, median_us, baseline_us; "latency recovered for {}: median {:.3}ms vs baseline {:.3}ms",
+                      self.host, median_us as f64 / 1000.0, baseline_us as f64 / 1000.0);
+                if let Some(mut ep) = self.open_episode.take() {
+                    ep.end = Some(SystemTime::now());
+                    self.completed_episodes.push(ep);
+                }
+            }
+            None => {}
+        }
+    }
 }
 
 struct TracksInner {
-    map: HashMap<std::net::IpAddr, TrackPerHost>,
+    map: HashMap<HostKey, TrackPerHost>,
+    /// Add the sliding window table to reports.
+    show_windows: bool,
+    correlator: Correlator,
+    /// Fault diagnosis as of the last reachability change.
+    verdict: Verdict,
+}
+
+impl TracksInner {
+    /// Follows up a probe on `key`, given the host's reachability and state
+    /// before it: a reachability change goes to the correlator, and any
+    /// change re-runs the diagnosis.
+    fn after_probe(&mut self, key: &HostKey, (was_reachable, was_state): (bool, HealthState), now_s: SystemTime) {
+        let per_host = &self.map[key];
+        if (was_reachable, was_state) == per_host.health_now() {
+            return;
+        }
+        match (was_reachable, per_host.health.is_reachable()) {
+            (true, false) => {
+                let start = per_host.outage_streak_start.unwrap_or(now_s);
+                self.correlator.host_down(key, &per_host.host, start);
+            }
+            (false, true) => {
+                let end = per_host.completed_outages.last().and_then(|o| o.end).unwrap_or(now_s);
+                if let Some(c) = self.correlator.host_up(key, end) {
+                    // written with the probe's events, after the lock is released
+                    let line = events::common_outage(&c);
+                    self.map.get_mut(key).unwrap().pending.events.extend(line);
+                }
+            }
+            _ => {}
+        }
+        let verdict = self.diagnose();
+        if verdict != self.verdict {
+            if verdict == Verdict::AllUp {
+                info!("diagnosis: {}", verdict);
+            } else {
+                warn!("diagnosis: {}", verdict);
+            }
+            self.verdict = verdict;
+        }
+    }
+
+    fn diagnose(&self) -> Verdict {
+        let mut hosts: Vec<&TrackPerHost> = self.map.values().collect();
+        hosts.sort_by_key(|h| h.order);
+        diagnose::diagnose(hosts.iter().map(|h| (&h.host, h.health.is_reachable(), h.health.state())))
+    }
 }
 
 pub struct Tracks {
@@ -166,6 +720,9 @@ impl Clone for Tracks {
 pub struct UpdateSendIteration {
     pub ident: u16,
     pub ip: IpAddr,
+    pub key: HostKey,
+    pub flow: usize,
+    pub mark: Option<u32>,
     pub sa: SockAddr,
     pub now: Instant,
 }
@@ -173,29 +730,75 @@ pub struct UpdateSendIteration {
 impl Tracks {
     pub fn new(cfg: &Config) -> Result<Self, anyhow::Error>
     {
+        if cfg.flows == 0 {
+            return Err(anyhow!("number of flows (--flows) must be at least 1"));
+        }
+        let health_cfg = HealthConfig {
+            down_after: cfg.down_after.max(1),
+            up_after: cfg.up_after.max(1),
+            degraded_loss_pct: cfg.degraded_loss,
+            flap_count: cfg.flap_count,
+            flap_window: cfg.flap_window,
+        };
+        let started = SystemTime::now();
         let mut ident = cfg.ident_base;
         let mut map = HashMap::new();
-        for h in &cfg.ips {
-            if map.contains_key(&h.ip) {
-                return Err(anyhow!("duplicate ip for {}", h));
-            } else {
-                map.insert(h.ip.clone(), TrackPerHost {
+        for (order, h) in cfg.ips.iter().enumerate() {
+            if let Entry::Vacant(e) = map.entry(h.key()) {
+                let mut flows = Vec::with_capacity(cfg.flows);
+                for _ in 0..cfg.flows {
+                    flows.push(FlowTrack {
+                        ident,
+                        last_time: None,
+                        last_seq: None,
+                        mark: false,
+                        sent: 0,
+                        last_send_stime: None,
+                        stats: Stats::new(),
+                        route: None,
+                        route_changes: 0,
+                        stamps: None,
+                        hops: None,
+                        last_rtt_us: None,
+                        last_error: None,
+                        loss: LossPattern::new(),
+                    });
+                    ident = ident.wrapping_add(1);
+                }
+                e.insert(TrackPerHost {
                     host: h.clone(),
-                    last_time: None,
-                    last_seq: None,
-                    ident,
-                    mark: false,
+                    order,
                     stats: Stats::new(),
-                    last_send_stime: None,
+                    flows,
+                    rounds: BTreeMap::new(),
+                    next_round: 0,
                     outage_streak_start: None,
                     outage_streak_count: 0,
+                    recovery_start: None,
                     completed_outages: Vec::new(),
+                    last_rtt_us: None,
+                    windows: Windows::new(),
+                    baseline: LatencyBaseline::new(cfg.degrade_factor, cfg.degrade_probes),
+                    health: Health::new(health_cfg.clone()),
+                    sla_run: Availability::new(started),
+                    sla_window: Availability::new(started),
+                    open_episode: None,
+                    completed_episodes: Vec::new(),
+                    rtt_buckets: RttBuckets::new(),
+                    non_replies: 0,
+                    pending: Pending::default(),
                 });
+            } else {
+                return Err(anyhow!("duplicate ip for {}", h));
             }
-            ident = ident.wrapping_add(1);
         }
         Ok(Tracks {
-            inner: Arc::new(Mutex::new(TracksInner { map }))
+            inner: Arc::new(Mutex::new(TracksInner {
+                correlator: Correlator::new(cfg.correlate_window, map.len()),
+                map,
+                show_windows: cfg.windows,
+                verdict: Verdict::AllUp,
+            }))
         })
     }
 
@@ -203,73 +806,133 @@ impl Tracks {
         self.inner.lock().unwrap().map.len()
     }
 
-    pub fn update_for_recv(&mut self, ip: IpAddr, now: Instant, ident: u16, seq: u16) -> bool {
+    /// The ident initially assigned to `flow` of a host (senders may override it).
+    pub fn ident_for(&self, key: &HostKey, flow: usize) -> u16 {
+        let lock = self.inner.lock().unwrap();
+        lock.map.get(key).expect("hey - this ip should be there but is not").flows[flow].ident
+    }
+
+    /// Finds the target and flow a reply belongs to when only its source address
+    /// is known.  Targets sharing an IP (different marks) and the flows of one
+    /// target are told apart by their ident.
+    pub fn key_for_reply(&self, ip: IpAddr, ident: u16) -> Option<(HostKey, usize)> {
+        let lock = self.inner.lock().unwrap();
+        lock.map.iter()
+            .filter(|(k, _)| k.ip == ip)
+            .find_map(|(k, v)| v.flows.iter().position(|f| f.ident == ident).map(|flow| (k.clone(), flow)))
+            .or_else(|| lock.map.keys().find(|k| k.ip == ip).map(|k| (k.clone(), 0)))
+    }
+
+    pub fn update_for_recv(&mut self, key: &HostKey, flow: usize, now: Instant, ident: u16, seq: u16, ttl: Option<u8>) -> bool {
         let mut lock = self.inner.lock().unwrap();
-        if let Some(per_host) = lock.map.get_mut(&ip) {
-            if per_host.ident != ident {
-                info!("ident difference for {} expected: {} got {}", per_host.host,
-                      per_host.ident, ident);
-            }
-            if per_host.last_seq.expect("should get a seqence") != seq {
-                info!("seq out of order for {}: sent: {} but just got {}", per_host.host,
-                      per_host.last_seq.unwrap(), seq);
-            }
-            let dur = now - per_host.last_time.expect("was supposed to have sometime");
-            per_host.stats.update_micros_working(dur.as_micros() as u64);
-            debug!("success for {} time: {:?}", per_host.host, dur);
-            // Close any open outage streak.
-            if let Some(start) = per_host.outage_streak_start.take() {
-                per_host.completed_outages.push(OutageRange {
-                    start,
-                    end: Some(instant_to_system_time(now)),
-                    count: per_host.outage_streak_count,
-                });
-                per_host.outage_streak_count = 0;
-            }
-            per_host.mark = true;
+        if let Some(per_host) = lock.map.get_mut(key) {
+            let was = per_host.health_now();
+            per_host.record_recv(flow, now, ident, seq, ttl);
+            lock.after_probe(key, was, instant_to_system_time(now));
+            let pending = std::mem::take(&mut lock.map.get_mut(key).unwrap().pending);
+            drop(lock);
+            pending.write();
+            true
+        } else {
+            false
+        }
+    }
+
+    /// An ICMP error quoting the probe `seq` of `flow`.  Returns false for an unknown target.
+    pub fn update_for_non_reply(&mut self, key: &HostKey, flow: usize, seq: u16) -> bool {
+        let mut lock = self.inner.lock().unwrap();
+        if let Some(per_host) = lock.map.get_mut(key) {
+            per_host.record_non_reply(flow, seq);
             true
         } else {
             false
         }
     }
-    pub fn update_for_send(&mut self, ip: IpAddr, now: Instant, ident: u16, seq: u16) -> bool {
+
+    /// Records the path reported by the Record Route option on a reply,
+    /// logging when it differs from the previous round's.
+    pub fn update_route(&mut self, key: &HostKey, flow: usize, route: Vec<Ipv4Addr>) {
         let mut lock = self.inner.lock().unwrap();
-        let mut per_host = lock.map.get_mut(&ip).expect("hey - this ip should be there but is not");
-        let last_mark = per_host.mark;
-        if !per_host.mark && per_host.last_seq.is_some() {
-            info!("timeout for {} missed seq {}", per_host.host, per_host.last_seq.unwrap());
-            per_host.stats.update_fail();
-            // Open or extend the outage streak.
-            if per_host.outage_streak_start.is_none() {
-                per_host.outage_streak_start = per_host.last_send_stime;
+        let per_host = lock.map.get_mut(key).expect("hey - this ip should be there but is not");
+        let f = &mut per_host.flows[flow];
+        if let Some(prev) = &f.route {
+            if *prev != route {
+                warn!("path change for {} flow {}: [{}] -> [{}]", per_host.host, flow,
+                      format_route(prev), format_route(&route));
+                f.route_changes += 1;
             }
-            per_host.outage_streak_count += 1;
         }
-        per_host.last_send_stime = Some(SystemTime::now());
-        per_host.last_seq = Some(seq);
-        per_host.last_time = Some(now);
-        per_host.mark = false;
-        last_mark
+        f.route = Some(route);
+    }
+
+    /// Records the hops and times reported by the Timestamp option on a reply.
+    pub fn update_timestamps(&mut self, key: &HostKey, flow: usize, stamps: Vec<(Ipv4Addr, u32)>) {
+        let mut lock = self.inner.lock().unwrap();
+        let per_host = lock.map.get_mut(key).expect("hey - this ip should be there but is not");
+        per_host.flows[flow].stamps = Some(stamps);
+    }
+
+    pub fn update_for_send(&mut self, key: &HostKey, flow: usize, now: Instant, ident: u16, seq: u16) -> bool {
+        let mut lock = self.inner.lock().unwrap();
+        let now_s = SystemTime::now();
+        let per_host = lock.map.get_mut(key).expect("hey - this ip should be there but is not");
+        let was = per_host.health_now();
+        let answered = per_host.record_send(flow, now, now_s, ident, seq);
+        lock.after_probe(key, was, now_s);
+        let pending = std::mem::take(&mut lock.map.get_mut(key).unwrap().pending);
+        drop(lock);
+        pending.write();
+        answered
+    }
+
+    /// Since-start view of every host, in command line order, for the metric
+    /// exporters.  Reads only; the -R resets of the report are not affected.
+    pub fn metrics(&self) -> Vec<HostMetrics> {
+        let lock = self.inner.lock().unwrap();
+        let at = SystemTime::now();
+        let mut v: Vec<(usize, HostMetrics)> = lock.map.values().map(|h| {
+            let snap = h.stats.snapshot();
+            (h.order, HostMetrics {
+                host: h.host.clone(),
+                state: h.health.state(),
+                reachable: h.health.is_reachable(),
+                totals: h.windows.since_start(),
+                non_replies: h.non_replies,
+                rtt: h.rtt_buckets.clone(),
+                outages: h.sla_run.outages(),
+                downtime: h.sla_run.downtime(),
+                last_rtt_us: h.last_rtt_us,
+                jitter_ms: snap.jitter_ms(),
+                voice: snap.voice_quality(),
+                at,
+            })
+        }).collect();
+        v.sort_by(|a, b| (&a.1.host.netns, a.0).cmp(&(&b.1.host.netns, b.0)));
+        v.into_iter().map(|(_, m)| m).collect()
+    }
+
+    /// Notes why the outstanding probe on `flow` failed, reported with its
+    /// timeout once the next probe is sent.
+    pub fn note_error(&mut self, key: &HostKey, flow: usize, class: &'static str) {
+        let mut lock = self.inner.lock().unwrap();
+        if let Some(per_host) = lock.map.get_mut(key) {
+            per_host.flows[flow].last_error = Some(class);
+        }
     }
 
-    pub fn update_for_send_bulk(&mut self, v: &Vec<UpdateSendIteration>, seq: u16) {
+    pub fn update_for_send_bulk(&mut self, v: &[UpdateSendIteration], seq: u16) {
         let now_s = SystemTime::now();
         let mut lock = self.inner.lock().unwrap();
+        let mut pending = Vec::with_capacity(v.len());
         for i in v.iter() {
-            let mut per_host = lock.map.get_mut(&i.ip).expect("hey - this ip should be there but is not");
-            if !per_host.mark && per_host.last_seq.is_some() {
-                info!("timeout for {} missed seq {}", per_host.host, per_host.last_seq.unwrap());
-                per_host.stats.update_fail();
-                if per_host.outage_streak_start.is_none() {
-                    per_host.outage_streak_start = per_host.last_send_stime;
-                }
-                per_host.outage_streak_count += 1;
-            }
-            per_host.last_send_stime = Some(now_s);
-            per_host.last_seq = Some(seq);
-            per_host.last_time = Some(i.now);
-            per_host.mark = false;
+            let per_host = lock.map.get_mut(&i.key).expect("hey - this ip should be there but is not");
+            let was = per_host.health_now();
+            per_host.record_send(i.flow, i.now, now_s, i.ident, seq);
+            lock.after_probe(&i.key, was, now_s);
+            pending.push(std::mem::take(&mut lock.map.get_mut(&i.key).unwrap().pending));
         }
+        drop(lock);
+        pending.into_iter().for_each(Pending::write);
     }
 
 
@@ -278,9 +941,10 @@ impl Tracks {
         let mut out = String::new();
         let now_s = SystemTime::now();
 
-        let mut table = Table::new("\t{:<} {:>} {:>} {:>} {:>} {:>} {:>} {:>}");
+        let mut table = Table::new("\t{:<} {:<} {:>} {:>} {:>} {:>} {:>} {:>} {:>} {:>} {:>} {:>} {:>} {:>} {:>} {:>} {:>} {:>}");
         table.add_row(Row::new()
             .with_cell("host")
+            .with_cell("state")
             .with_cell("reply")
             .with_cell("nonreply")
             .with_cell("timeout")
@@ -288,21 +952,55 @@ impl Tracks {
             .with_cell("min(ms)")
             .with_cell("max(ms)")
             .with_cell("stdev(ms)")
+            .with_cell("jitter(ms)")
+            .with_cell("ipdv avg/max(ms)")
+            .with_cell("p50(ms)")
+            .with_cell("p90(ms)")
+            .with_cell("p99(ms)")
+            .with_cell("ttl")
+            .with_cell("hops(min-max)")
+            .with_cell("R")
+            .with_cell("MOS")
         );
 
         // Collect per-host data under the lock, then release before formatting.
         struct HostData {
             host: HostInfo,
+            order: usize,
             stat: StatsSnapShot,
+            flows: Vec<(u16, StatsSnapShot)>,
+            routes: Vec<(Option<Vec<Ipv4Addr>>, u32)>,
+            stamps: Vec<Option<Vec<(Ipv4Addr, u32)>>>,  // per flow
             outages: Vec<OutageRange>,
             open_outage: Option<(SystemTime, u32)>,  // (start, count) if still ongoing
+            windows: Vec<WindowAgg>,  // WINDOWS in order, then since start
+            episodes: Vec<LatencyEpisode>,  // completed, then the open one if any
+            state: HealthState,
+            sla: [SlaSummary; 2],  // since start, since last report
+            loss: Vec<LossPattern>,  // per flow
         }
-        let host_data: Vec<HostData> = {
+        let (show_windows, verdict, common, mut host_data): (bool, Verdict, Vec<CommonOutage>, Vec<HostData>) = {
             let mut lock = self.inner.lock().unwrap();
-            lock.map.iter_mut().map(|(_ip, v)| {
+            let show_windows = lock.show_windows;
+            let verdict = lock.diagnose();
+            let common = lock.correlator.report(reset);
+            (show_windows, verdict, common, lock.map.iter_mut().map(|(_ip, v)| {
                 let stat = if reset { v.stats.zero_extract() } else { v.stats.snapshot() };
+                let flows = v.flows.iter_mut().map(|f| {
+                    (f.ident, if reset { f.stats.zero_extract() } else { f.stats.snapshot() })
+                }).collect();
+                let routes = v.flows.iter_mut().map(|f| {
+                    let changes = f.route_changes;
+                    if reset {
+                        f.route_changes = 0;
+                    }
+                    (f.route.clone(), changes)
+                }).collect();
+                let stamps = v.flows.iter().map(|f| f.stamps.clone()).collect();
                 // Snapshot the open streak (don't close it — host may still be down).
-                let open_outage = v.outage_streak_start.map(|s| (s, v.outage_streak_count));
+                let open_outage = v.outage_streak_start
+                    .filter(|_| !v.health.is_reachable())
+                    .map(|s| (s, v.outage_streak_count));
                 // Drain completed outages; in cumulative mode leave them in place.
                 let outages = if reset {
                     std::mem::take(&mut v.completed_outages)
@@ -313,8 +1011,52 @@ impl Tracks {
                         count: o.count,
                     }).collect()
                 };
-                HostData { host: v.host.clone(), stat, outages, open_outage }
-            }).collect()
+                let windows = if show_windows {
+                    let mut w: Vec<WindowAgg> = WINDOWS.iter().map(|(_, span)| v.windows.aggregate(now_s, *span)).collect();
+                    w.push(v.windows.since_start());
+                    w
+                } else {
+                    vec![]
+                };
+                // Same drain/keep rule as outages; an open episode is always shown.
+                let mut episodes = if reset {
+                    std::mem::take(&mut v.completed_episodes)
+                } else {
+                    v.completed_episodes.clone()
+                };
+                episodes.extend(v.open_episode.clone());
+                let open_start = open_outage.map(|(s, _)| s);
+                let sla = [v.sla_run.summary(now_s, open_start), v.sla_window.summary(now_s, open_start)];
+                v.sla_window = Availability::new(now_s);
+                let loss = v.flows.iter_mut().map(|f| f.loss.extract(reset)).collect();
+                HostData { host: v.host.clone(), order: v.order, stat, flows, routes, stamps, outages, open_outage, windows, episodes,
+                           state: v.health.state(), sla, loss }
+            }).collect())
+        };
+        // Group hosts by network namespace, keeping command line order within each group.
+        host_data.sort_by(|a, b| (&a.host.netns, a.order).cmp(&(&b.host.netns, b.order)));
+
+        let _ = writeln!(out, "\tDIAGNOSIS: {}", verdict);
+
+        // Outages that hit several hosts at once, likely a local fault.  They are
+        // numbered so the per-host outages below can point at theirs.
+        if !common.is_empty() {
+            let _ = writeln!(out, "\tCOMMON-CAUSE OUTAGES:");
+            for (no, c) in common.iter().enumerate() {
+                let end_str = match c.end {
+                    Some(t) => format_rfc3339_millis(t).to_string(),
+                    None    => "ongoing".to_string(),
+                };
+                let hosts = c.hosts.iter().map(|h| h.to_string()).collect::<Vec<_>>().join(", ");
+                let _ = writeln!(out, "\t  #{} [{} -> {}, {} hosts] {}",
+                    no + 1, format_rfc3339_millis(c.start), end_str, c.hosts.len(), hosts);
+            }
+        }
+        // " common #n" for a host outage that is part of a common-cause outage.
+        let common_mark = |host: &HostInfo, start: SystemTime| {
+            common.iter().position(|c| {
+                c.hosts.iter().any(|h| h.key() == host.key()) && start >= c.start && c.end.is_none_or(|end| start <= end)
+            }).map_or(String::new(), |no| format!(", common #{}", no + 1))
         };
 
         // Build outage section (only if any host has outage data).
@@ -331,56 +1073,589 @@ impl Tracks {
                         Some(t) => format_rfc3339_millis(t).to_string(),
                         None    => "ongoing".to_string(),
                     };
-                    let _ = write!(out, " [{} -> {}, {} missed]",
-                        format_rfc3339_millis(o.start), end_str, o.count);
+                    let _ = write!(out, " [{} -> {}, {} missed{}]",
+                        format_rfc3339_millis(o.start), end_str, o.count, common_mark(&hd.host, o.start));
                 }
                 if let Some((start, count)) = hd.open_outage {
-                    let _ = write!(out, " [{} -> ongoing, {} missed]",
-                        format_rfc3339_millis(start), count);
+                    let _ = write!(out, " [{} -> ongoing, {} missed{}]",
+                        format_rfc3339_millis(start), count, common_mark(&hd.host, start));
                 }
                 let _ = writeln!(out);
             }
         }
 
-        // Build stats table.
+        // Latency degradation episodes (only if any host has them).
+        if host_data.iter().any(|h| !h.episodes.is_empty()) {
+            let _ = writeln!(out, "\tDEGRADED:");
+            for hd in host_data.iter().filter(|h| !h.episodes.is_empty()) {
+                let _ = write!(out, "\t  {}:", hd.host);
+                for e in &hd.episodes {
+                    let end_str = match e.end {
+                        Some(t) => format_rfc3339_millis(t).to_string(),
+                        None    => "ongoing".to_string(),
+                    };
+                    let _ = write!(out, " [{} -> {}, baseline {:.3}ms, peak {:.3}ms]",
+                        format_rfc3339_millis(e.start), end_str,
+                        e.baseline_us as f64 / 1000.0, e.peak_us as f64 / 1000.0);
+                }
+                let _ = writeln!(out);
+            }
+        }
+
+        // Recorded paths (only present when an IP option is in use).
+        let any_routes = host_data.iter().any(|h| h.routes.iter().any(|(r, _)| r.is_some()));
+        if any_routes {
+            let _ = writeln!(out, "\tROUTES:");
+            for hd in &host_data {
+                for (no, (route, changes)) in hd.routes.iter().enumerate() {
+                    if let Some(route) = route {
+                        if hd.routes.len() > 1 {
+                            let _ = write!(out, "\t  {} flow{}:", hd.host, no);
+                        } else {
+                            let _ = write!(out, "\t  {}:", hd.host);
+                        }
+                        let _ = writeln!(out, " [{}] {} changes", format_route(route), changes);
+                    }
+                }
+            }
+        }
+
+        // Hops that stamped the Timestamp option, with their times; not a path.
+        if host_data.iter().any(|h| h.stamps.iter().any(Option::is_some)) {
+            let _ = writeln!(out, "\tTIMESTAMPS (ms since midnight UT):");
+            for hd in &host_data {
+                for (no, stamps) in hd.stamps.iter().enumerate() {
+                    if let Some(stamps) = stamps {
+                        if hd.stamps.len() > 1 {
+                            let _ = write!(out, "\t  {} flow{}:", hd.host, no);
+                        } else {
+                            let _ = write!(out, "\t  {}:", hd.host);
+                        }
+                        let _ = writeln!(out, " [{}]", format_stamps(stamps));
+                    }
+                }
+            }
+        }
+
+        // Build stats table.  Per-flow rows are only shown when probing several flows.
         for hd in &host_data {
-            let stat = &hd.stat;
-            let count = stat.reply + stat.non_reply;
-            if count > 0 {
-                let avg_ms = (stat.time_sum_us as f64 / count as f64) / 1000.0;
-                let min_ms = stat.time_min_us as f64 / 1000.0;
-                let max_ms = stat.time_max_us as f64 / 1000.0;
-                let stdev_ms = if count >= 2 {
-                    let avg_us = stat.time_sum_us as f64 / count as f64;
-                    let mean_sq = stat.time_sum_sq_us as f64 / count as f64;
-                    let var = (mean_sq - avg_us * avg_us).max(0.0);
-                    format!("{:.3}", var.sqrt() / 1000.0)
-                } else {
-                    "NA".to_string()
-                };
-                table.add_row(Row::new()
-                    .with_cell(&hd.host)
-                    .with_cell(stat.reply)
-                    .with_cell(stat.non_reply)
-                    .with_cell(stat.timeout)
-                    .with_cell(format!("{:.3}", avg_ms))
-                    .with_cell(format!("{:.3}", min_ms))
-                    .with_cell(format!("{:.3}", max_ms))
-                    .with_cell(stdev_ms));
-            } else {
-                table.add_row(Row::new()
-                    .with_cell(&hd.host)
-                    .with_cell(stat.reply)
-                    .with_cell(stat.non_reply)
-                    .with_cell(stat.timeout)
-                    .with_cell("NA")
-                    .with_cell("NA")
-                    .with_cell("NA")
-                    .with_cell("NA"));
+            table.add_row(stats_row(&hd.host, hd.state.to_string(), &hd.stat));
+            if hd.flows.len() > 1 {
+                for (no, (ident, stat)) in hd.flows.iter().enumerate() {
+                    table.add_row(stats_row(format!("  flow{} id={}", no, ident), "", stat));
+                }
             }
         }
         let _ = write!(out, "{}", table);
+
+        // The same rows for --csv-stats.
+        if csv::stats_enabled() {
+            let mode = if reset { "interval" } else { "cumulative" };
+            for hd in &host_data {
+                let state = hd.state.to_string();
+                csv::stats_row(&stats_csv_row(now_s, mode, &hd.host, None, &state, &hd.stat));
+                if hd.flows.len() > 1 {
+                    for (no, (ident, stat)) in hd.flows.iter().enumerate() {
+                        csv::stats_row(&stats_csv_row(now_s, mode, &hd.host, Some((no, *ident)), "", stat));
+                    }
+                }
+            }
+        }
+
+        // Availability from outages, for the whole run and since the previous report.
+        let mut sla_table = Table::new("\t{:<} {:<} {:>} {:>} {:>} {:>} {:>} {:>} {:>}");
+        sla_table.add_row(Row::new()
+            .with_cell("host")
+            .with_cell("span")
+            .with_cell("period")
+            .with_cell("avail%")
+            .with_cell("nines")
+            .with_cell("outages")
+            .with_cell("downtime")
+            .with_cell("MTTR")
+            .with_cell("MTBF"));
+        for hd in &host_data {
+            for (span, sla) in ["run", "interval"].iter().zip(hd.sla.iter()) {
+                sla_table.add_row(Row::new()
+                    .with_cell(&hd.host)
+                    .with_cell(span)
+                    .with_cell(fmt_secs(Some(sla.period)))
+                    .with_cell(format!("{:.3}", sla.availability_pct))
+                    .with_cell(sla.nines().map_or("-".to_string(), |n| format!("{:.1}", n)))
+                    .with_cell(sla.outages)
+                    .with_cell(fmt_secs(Some(sla.downtime)))
+                    .with_cell(fmt_secs(sla.mttr))
+                    .with_cell(fmt_secs(sla.mtbf)));
+            }
+        }
+        let _ = write!(out, "\tSLA:\n{}", sla_table);
+
+        // Loss burst structure (only if any host lost probes).  Bursts are found
+        // per flow; the host row adds up its flows.
+        if host_data.iter().any(|h| h.loss.iter().any(|l| l.losses() > 0)) {
+            let mut ltable = Table::new("\t{:<} {:>} {:>} {:>} {:>} {:>} {:>} {:>} {:>} {:>} {:>} {:<}");
+            ltable.add_row(Row::new()
+                .with_cell("host")
+                .with_cell("lost")
+                .with_cell("periods")
+                .with_cell("avg len")
+                .with_cell("max len")
+                .with_cell("avg dist")
+                .with_cell("GE p%")
+                .with_cell("GE r%")
+                .with_cell("good loss%")
+                .with_cell("bad loss%")
+                .with_cell("clp%")
+                .with_cell("bursts(len:count)"));
+            for hd in host_data.iter() {
+                let mut total = LossPattern::new();
+                for l in &hd.loss {
+                    total.merge(l);
+                }
+                if total.losses() == 0 {
+                    continue;
+                }
+                ltable.add_row(loss_row(&hd.host, &total));
+                if hd.loss.len() > 1 {
+                    for (no, l) in hd.loss.iter().enumerate() {
+                        ltable.add_row(loss_row(format!("  flow{}", no), l));
+                    }
+                }
+            }
+            let _ = write!(out, "\tLOSS PATTERN:\n{}", ltable);
+        }
+
+        // Sliding windows next to since-start totals, regardless of -R.
+        if show_windows {
+            let spec = format!("\t{{:<}}{}", " {:>}".repeat(2 * (WINDOWS.len() + 1)));
+            let mut wtable = Table::new(&spec);
+            let mut header = Row::new().with_cell("host");
+            for name in WINDOWS.iter().map(|(n, _)| *n).chain(std::iter::once("all")) {
+                header = header.with_cell(format!("{} loss%", name)).with_cell(format!("{} avg(ms)", name));
+            }
+            wtable.add_row(header);
+            for hd in &host_data {
+                let mut row = Row::new().with_cell(&hd.host);
+                for w in &hd.windows {
+                    row = row.with_cell(w.loss_pct().map_or("NA".to_string(), |l| format!("{:.1}", l)))
+                        .with_cell(fmt_ms(w.avg_ms()));
+                }
+                wtable.add_row(row);
+            }
+            // windows are whole buckets, so each covers up to a bucket less than its length
+            let (name, span) = WINDOWS[0];
+            let _ = write!(out, "\tWINDOWS ({}s buckets, {} covers the last {}-{}s):\n{}", BUCKET_SECS, name,
+                           span.as_secs() - BUCKET_SECS, span.as_secs(), wtable);
+        }
         out
     }
 }
 
+
+fn format_route(route: &[Ipv4Addr]) -> String {
+    route.iter().map(|a| a.to_string()).collect::<Vec<_>>().join(" -> ")
+}
+
+fn format_stamps(stamps: &[(Ipv4Addr, u32)]) -> String {
+    stamps.iter().map(|(a, ms)| format!("{} {}", a, ms)).collect::<Vec<_>>().join(", ")
+}
+
+fn fmt_secs(v: Option<Duration>) -> String {
+    match v {
+        Some(d) => humantime::format_duration(Duration::from_secs(d.as_secs())).to_string(),
+        None => "NA".to_string(),
+    }
+}
+
+fn loss_row<L: fmt::Display>(label: L, l: &LossPattern) -> Row {
+    let fit = l.gilbert();
+    let bursts = l.periods().iter().map(|(len, c)| format!("{}:{}", len, c)).collect::<Vec<_>>().join(" ");
+    Row::new()
+        .with_cell(label)
+        .with_cell(l.losses())
+        .with_cell(l.period_count())
+        .with_cell(l.period_avg().map_or("-".to_string(), |v| format!("{:.2}", v)))
+        .with_cell(l.period_max().map_or("-".to_string(), |v| v.to_string()))
+        .with_cell(l.distance_avg().map_or("-".to_string(), |v| format!("{:.1}", v)))
+        .with_cell(fit.as_ref().map_or("-".to_string(), |g| format!("{:.2}", g.p * 100.0)))
+        .with_cell(fit.as_ref().map_or("-".to_string(), |g| format!("{:.2}", g.r * 100.0)))
+        .with_cell(fit.as_ref().map_or("-".to_string(), |g| format!("{:.2}", g.loss_good * 100.0)))
+        .with_cell(fit.as_ref().map_or("-".to_string(), |g| format!("{:.2}", g.loss_bad * 100.0)))
+        .with_cell(fit.as_ref().map_or("-".to_string(), |g| format!("{:.2}", g.clp() * 100.0)))
+        .with_cell(if bursts.is_empty() { "-".to_string() } else { bursts })
+}
+
+fn fmt_ms(v: Option<f64>) -> String {
+    match v {
+        Some(ms) => format!("{:.3}", ms),
+        None => "NA".to_string(),
+    }
+}
+
+/// A csv::STATS_HEADER row; `flow` is (index, ident) for per-flow rows.
+fn stats_csv_row(now_s: SystemTime, mode: &str, host: &HostInfo, flow: Option<(usize, u16)>, state: &str,
+                 stat: &StatsSnapShot) -> Vec<String> {
+    let ms = |v: Option<f64>| v.map_or(String::new(), |v| format!("{:.3}", v));
+    let (hops_min, hops_max) = match stat.hops_range() {
+        Some((min, max)) => (min.to_string(), max.to_string()),
+        None => (String::new(), String::new()),
+    };
+    let vq = stat.voice_quality();
+    let mut row = vec![csv::ts(now_s), mode.to_string()];
+    row.extend(csv::host_fields(host));
+    row.extend([
+        flow.map_or(String::new(), |(no, _)| no.to_string()),
+        flow.map_or(String::new(), |(_, ident)| ident.to_string()),
+        state.to_string(),
+        stat.reply.to_string(),
+        stat.non_reply.to_string(),
+        stat.timeout.to_string(),
+        stat.loss_pct().map_or(String::new(), |v| format!("{:.2}", v)),
+        ms(stat.avg_ms()),
+        ms(stat.min_ms()),
+        ms(stat.max_ms()),
+        ms(stat.stdev_ms()),
+        ms(stat.jitter_ms()),
+        ms(stat.ipdv_avg_ms()),
+        ms(stat.ipdv_max_ms()),
+        ms(stat.percentile_ms(50.0)),
+        ms(stat.percentile_ms(90.0)),
+        ms(stat.percentile_ms(99.0)),
+        stat.ttl().map_or(String::new(), |t| t.to_string()),
+        hops_min,
+        hops_max,
+        vq.as_ref().map_or(String::new(), |q| format!("{:.1}", q.r)),
+        vq.as_ref().map_or(String::new(), |q| format!("{:.2}", q.mos)),
+    ]);
+    row
+}
+
+fn stats_row<L: fmt::Display, S: fmt::Display>(label: L, state: S, stat: &StatsSnapShot) -> Row {
+    let hops = match (stat.ttl(), stat.hops_range()) {
+        (Some(ttl), Some((min, max))) => format!("{} ({}-{})", hops_from_ttl(ttl), min, max),
+        _ => "NA".to_string(),
+    };
+    let ipdv = match (stat.ipdv_avg_ms(), stat.ipdv_max_ms()) {
+        (Some(avg), Some(max)) => format!("{:.3}/{:.3}", avg, max),
+        _ => "NA".to_string(),
+    };
+    let (r, mos) = match stat.voice_quality() {
+        Some(q) => (format!("{:.1}", q.r), format!("{:.2}", q.mos)),
+        None => ("NA".to_string(), "NA".to_string()),
+    };
+    Row::new()
+        .with_cell(label)
+        .with_cell(state)
+        .with_cell(stat.reply)
+        .with_cell(stat.non_reply)
+        .with_cell(stat.timeout)
+        .with_cell(fmt_ms(stat.avg_ms()))
+        .with_cell(fmt_ms(stat.min_ms()))
+        .with_cell(fmt_ms(stat.max_ms()))
+        .with_cell(fmt_ms(stat.stdev_ms()))
+        .with_cell(fmt_ms(stat.jitter_ms()))
+        .with_cell(ipdv)
+        .with_cell(fmt_ms(stat.percentile_ms(50.0)))
+        .with_cell(fmt_ms(stat.percentile_ms(90.0)))
+        .with_cell(fmt_ms(stat.percentile_ms(99.0)))
+        .with_cell(stat.ttl().map_or("NA".to_string(), |t| t.to_string()))
+        .with_cell(hops)
+        .with_cell(r)
+        .with_cell(mos)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Feeds consecutive reply RTTs through the delay variation estimators.
+    fn replies(rtts: &[u64]) -> StatsSnapShot {
+        let s = Stats::new();
+        for w in rtts.windows(2) {
+            s.update_delay_variation(w[0], w[1]);
+        }
+        s.snapshot()
+    }
+
+    #[test]
+    fn jitter_steps() {
+        // D = 1000us each of the first two steps, then 0: J is 62.5, 121.06 and 113.5us
+        let mut jitter = vec![];
+        let rtts = [10_000, 11_000, 10_000, 10_000];
+        for n in 2..=rtts.len() {
+            jitter.push(replies(&rtts[..n]).jitter_x16);
+        }
+        assert_eq!(jitter, vec![1000, 1937, 1816]);
+    }
+
+    #[test]
+    fn jitter_tracks_the_rfc_formula() {
+        let rtts: Vec<u64> = (0..200).map(|i| 20_000 + (i * 7919 % 5000)).collect();
+        let mut j = 0.0f64;
+        for w in rtts.windows(2) {
+            j += (w[0].abs_diff(w[1]) as f64 - j) / 16.0;
+        }
+        let got = replies(&rtts).jitter_ms().unwrap() * 1000.0;
+        assert!((got - j).abs() < 1.0, "fixed point {} vs {}", got, j);
+    }
+
+    #[test]
+    fn jitter_needs_a_reply_pair() {
+        let s = Stats::new();
+        s.update_micros_working(10_000);
+        s.update_micros_working(10_000);
+        // two replies with a loss in between are not a pair
+        assert_eq!(s.snapshot().jitter_ms(), None);
+        s.update_delay_variation(10_000, 10_000);
+        assert_eq!(s.snapshot().jitter_ms(), Some(0.0));
+    }
+
+    #[test]
+    fn voice_quality_from_snapshot() {
+        let s = Stats::new();
+        assert!(s.snapshot().voice_quality().is_none());
+        s.update_micros_working(100_000);
+        s.update_micros_working(100_000);
+        s.update_delay_variation(100_000, 100_000);
+        let q = s.snapshot().voice_quality().unwrap();
+        // 100ms RTT, no jitter or loss: delay 60ms, R = 93.2 - 1.44
+        assert!((q.r - 91.76).abs() < 1e-9, "{}", q.r);
+    }
+
+    #[test]
+    fn ipdv_over_reply_pairs() {
+        let s = replies(&[10_000, 12_000, 11_500, 11_500]);
+        assert_eq!(s.ipdv_count, 3);
+        assert_eq!(s.ipdv_avg_ms(), Some(2.5 / 3.0));
+        assert_eq!(s.ipdv_max_ms(), Some(2.0));
+    }
+
+    #[test]
+    fn interval_reset_keeps_jitter() {
+        let mut s = Stats::new();
+        s.update_delay_variation(10_000, 11_000);
+        let first = s.zero_extract();
+        assert_eq!((first.jitter_x16, first.ipdv_count), (1000, 1));
+        let next = s.zero_extract();
+        assert_eq!((next.jitter_x16, next.ipdv_count), (1000, 0));
+        assert_eq!(next.ipdv_avg_ms(), None);
+    }
+
+    #[test]
+    fn non_replies_are_counted_without_their_time() {
+        let s = Stats::new();
+        s.update_micros_working(20_000);
+        s.update_non_reply();
+        s.update_non_reply();
+        s.update_fail();
+        let snap = s.snapshot();
+        assert_eq!((snap.reply, snap.non_reply, snap.timeout), (1, 2, 1));
+        assert_eq!((snap.min_ms(), snap.avg_ms(), snap.max_ms()), (Some(20.0), Some(20.0), Some(20.0)));
+        assert_eq!(snap.percentile_ms(50.0), Some(20.0));
+        assert_eq!(snap.loss_pct(), Some(50.0));
+        let only_errors = Stats::new();
+        only_errors.update_non_reply();
+        assert_eq!((only_errors.snapshot().avg_ms(), only_errors.snapshot().min_ms()), (None, None));
+    }
+
+    fn tracks(args: &[&str]) -> Tracks {
+        use clap::Parser;
+        let args = std::iter::once("sirpingsalot").chain(args.iter().copied());
+        Tracks::new(&Config::parse_from(args)).unwrap()
+    }
+
+    fn at(secs: u64) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000 + secs)
+    }
+
+    /// Runs `f` on the only host of a Tracks built from `args`.
+    fn with_host(args: &[&str], f: impl FnOnce(&mut TrackPerHost)) {
+        let t = tracks(args);
+        let mut lock = t.inner.lock().unwrap();
+        f(lock.map.values_mut().next().unwrap());
+    }
+
+    #[test]
+    fn replies_are_matched_to_marks_by_ident() {
+        let t = tracks(&["-I", "100", "--flows", "2", "192.0.2.1,mark=1", "192.0.2.1,mark=2", "192.0.2.2"]);
+        let ip = IpAddr::from([192, 0, 2, 1]);
+        let mark_flow = |ident| t.key_for_reply(ip, ident).map(|(k, flow)| (k.mark, flow));
+        assert_eq!(mark_flow(100), Some((Some(1), 0)));
+        assert_eq!(mark_flow(101), Some((Some(1), 1)));
+        assert_eq!(mark_flow(103), Some((Some(2), 1)));
+        // an unknown ident still lands on the first flow of a target with that address
+        assert_eq!(t.key_for_reply(ip, 7).map(|(k, flow)| (k.ip, flow)), Some((ip, 0)));
+        assert_eq!(t.key_for_reply(IpAddr::from([192, 0, 2, 9]), 100), None);
+    }
+
+    #[test]
+    fn loss_on_some_flows_is_not_an_outage() {
+        with_host(&["--flows", "2", "--down-after", "2", "192.0.2.1"], |h| {
+            for round in 0..6 {
+                h.round_outcome(round, false, Some(at(round)), at(round + 1));
+                h.round_outcome(round, true, None, at(round + 1));
+            }
+            assert!(h.health.is_reachable());
+            assert_eq!((h.outage_streak_start, h.outage_streak_count), (None, 0));
+            assert!(h.rounds.is_empty());
+            assert_eq!(h.next_round, 6);
+        });
+    }
+
+    #[test]
+    fn loss_on_every_flow_is_an_outage() {
+        with_host(&["--flows", "2", "--down-after", "2", "192.0.2.1"], |h| {
+            h.round_outcome(0, false, Some(at(1)), at(3));
+            // undecided until the other flow's probe is resolved too
+            assert_eq!(h.next_round, 0);
+            h.round_outcome(0, false, Some(at(0)), at(3));
+            assert_eq!((h.next_round, h.outage_streak_start), (1, Some(at(0))));
+            assert!(h.health.is_reachable());
+            h.round_outcome(1, false, Some(at(1)), at(4));
+            h.round_outcome(1, false, Some(at(1)), at(4));
+            assert!(!h.health.is_reachable());
+            assert_eq!(h.outage_streak_count, 2);
+            // a reply in a round that already settled does not count
+            h.round_outcome(1, true, None, at(5));
+            assert!(!h.health.is_reachable());
+            assert_eq!(h.next_round, 2);
+        });
+    }
+
+    #[test]
+    fn stalled_flow_holds_rounds_up_to_the_limit() {
+        with_host(&["--flows", "2", "--down-after", "2", "192.0.2.1"], |h| {
+            // flow 1 never resolves; flow 0 misses every round
+            for round in 0..MAX_OPEN_ROUNDS as u64 {
+                h.round_outcome(round, false, Some(at(round)), at(round + 1));
+            }
+            assert_eq!((h.next_round, h.rounds.len()), (0, MAX_OPEN_ROUNDS));
+            assert!(h.health.is_reachable());
+            // one more and the oldest is settled as missed with what it has
+            let round = MAX_OPEN_ROUNDS as u64;
+            h.round_outcome(round, false, Some(at(round)), at(round + 1));
+            assert_eq!((h.next_round, h.rounds.len()), (1, MAX_OPEN_ROUNDS));
+            assert_eq!((h.outage_streak_start, h.outage_streak_count), (Some(at(0)), 1));
+            h.round_outcome(round + 1, false, Some(at(round + 1)), at(round + 2));
+            assert!(!h.health.is_reachable());
+            // rounds settle oldest first, so a reply waits behind the held ones
+            h.round_outcome(round + 2, true, None, at(round + 3));
+            assert_eq!(h.next_round, 3);
+            assert!(h.rounds.contains_key(&(round + 2)));
+            // and is settled once the stalled flow catches up
+            for r in 3..round + 2 {
+                h.round_outcome(r, false, Some(at(r)), at(round + 4));
+            }
+            assert_eq!((h.next_round, h.rounds.len()), (round + 3, 0));
+            assert_eq!(h.outage_streak_count, 6);
+        });
+    }
+
+    #[test]
+    fn degraded_host_is_diagnosed() {
+        let t = tracks(&["192.0.2.1,tier=gateway", "192.0.2.2"]);
+        let key = HostInfo::new(None, "192.0.2.2".parse().unwrap()).key();
+        let mut lock = t.inner.lock().unwrap();
+        // every third probe lost: degraded, never down
+        for round in 0..12 {
+            let h = lock.map.get_mut(&key).unwrap();
+            let was = h.health_now();
+            h.round_outcome(round, round % 3 != 2, Some(at(round)), at(round + 1));
+            lock.after_probe(&key, was, at(round + 1));
+        }
+        assert_eq!(lock.map[&key].health.state(), HealthState::Degraded);
+        assert!(matches!(&lock.verdict, Verdict::Degraded(crate::cli::Tier::Remote, hosts) if hosts.len() == 1));
+    }
+
+    #[test]
+    fn outage_ends_at_the_first_reply_of_the_recovery() {
+        let t = tracks(&["--down-after", "2", "--up-after", "3", "192.0.2.1"]);
+        let key = HostInfo::new(None, "192.0.2.1".parse().unwrap()).key();
+        let mut lock = t.inner.lock().unwrap();
+        let h = lock.map.get_mut(&key).unwrap();
+        h.sla_run = Availability::new(at(0));
+        h.round_outcome(0, true, None, at(0));
+        h.round_outcome(1, false, Some(at(1)), at(2));
+        h.round_outcome(2, false, Some(at(2)), at(3));
+        assert!(!h.health.is_reachable());
+        // a reply that is not followed by enough others does not end it
+        h.round_outcome(3, true, None, at(4));
+        h.round_outcome(4, false, Some(at(5)), at(6));
+        h.round_outcome(5, true, None, at(10));
+        h.round_outcome(6, true, None, at(11));
+        assert!(!h.health.is_reachable());
+        h.round_outcome(7, true, None, at(12));
+        assert!(h.health.is_reachable());
+        let o = h.completed_outages.last().unwrap();
+        assert_eq!((o.start, o.end, o.count), (at(1), Some(at(10)), 3));
+        let sla = h.sla_run.summary(at(20), None);
+        assert_eq!((sla.downtime, sla.mttr), (Duration::from_secs(9), Some(Duration::from_secs(9))));
+    }
+
+    #[test]
+    fn icmp_error_for_the_outstanding_probe() {
+        let mut t = tracks(&["192.0.2.1"]);
+        let key = HostInfo::new(None, "192.0.2.1".parse().unwrap()).key();
+        let start = Instant::now();
+        t.update_for_send(&key, 0, start, 7, 100);
+        assert!(t.update_for_non_reply(&key, 0, 100));
+        // about an older probe: ignored
+        t.update_for_non_reply(&key, 0, 99);
+        t.update_for_send(&key, 0, start + Duration::from_secs(1), 7, 101);
+        let lock = t.inner.lock().unwrap();
+        let h = &lock.map[&key];
+        let snap = h.stats.snapshot();
+        assert_eq!((snap.reply, snap.non_reply, snap.timeout), (0, 1, 1));
+        assert_eq!(snap.avg_ms(), None);
+        assert_eq!(h.non_replies, 1);
+    }
+
+    #[test]
+    fn timestamp_hops_are_not_path_changes() {
+        let mut t = tracks(&["192.0.2.1"]);
+        let key = HostInfo::new(None, "192.0.2.1".parse().unwrap()).key();
+        let (a, b): (Ipv4Addr, Ipv4Addr) = ("198.51.100.1".parse().unwrap(), "198.51.100.2".parse().unwrap());
+        t.update_route(&key, 0, vec![a, b]);
+        // hops that decline to stamp change the list, not the path
+        t.update_timestamps(&key, 0, vec![(a, 1000), (b, 1002)]);
+        t.update_timestamps(&key, 0, vec![(b, 2002)]);
+        {
+            let lock = t.inner.lock().unwrap();
+            let f = &lock.map[&key].flows[0];
+            assert_eq!((f.route.clone(), f.route_changes), (Some(vec![a, b]), 0));
+            assert_eq!(f.stamps, Some(vec![(b, 2002)]));
+        }
+        t.update_route(&key, 0, vec![b, a]);
+        let report = t.create_report(false);
+        assert!(report.contains("192.0.2.1: [198.51.100.2 -> 198.51.100.1] 1 changes"), "{}", report);
+        assert!(report.contains("TIMESTAMPS (ms since midnight UT):\n\t  192.0.2.1: [198.51.100.2 2002]"), "{}", report);
+    }
+
+    #[test]
+    fn hops_from_the_nearest_initial_ttl() {
+        assert_eq!(hops_from_ttl(64), 0);
+        assert_eq!(hops_from_ttl(57), 7);
+        assert_eq!(hops_from_ttl(30), 2);
+        assert_eq!(hops_from_ttl(120), 8);
+        assert_eq!(hops_from_ttl(65), 63);
+        assert_eq!(hops_from_ttl(250), 5);
+    }
+
+    #[test]
+    fn reply_ttl_sets_hops() {
+        let mut t = tracks(&["192.0.2.1"]);
+        let key = HostInfo::new(None, "192.0.2.1".parse().unwrap()).key();
+        let start = Instant::now();
+        for (i, ttl) in [Some(58), Some(61), None].into_iter().enumerate() {
+            let sent = start + Duration::from_secs(i as u64);
+            t.update_for_send(&key, 0, sent, 7, 100 + i as u16);
+            t.update_for_recv(&key, 0, sent + Duration::from_millis(2), 7, 100 + i as u16, ttl);
+        }
+        let lock = t.inner.lock().unwrap();
+        let h = &lock.map[&key];
+        // a reply without a TTL (no hop limit delivered) leaves the last one
+        assert_eq!(h.flows[0].hops, Some(3));
+        let snap = h.stats.snapshot();
+        assert_eq!((snap.ttl_last, snap.hops_min, snap.hops_max), (61, 3, 6));
+    }
+}
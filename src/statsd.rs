@@ -0,0 +1,260 @@
+#![allow(dead_code)]
+use std::fmt::Write as FmtWrite;
+use std::io::Write;
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs, UdpSocket};
+use std::sync::OnceLock;
+use std::time::{Duration, SystemTime};
+use anyhow::{anyhow, Context, Result};
+use log::{debug, info, warn};
+use crate::events::ProbeEvent;
+use crate::metrics::{metric_path, Deltas, HostMetrics, IntervalExporter};
+
+/// Keep datagrams under a typical path MTU.
+const MAX_DATAGRAM: usize = 1400;
+
+fn resolve(addr: &str) -> Result<SocketAddr> {
+    addr.to_socket_addrs().with_context(|| format!("resolving \"{}\"", addr))?
+        .next().ok_or_else(|| anyhow!("no address for \"{}\"", addr))
+}
+
+fn udp_to(addr: SocketAddr) -> Result<UdpSocket> {
+    let local: SocketAddr = if addr.is_ipv4() { "0.0.0.0:0".parse()? } else { "[::]:0".parse()? };
+    let sock = UdpSocket::bind(local).context("binding statsd socket")?;
+    sock.connect(addr).with_context(|| format!("connecting statsd socket to {}", addr))?;
+    Ok(sock)
+}
+
+/// StatsD over UDP.  The per-probe timers and counters go through a global
+/// socket like the other per-probe sinks; gauges are sent each stats interval.
+pub struct Statsd {
+    sock: UdpSocket,
+    prefix: String,
+}
+
+struct ProbeSink {
+    sock: UdpSocket,
+    prefix: String,
+}
+
+static PROBES: OnceLock<ProbeSink> = OnceLock::new();
+
+impl Statsd {
+    pub fn open(addr: &str, prefix: &str) -> Result<Statsd> {
+        let addr = resolve(addr)?;
+        let _ = PROBES.set(ProbeSink { sock: udp_to(addr)?, prefix: prefix.to_string() });
+        info!("sending statsd to {}", addr);
+        Ok(Statsd { sock: udp_to(addr)?, prefix: prefix.to_string() })
+    }
+
+    /// Sends `lines` packed into as few datagrams as fit.
+    fn send(&self, lines: &[String]) {
+        let mut buf = String::new();
+        for l in lines {
+            if !buf.is_empty() && buf.len() + 1 + l.len() > MAX_DATAGRAM {
+                self.send_one(&buf);
+                buf.clear();
+            }
+            if !buf.is_empty() {
+                buf.push('\n');
+            }
+            buf.push_str(l);
+        }
+        if !buf.is_empty() {
+            self.send_one(&buf);
+        }
+    }
+
+    fn send_one(&self, datagram: &str) {
+        // nobody listening is not worth more than a debug line per datagram
+        if let Err(e) = self.sock.send(datagram.as_bytes()) {
+            debug!("statsd send error: {}", e);
+        }
+    }
+}
+
+impl IntervalExporter for Statsd {
+    fn export(&mut self, hosts: &[HostMetrics]) {
+        let mut lines = vec![];
+        for m in hosts {
+            let p = format!("{}.{}", self.prefix, metric_path(&m.host, '.'));
+            lines.push(format!("{}.up:{}|g", p, m.reachable as u8));
+            lines.push(format!("{}.outages:{}|g", p, m.outages));
+            lines.push(format!("{}.downtime_s:{:.3}|g", p, m.downtime.as_secs_f64()));
+            if let Some(q) = m.voice {
+                lines.push(format!("{}.r:{:.2}|g", p, q.r));
+                lines.push(format!("{}.mos:{:.2}|g", p, q.mos));
+            }
+        }
+        self.send(&lines);
+    }
+}
+
+/// Per-probe StatsD: an rtt timer and a reply counter, or a timeout / error
+/// counter, to be sent with `send_probes`.
+pub fn probe(ev: &ProbeEvent) -> Option<String> {
+    let sink = PROBES.get()?;
+    let p = format!("{}.{}", sink.prefix, metric_path(ev.host, '.'));
+    let mut msg = String::new();
+    match ev.rtt_us {
+        Some(us) => {
+            let _ = write!(msg, "{}.rtt:{:.3}|ms\n{}.reply:1|c", p, us as f64 / 1000.0, p);
+        }
+        None => {
+            let _ = write!(msg, "{}.{}:1|c", p, ev.outcome());
+        }
+    }
+    Some(msg)
+}
+
+/// Sends messages built by `probe`, one datagram each.  Callers holding the
+/// `Tracks` lock collect them and send once it is released.
+pub fn send_probes(msgs: &[String]) {
+    let Some(sink) = PROBES.get() else {
+        return;
+    };
+    for msg in msgs {
+        if let Err(e) = sink.sock.send(msg.as_bytes()) {
+            debug!("statsd send error: {}", e);
+        }
+    }
+}
+
+/// Graphite plaintext protocol over TCP, pushed each stats interval.  Counts
+/// are since start; loss and average RTT are over the interval just ended.
+pub struct Graphite {
+    addr: SocketAddr,
+    prefix: String,
+    conn: Option<TcpStream>,
+    deltas: Deltas,
+}
+
+impl Graphite {
+    pub fn new(addr: &str, prefix: &str) -> Result<Graphite> {
+        let addr = resolve(addr)?;
+        info!("pushing graphite to {}", addr);
+        Ok(Graphite { addr, prefix: prefix.to_string(), conn: None, deltas: Deltas::default() })
+    }
+
+    fn write(&mut self, payload: &str) -> std::io::Result<()> {
+        if self.conn.is_none() {
+            let s = TcpStream::connect_timeout(&self.addr, Duration::from_secs(2))?;
+            s.set_write_timeout(Some(Duration::from_secs(5)))?;
+            self.conn = Some(s);
+        }
+        let res = self.conn.as_mut().unwrap().write_all(payload.as_bytes());
+        if res.is_err() {
+            // reconnect on the next interval
+            self.conn = None;
+        }
+        res
+    }
+}
+
+impl IntervalExporter for Graphite {
+    fn export(&mut self, hosts: &[HostMetrics]) {
+        let ts = hosts.first().map_or(SystemTime::now(), |m| m.at)
+            .duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().as_secs();
+        let mut out = String::new();
+        for m in hosts {
+            let p = format!("{}.{}", self.prefix, metric_path(&m.host, '.'));
+            let t = &m.totals;
+            let d = self.deltas.update(m);
+            let _ = writeln!(out, "{}.replies {} {}", p, t.reply, ts);
+            let _ = writeln!(out, "{}.timeouts {} {}", p, t.timeout, ts);
+            let _ = writeln!(out, "{}.up {} {}", p, m.reachable as u8, ts);
+            let _ = writeln!(out, "{}.outages {} {}", p, m.outages, ts);
+            let _ = writeln!(out, "{}.downtime_s {:.3} {}", p, m.downtime.as_secs_f64(), ts);
+            if let Some(loss) = d.loss_pct() {
+                let _ = writeln!(out, "{}.loss_pct {:.3} {}", p, loss, ts);
+            }
+            if let Some(avg) = d.rtt_avg_ms() {
+                let _ = writeln!(out, "{}.rtt_avg_ms {:.3} {}", p, avg, ts);
+            }
+            if let Some(q) = m.voice {
+                let _ = writeln!(out, "{}.r {:.2} {}", p, q.r, ts);
+                let _ = writeln!(out, "{}.mos {:.2} {}", p, q.mos, ts);
+            }
+        }
+        if let Err(e) = self.write(&out) {
+            warn!("error pushing to graphite {}: {}", self.addr, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader};
+    use std::net::TcpListener;
+    use crate::cli::HostInfo;
+    use crate::metrics::tests::host_metrics;
+
+    fn host() -> HostInfo {
+        let mut h = HostInfo::new(Some("db.example:1/x".to_string()), "192.0.2.3".parse().unwrap());
+        h.mark = Some(0x2a);
+        h
+    }
+
+    fn recv(sock: &UdpSocket) -> String {
+        let mut buf = [0u8; 2000];
+        let n = sock.recv(&mut buf).unwrap();
+        String::from_utf8(buf[..n].to_vec()).unwrap()
+    }
+
+    #[test]
+    fn statsd_probe_and_interval_datagrams() {
+        let listener = UdpSocket::bind("127.0.0.1:0").unwrap();
+        listener.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut statsd = Statsd::open(&listener.local_addr().unwrap().to_string(), "sp").unwrap();
+        let h = host();
+        let p = "sp.db_example_1_x_mark_2a";
+
+        let ev = ProbeEvent { host: &h, flow: 0, sent: SystemTime::now(), seq: 1, ident: 2, rtt_us: Some(1234), ttl: None, error: None };
+        let timeout = ProbeEvent { rtt_us: None, ..ev };
+        let failed = ProbeEvent { rtt_us: None, error: Some("send"), ..ev };
+        let msgs: Vec<String> = [&ev, &timeout, &failed].into_iter().filter_map(probe).collect();
+        send_probes(&msgs);
+        assert_eq!(recv(&listener), format!("{p}.rtt:1.234|ms\n{p}.reply:1|c"));
+        assert_eq!(recv(&listener), format!("{p}.timeout:1|c"));
+        assert_eq!(recv(&listener), format!("{p}.error:1|c"));
+
+        statsd.export(&[host_metrics(h, 3, 1)]);
+        assert_eq!(recv(&listener), format!("{p}.up:1|g\n{p}.outages:1|g\n{p}.downtime_s:1.500|g\n{p}.r:93.00|g\n{p}.mos:4.40|g"));
+    }
+
+    #[test]
+    fn graphite_plaintext_lines() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut g = Graphite::new(&listener.local_addr().unwrap().to_string(), "sp").unwrap();
+        let h = host();
+        g.export(&[host_metrics(h.clone(), 8, 2)]);
+        let mut second = host_metrics(h, 11, 3);
+        second.at += Duration::from_secs(10);
+        second.voice = None;
+        g.export(&[second]);
+        drop(g);
+
+        let (s, _) = listener.accept().unwrap();
+        let lines: Vec<String> = BufReader::new(s).lines().map(|l| l.unwrap()).collect();
+        let p = "sp.db_example_1_x_mark_2a";
+        assert_eq!(lines, [
+            format!("{p}.replies 8 1700000000"),
+            format!("{p}.timeouts 2 1700000000"),
+            format!("{p}.up 1 1700000000"),
+            format!("{p}.outages 1 1700000000"),
+            format!("{p}.downtime_s 1.500 1700000000"),
+            format!("{p}.loss_pct 20.000 1700000000"),
+            format!("{p}.rtt_avg_ms 2.000 1700000000"),
+            format!("{p}.r 93.00 1700000000"),
+            format!("{p}.mos 4.40 1700000000"),
+            // counts since start, loss over the 3 replies and 1 timeout since the last push
+            format!("{p}.replies 11 1700000010"),
+            format!("{p}.timeouts 3 1700000010"),
+            format!("{p}.up 1 1700000010"),
+            format!("{p}.outages 1 1700000010"),
+            format!("{p}.downtime_s 1.500 1700000010"),
+            format!("{p}.loss_pct 25.000 1700000010"),
+            format!("{p}.rtt_avg_ms 2.000 1700000010"),
+        ]);
+    }
+}
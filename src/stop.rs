@@ -40,11 +40,7 @@ impl Stop {
             true
         } else {
             let result = self.cond.wait_timeout(lock, time).unwrap();
-            if *result.0 {
-                true
-            } else {
-                false
-            }
+            *result.0
         }
     }
 
@@ -2,11 +2,14 @@
 
 use std::time::{Duration, SystemTime};
 use std::fmt;
-use socket2::SockAddr;
+use socket2::{Domain, SockAddr, Socket, Type};
+use std::os::fd::AsRawFd;
 use std::fmt::{Display, Formatter};
-use std::net::IpAddr;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use crate::stop::Stop;
 use log::LevelFilter;
+use crate::cli::{LogFormat, LogSink};
+use crate::logging;
 use humantime::format_rfc3339_millis;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::io::IsTerminal;
@@ -109,35 +112,159 @@ impl Display for SockAddrWrap<'_> {
     }
 }
 
-pub fn init_log(level: LevelFilter) {
-    STDERR_IS_TERMINAL.store(std::io::stderr().is_terminal(), Ordering::Relaxed);
+/// Sets up logging to `sink` in `format`.  The line clearing that keeps log
+/// lines from running into the status line is only done for text on a terminal.
+pub fn init_log(level: LevelFilter, sink: &LogSink, format: LogFormat) -> anyhow::Result<()> {
+    let is_terminal = std::io::stderr().is_terminal();
+    // the status line would garble machine readable output on stderr
+    STDERR_IS_TERMINAL.store(is_terminal && (*sink != LogSink::Stderr || format == LogFormat::Text), Ordering::Relaxed);
 
     let mut builder = env_logger::Builder::new();
 
-    use env_logger::fmt::Formatter;
     use std::io::Write;
 
-    // builder.format(|buf, record| {
-    //     writeln!(buf, "{} [{:4}] [{}:{}] {:>5}: {} ", format_rfc3339_millis(SystemTime::now()),
-    //              std::thread::current().name().or(Some("unknown")).unwrap(),
-    //              record.file().unwrap(),
-    //              record.line().unwrap(),
-    //              record.level(),
-    //              record.args())
-    // });
-    builder.format(|buf, record| {
-        use std::io::Write;
-        if STDERR_IS_TERMINAL.load(Ordering::Relaxed) {
-            write!(buf, "\r{:80}\r", "")?;
-        }
-        writeln!(buf, "{} [{:4}] {:>5} {} ", format_rfc3339_millis(SystemTime::now()),
-                 std::thread::current().name().or(Some("unknown")).unwrap(),
-                 record.level(),
-                 record.args())
-    });
+    if *sink == LogSink::Stderr {
+        let clear_line = is_terminal && format == LogFormat::Text;
+        builder.format(move |buf, record| {
+            if clear_line {
+                write!(buf, "\r{:80}\r", "")?;
+            }
+            writeln!(buf, "{}", logging::format_record(format, record, true))
+        });
+    } else {
+        let header = logging::SyslogHeader::new();
+        builder.format(move |buf, record| {
+            write!(buf, "{}", header.format(format, record))
+        });
+        builder.target(env_logger::Target::Pipe(Box::new(logging::SyslogWriter::open(sink)?)));
+    }
     builder.filter_level(level);
     builder.init();
+    Ok(())
+}
+
+
+/// Receives one datagram like `Socket::recv_from`, also returning the IPv6 hop
+/// limit delivered as ancillary data when IPV6_RECVHOPLIMIT is enabled on the socket.
+/// IPv4 raw sockets carry the TTL in the returned IP header instead, so this is None for them.
+pub fn recv_from_with_hop_limit(socket: &Socket, buf: &mut [u8]) -> std::io::Result<(usize, SockAddr, Option<u8>)> {
+    // u64 storage keeps the control buffer aligned for cmsghdr
+    let mut control = [0u64; 8];
+    let mut iov = libc::iovec { iov_base: buf.as_mut_ptr().cast(), iov_len: buf.len() };
+    // SAFETY: msghdr points at iov/control/storage which all outlive the recvmsg call,
+    // and the cmsg macros only walk the msg_controllen bytes the kernel filled in.
+    let ((size, hop_limit), addr) = unsafe {
+        SockAddr::try_init(|storage, len| {
+            let mut msg: libc::msghdr = std::mem::zeroed();
+            msg.msg_name = storage.cast();
+            msg.msg_namelen = *len;
+            msg.msg_iov = &mut iov;
+            msg.msg_iovlen = 1;
+            msg.msg_control = control.as_mut_ptr().cast();
+            msg.msg_controllen = std::mem::size_of_val(&control) as _;
+            let n = libc::recvmsg(socket.as_raw_fd(), &mut msg, 0);
+            if n < 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            *len = msg.msg_namelen;
+
+            let mut hop_limit = None;
+            let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+            while !cmsg.is_null() {
+                if (*cmsg).cmsg_level == libc::IPPROTO_IPV6 && (*cmsg).cmsg_type == libc::IPV6_HOPLIMIT {
+                    let v = std::ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const libc::c_int);
+                    hop_limit = u8::try_from(v).ok();
+                }
+                cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+            }
+            Ok((n as usize, hop_limit))
+        })?
+    };
+    Ok((size, addr, hop_limit))
+}
+
+/// IPv4 default gateway of the main routing table, from /proc/net/route.
+pub fn default_gateway() -> anyhow::Result<Option<Ipv4Addr>> {
+    use anyhow::Context;
+    let routes = std::fs::read_to_string("/proc/net/route").context("reading /proc/net/route")?;
+    // Iface Destination Gateway Flags ...; addresses are the in-memory (network order) bytes printed as a native u32
+    for line in routes.lines().skip(1) {
+        let f: Vec<&str> = line.split_whitespace().collect();
+        if f.len() < 4 || f[1] != "00000000" {
+            continue;
+        }
+        let flags = u16::from_str_radix(f[3], 16).unwrap_or(0);
+        // RTF_UP | RTF_GATEWAY
+        if flags & 0x3 != 0x3 {
+            continue;
+        }
+        let gw = u32::from_str_radix(f[2], 16).with_context(|| format!("bad gateway \"{}\" in /proc/net/route", f[2]))?;
+        return Ok(Some(Ipv4Addr::from(gw.to_ne_bytes())));
+    }
+    Ok(None)
+}
 
+/// Source address the kernel would pick for `dst` (with `mark` applied to
+/// the route lookup), or the unspecified address when there is no route.
+pub fn route_source(dst: IpAddr, mark: Option<u32>) -> IpAddr {
+    let lookup = || -> std::io::Result<IpAddr> {
+        let s = Socket::new(if dst.is_ipv4() { Domain::IPV4 } else { Domain::IPV6 }, Type::DGRAM, None)?;
+        if let Some(mark) = mark {
+            s.set_mark(mark)?;
+        }
+        // connecting a UDP socket only does the route lookup, nothing is sent
+        s.connect(&SocketAddr::new(dst, 9).into())?;
+        s.local_addr()?.as_socket().map(|a| a.ip()).ok_or_else(|| std::io::Error::other("not an inet address"))
+    };
+    lookup().unwrap_or(unspecified(dst))
+}
 
+/// Interface the kernel would send to `dst` through: the one holding the
+/// source address of the route, None when there is no route.
+pub fn route_interface(dst: IpAddr, mark: Option<u32>) -> Option<String> {
+    let src = route_source(dst, mark);
+    if src.is_unspecified() {
+        return None;
+    }
+    let mut ifap: *mut libc::ifaddrs = std::ptr::null_mut();
+    // SAFETY: getifaddrs allocates the list, freed below with freeifaddrs
+    if unsafe { libc::getifaddrs(&mut ifap) } != 0 {
+        return None;
+    }
+    let mut found = None;
+    let mut cur = ifap;
+    while !cur.is_null() && found.is_none() {
+        // SAFETY: cur is a node of the list getifaddrs returned
+        let ifa = unsafe { &*cur };
+        cur = ifa.ifa_next;
+        if ifa.ifa_addr.is_null() {
+            continue;
+        }
+        // SAFETY: ifa_addr points to a sockaddr of the family it names
+        let addr = unsafe {
+            match i32::from((*ifa.ifa_addr).sa_family) {
+                libc::AF_INET => {
+                    let sin = &*(ifa.ifa_addr as *const libc::sockaddr_in);
+                    IpAddr::V4(Ipv4Addr::from(u32::from_be(sin.sin_addr.s_addr)))
+                }
+                libc::AF_INET6 => {
+                    let sin6 = &*(ifa.ifa_addr as *const libc::sockaddr_in6);
+                    IpAddr::V6(Ipv6Addr::from(sin6.sin6_addr.s6_addr))
+                }
+                _ => continue,
+            }
+        };
+        if addr == src {
+            // SAFETY: ifa_name is a NUL terminated interface name
+            found = Some(unsafe { std::ffi::CStr::from_ptr(ifa.ifa_name) }.to_string_lossy().into_owned());
+        }
+    }
+    // SAFETY: ifap came from getifaddrs and is not used after this
+    unsafe { libc::freeifaddrs(ifap) };
+    found
 }
 
+/// 0.0.0.0 or :: to match `addr`.
+pub fn unspecified(addr: IpAddr) -> IpAddr {
+    if addr.is_ipv4() { IpAddr::V4(Ipv4Addr::UNSPECIFIED) } else { IpAddr::V6(Ipv6Addr::UNSPECIFIED) }
+}
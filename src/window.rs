@@ -0,0 +1,195 @@
+#![allow(dead_code)]
+use std::collections::VecDeque;
+use std::time::{Duration, SystemTime};
+
+/// Width of one time bucket; windows are made of whole buckets, so the "last
+/// minute" covers between 50 and 60 seconds of samples.
+pub const BUCKET_SECS: u64 = 10;
+/// Longest window kept, in seconds.
+const MAX_WINDOW_SECS: u64 = 15 * 60;
+
+/// Sliding windows reported next to since-start totals.
+pub const WINDOWS: &[(&str, Duration)] = &[
+    ("1m", Duration::from_secs(60)),
+    ("5m", Duration::from_secs(5 * 60)),
+    ("15m", Duration::from_secs(15 * 60)),
+];
+
+/// Reply / timeout totals for one bucket or an aggregate of buckets.
+#[derive(Clone, Copy)]
+pub struct WindowAgg {
+    pub reply: u64,
+    pub timeout: u64,
+    pub sum_us: u64,
+    pub sum_sq_us: u64,
+    pub min_us: u64,
+    pub max_us: u64,
+}
+
+impl WindowAgg {
+    fn new() -> WindowAgg {
+        WindowAgg { reply: 0, timeout: 0, sum_us: 0, sum_sq_us: 0, min_us: u64::MAX, max_us: 0 }
+    }
+
+    fn add_reply(&mut self, micros: u64) {
+        self.reply += 1;
+        self.sum_us += micros;
+        self.sum_sq_us = self.sum_sq_us.saturating_add(micros.saturating_mul(micros));
+        self.min_us = self.min_us.min(micros);
+        self.max_us = self.max_us.max(micros);
+    }
+
+    fn merge(&mut self, o: &WindowAgg) {
+        self.reply += o.reply;
+        self.timeout += o.timeout;
+        self.sum_us += o.sum_us;
+        self.sum_sq_us = self.sum_sq_us.saturating_add(o.sum_sq_us);
+        self.min_us = self.min_us.min(o.min_us);
+        self.max_us = self.max_us.max(o.max_us);
+    }
+
+    pub fn loss_pct(&self) -> Option<f64> {
+        let sent = self.reply + self.timeout;
+        (sent > 0).then(|| self.timeout as f64 * 100.0 / sent as f64)
+    }
+
+    pub fn avg_ms(&self) -> Option<f64> {
+        (self.reply > 0).then(|| self.sum_us as f64 / self.reply as f64 / 1000.0)
+    }
+
+    pub fn max_ms(&self) -> Option<f64> {
+        (self.reply > 0).then(|| self.max_us as f64 / 1000.0)
+    }
+}
+
+/// Time bucketed reply/timeout history for one host.  Unlike `Stats` it is
+/// never reset by -R, so windows and since-start totals stay meaningful.
+pub struct Windows {
+    /// (bucket start in epoch seconds, totals), oldest first.
+    buckets: VecDeque<(u64, WindowAgg)>,
+    total: WindowAgg,
+}
+
+fn epoch_secs(t: SystemTime) -> u64 {
+    t.duration_since(SystemTime::UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+impl Windows {
+    pub fn new() -> Windows {
+        Windows { buckets: VecDeque::new(), total: WindowAgg::new() }
+    }
+
+    fn bucket(&mut self, now: SystemTime) -> &mut WindowAgg {
+        let start = epoch_secs(now) / BUCKET_SECS * BUCKET_SECS;
+        while let Some((s, _)) = self.buckets.front() {
+            if *s + MAX_WINDOW_SECS <= start {
+                self.buckets.pop_front();
+            } else {
+                break;
+            }
+        }
+        if self.buckets.back().is_none_or(|(s, _)| *s != start) {
+            self.buckets.push_back((start, WindowAgg::new()));
+        }
+        &mut self.buckets.back_mut().unwrap().1
+    }
+
+    pub fn record_reply(&mut self, now: SystemTime, micros: u64) {
+        self.bucket(now).add_reply(micros);
+        self.total.add_reply(micros);
+    }
+
+    pub fn record_timeout(&mut self, now: SystemTime) {
+        self.bucket(now).timeout += 1;
+        self.total.timeout += 1;
+    }
+
+    /// Totals over the buckets that fall within `span` before `now`.
+    pub fn aggregate(&self, now: SystemTime, span: Duration) -> WindowAgg {
+        let now_s = epoch_secs(now);
+        let cutoff = now_s.saturating_sub(span.as_secs());
+        let mut agg = WindowAgg::new();
+        for (start, b) in self.buckets.iter() {
+            if *start >= cutoff && *start <= now_s {
+                agg.merge(b);
+            }
+        }
+        agg
+    }
+
+    pub fn since_start(&self) -> WindowAgg {
+        self.total
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(secs: u64) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000 + secs)
+    }
+
+    #[test]
+    fn aggregates_buckets_in_the_span() {
+        let mut w = Windows::new();
+        w.record_reply(at(1), 1000);
+        w.record_timeout(at(5));
+        w.record_reply(at(15), 3000);
+        w.record_reply(at(38), 2000);
+        w.record_timeout(at(39));
+        let agg = w.aggregate(at(39), Duration::from_secs(60));
+        assert_eq!((agg.reply, agg.timeout), (3, 2));
+        assert_eq!((agg.min_us, agg.max_us, agg.sum_us, agg.sum_sq_us), (1000, 3000, 6000, 14_000_000));
+        assert_eq!(agg.loss_pct(), Some(40.0));
+        assert_eq!(agg.avg_ms(), Some(2.0));
+        assert_eq!(agg.max_ms(), Some(3.0));
+        // the last 10s only hold the bucket starting at 30
+        let agg = w.aggregate(at(39), Duration::from_secs(10));
+        assert_eq!((agg.reply, agg.timeout), (1, 1));
+    }
+
+    #[test]
+    fn windows_are_whole_buckets() {
+        // one reply a second up to `now`, then the count in the last minute
+        let minute = |now: u64| {
+            let mut w = Windows::new();
+            for s in 0..=now {
+                w.record_reply(at(s), 1000);
+            }
+            w.aggregate(at(now), Duration::from_secs(60)).reply
+        };
+        // 1700000000 is a bucket boundary: at 69s the bucket from 0s has left
+        // the minute, at 71s the one from 10s too
+        assert_eq!(minute(69), 60);
+        assert_eq!(minute(70), 61);
+        assert_eq!(minute(71), 52);
+        assert_eq!(minute(79), 60);
+    }
+
+    #[test]
+    fn old_buckets_expire_but_totals_stay() {
+        let mut w = Windows::new();
+        w.record_reply(at(0), 1000);
+        w.record_timeout(at(0));
+        w.record_reply(at(MAX_WINDOW_SECS - 1), 4000);
+        assert_eq!(w.buckets.len(), 2);
+        w.record_reply(at(MAX_WINDOW_SECS), 2000);
+        assert_eq!(w.buckets.len(), 2);
+        assert_eq!(w.buckets.front().unwrap().0, epoch_secs(at(MAX_WINDOW_SECS - 10)));
+        let agg = w.aggregate(at(MAX_WINDOW_SECS), Duration::from_secs(MAX_WINDOW_SECS));
+        assert_eq!((agg.reply, agg.timeout, agg.min_us), (2, 0, 2000));
+        let total = w.since_start();
+        assert_eq!((total.reply, total.timeout, total.min_us, total.max_us), (3, 1, 1000, 4000));
+        assert_eq!(total.loss_pct(), Some(25.0));
+    }
+
+    #[test]
+    fn empty_window() {
+        let mut w = Windows::new();
+        w.record_reply(at(0), 1000);
+        let agg = w.aggregate(at(500), Duration::from_secs(60));
+        assert_eq!((agg.reply, agg.timeout), (0, 0));
+        assert_eq!((agg.loss_pct(), agg.avg_ms(), agg.max_ms()), (None, None, None));
+    }
+}